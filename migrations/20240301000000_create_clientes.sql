@@ -0,0 +1,16 @@
+CREATE TABLE clientes (
+    id INTEGER PRIMARY KEY,
+    limite INTEGER NOT NULL,
+    saldo INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE transacoes (
+    id SERIAL PRIMARY KEY,
+    cliente_id INTEGER NOT NULL REFERENCES clientes (id),
+    valor INTEGER NOT NULL,
+    tipo CHAR(1) NOT NULL,
+    descricao TEXT NOT NULL,
+    realizado_em TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX transacoes_cliente_id_idx ON transacoes (cliente_id, id DESC);
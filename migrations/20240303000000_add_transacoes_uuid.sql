@@ -0,0 +1,4 @@
+ALTER TABLE transacoes ADD COLUMN uuid UUID NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE transacoes ALTER COLUMN uuid DROP DEFAULT;
+
+CREATE UNIQUE INDEX transacoes_uuid_idx ON transacoes (uuid);
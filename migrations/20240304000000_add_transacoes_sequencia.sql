@@ -0,0 +1,19 @@
+ALTER TABLE clientes ADD COLUMN ultima_sequencia BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE transacoes ADD COLUMN sequencia BIGINT;
+
+UPDATE transacoes t
+SET sequencia = numbered.sequencia
+FROM (
+    SELECT id, row_number() OVER (PARTITION BY cliente_id ORDER BY id) AS sequencia
+    FROM transacoes
+) numbered
+WHERE t.id = numbered.id;
+
+UPDATE clientes c
+SET ultima_sequencia = COALESCE(
+    (SELECT max(sequencia) FROM transacoes WHERE cliente_id = c.id),
+    0
+);
+
+ALTER TABLE transacoes ALTER COLUMN sequencia SET NOT NULL;
+CREATE UNIQUE INDEX transacoes_cliente_sequencia_idx ON transacoes (cliente_id, sequencia);
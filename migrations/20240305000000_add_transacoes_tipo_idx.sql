@@ -0,0 +1 @@
+CREATE INDEX transacoes_cliente_tipo_idx ON transacoes (cliente_id, tipo, id DESC);
@@ -0,0 +1,3 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX transacoes_descricao_trgm_idx ON transacoes USING gin (lower(descricao) gin_trgm_ops);
@@ -0,0 +1,2 @@
+ALTER TABLE clientes ADD COLUMN alerta_percentual INTEGER
+    CHECK (alerta_percentual BETWEEN 1 AND 100);
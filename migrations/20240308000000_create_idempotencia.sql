@@ -0,0 +1,7 @@
+CREATE TABLE idempotencia (
+    chave TEXT PRIMARY KEY,
+    registro TEXT NOT NULL,
+    expira_em TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idempotencia_expira_em_idx ON idempotencia (expira_em);
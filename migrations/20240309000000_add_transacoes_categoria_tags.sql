@@ -0,0 +1,2 @@
+ALTER TABLE transacoes ADD COLUMN categoria TEXT;
+ALTER TABLE transacoes ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
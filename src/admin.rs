@@ -0,0 +1,408 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse},
+    Json,
+};
+use serde_json::json;
+use tracing::{error, warn};
+
+use crate::{
+    domain::{self, Account, Rejection},
+    i18n::{Lang, Localized, Message},
+    metrics,
+    models::{
+        ClientReconciliation, ImportReport, ImportedTransaction, ReconciliationReport, Statement,
+        User,
+    },
+    settings,
+    settings::ReloadSummary,
+    stats::StatsSnapshot,
+    storage::{StorageError, TransactionError},
+    tenants::{self, Tenant},
+    validation::{Valid, ValidationErrors, Validator},
+    warmup::{self, WarmupReport},
+    writer::WriteError,
+    AppState,
+};
+
+enum ReloadResult {
+    Success(Json<ReloadSummary>),
+    Failed(String),
+}
+
+impl IntoResponse for ReloadResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ReloadResult::Success(json) => json.into_response(),
+            ReloadResult::Failed(message) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+        }
+    }
+}
+
+pub async fn reload(State(state): State<AppState>) -> impl IntoResponse {
+    match state.reloader.reload(state.storage.as_ref()).await {
+        Ok(summary) => {
+            state.extratos.clear();
+            state.audit.record(
+                "configuracao_recarregada",
+                None,
+                summary.audit_details("http"),
+            );
+            ReloadResult::Success(Json(summary))
+        }
+        Err(err) => {
+            error!("reload failed, keeping previous configuration: {err}");
+            ReloadResult::Failed(err.to_string())
+        }
+    }
+}
+
+enum WarmupResult {
+    Success(Json<WarmupReport>),
+    InternalError,
+}
+
+impl IntoResponse for WarmupResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            WarmupResult::Success(json) => json.into_response(),
+            WarmupResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+pub async fn warmup(Tenant(state): Tenant) -> impl IntoResponse {
+    match warmup::warm_up(&state).await {
+        Ok(report) => {
+            state.audit.record(
+                "aquecimento",
+                state.tenant.as_deref(),
+                serde_json::to_value(&report).unwrap_or_default(),
+            );
+            WarmupResult::Success(Json(report))
+        }
+        Err(err) => {
+            error!("warmup failed: {err}");
+            WarmupResult::InternalError
+        }
+    }
+}
+
+/// Self-contained page polling the clients and stats and following the
+/// transaction feed.
+pub async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("../assets/dashboard.html"))
+}
+
+pub async fn stats(Tenant(state): Tenant) -> Json<StatsSnapshot> {
+    Json(state.stats.snapshot())
+}
+
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    state.http_metrics.refresh();
+
+    let mut body = metrics::render_prometheus();
+    state.stats.snapshot().write_prometheus(&mut body);
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+enum ReconciliationResult {
+    Report(Json<ReconciliationReport>),
+    Client(Json<ClientReconciliation>),
+    NotFound,
+    InternalError,
+}
+
+impl IntoResponse for ReconciliationResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ReconciliationResult::Report(json) => json.into_response(),
+            ReconciliationResult::Client(json) => json.into_response(),
+            ReconciliationResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            ReconciliationResult::InternalError => {
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+fn reconcile(user: &User, history: &[Statement]) -> ClientReconciliation {
+    let saldo_calculado: i64 = history
+        .iter()
+        .map(|s| match s.tipo.as_str() {
+            "d" => -i64::from(s.valor),
+            _ => i64::from(s.valor),
+        })
+        .sum();
+    let divergencia = i64::from(user.saldo) - saldo_calculado;
+
+    if divergencia != 0 {
+        warn!(
+            cliente = user.id,
+            saldo = user.saldo,
+            saldo_calculado,
+            "balance diverges from stored transactions"
+        );
+    }
+
+    ClientReconciliation {
+        id: user.id,
+        saldo: user.saldo,
+        saldo_calculado,
+        transacoes: history.len(),
+        divergencia,
+    }
+}
+
+async fn reconcile_all(state: &AppState) -> Result<ReconciliationReport, StorageError> {
+    let mut clientes = Vec::new();
+
+    for user in state.storage.list_clients(true).await? {
+        if let Some((user, history)) = state.storage.history(user.id).await? {
+            clientes.push(reconcile(&user, &history));
+        }
+    }
+
+    Ok(ReconciliationReport {
+        divergentes: clientes.iter().filter(|c| c.divergencia != 0).count(),
+        clientes,
+        transacoes_rejeitadas: state.storage.rejected_replays(),
+    })
+}
+
+pub async fn reconciliation(Tenant(state): Tenant) -> impl IntoResponse {
+    match reconcile_all(&state).await {
+        Ok(report) => ReconciliationResult::Report(Json(report)),
+        Err(err) => {
+            error!("reconciliation failed: {err}");
+            ReconciliationResult::InternalError
+        }
+    }
+}
+
+pub async fn client_reconciliation(
+    Tenant(state): Tenant,
+    Path(user_id): Path<i32>,
+) -> impl IntoResponse {
+    match state.storage.history(user_id).await {
+        Ok(Some((user, history))) => ReconciliationResult::Client(Json(reconcile(&user, &history))),
+        Ok(None) => ReconciliationResult::NotFound,
+        Err(err) => {
+            error!("reconciliation of client {user_id} failed: {err}");
+            ReconciliationResult::InternalError
+        }
+    }
+}
+
+enum TenantResult {
+    Created,
+    Reset,
+    InvalidName(Localized),
+    NotFound,
+    InternalError,
+}
+
+impl IntoResponse for TenantResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            TenantResult::Created => StatusCode::CREATED.into_response(),
+            TenantResult::Reset => StatusCode::NO_CONTENT.into_response(),
+            TenantResult::InvalidName(message) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+            TenantResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            TenantResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+pub async fn list_tenants(State(state): State<AppState>) -> Json<Vec<String>> {
+    Json(state.tenants.names())
+}
+
+/// Creates a tenant, or wipes an existing one, seeded with the clients in the
+/// body or, without one, the same clients as the default tenant.
+pub async fn reset_tenant(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    lang: Lang,
+    users: Option<Json<Vec<User>>>,
+) -> impl IntoResponse {
+    if !tenants::valid_name(&name) {
+        return TenantResult::InvalidName(Message::new("nome_de_tenant_invalido").localize(lang));
+    }
+
+    let users = match users {
+        Some(Json(users)) => users,
+        None => match settings::load_users(state.tenants.clients_file()) {
+            Ok(users) => users,
+            Err(err) => {
+                error!("failed to load clients for tenant {name}: {err}");
+                return TenantResult::InternalError;
+            }
+        },
+    };
+
+    let result = state.tenants.reset(&name, &users).await;
+    if let Ok(created) = result {
+        let acao = if created {
+            "tenant_criado"
+        } else {
+            "tenant_reiniciado"
+        };
+        let clientes: Vec<i32> = users.iter().map(|user| user.id).collect();
+        state
+            .audit
+            .record(acao, Some(&name), json!({ "clientes": clientes }));
+    }
+
+    match result {
+        Ok(true) => TenantResult::Created,
+        Ok(false) => TenantResult::Reset,
+        Err(err) => {
+            error!("failed to seed tenant {name}: {err}");
+            TenantResult::InternalError
+        }
+    }
+}
+
+pub async fn delete_tenant(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if state.tenants.remove(&name) {
+        state
+            .audit
+            .record("tenant_removido", Some(&name), json!({}));
+        TenantResult::Reset
+    } else {
+        TenantResult::NotFound
+    }
+}
+
+enum ImportResult {
+    Success(Json<ImportReport>),
+    Invalid(ValidationErrors),
+    HasHistory(Localized),
+    NotFound,
+    Gone,
+    Overloaded,
+    InternalError,
+}
+
+impl IntoResponse for ImportResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ImportResult::Success(json) => json.into_response(),
+            ImportResult::Invalid(errors) => errors.into_response(),
+            ImportResult::HasHistory(message) => (StatusCode::CONFLICT, message).into_response(),
+            ImportResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            ImportResult::Gone => StatusCode::GONE.into_response(),
+            ImportResult::Overloaded => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            ImportResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+/// Records a migrated client's past transactions, oldest first, at the times
+/// they happened, and leaves the client with the balance they add up to.
+/// Only for clients without transactions here yet, so `sequencia` keeps
+/// following time. The transactions are written together or not at all, so
+/// a failed import can be retried as it was. Errors name transactions by
+/// their position in the body. Imports skip the duplicate check and alerts.
+pub async fn import_history(
+    Tenant(state): Tenant,
+    Path(user_id): Path<i32>,
+    lang: Lang,
+    Valid(transactions): Valid<Vec<ImportedTransaction>>,
+) -> impl IntoResponse {
+    let user = match state.storage.client(user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return ImportResult::NotFound,
+        Err(err) => {
+            error!("failed to load client {user_id} for import: {err}");
+            return ImportResult::InternalError;
+        }
+    };
+    if user.ultima_sequencia > 0 {
+        return ImportResult::HasHistory(Message::new("cliente_com_historico").localize(lang));
+    }
+
+    let mut transactions: Vec<_> = transactions.into_iter().enumerate().collect();
+    transactions.sort_by_key(|(_, imported)| imported.realizado_em);
+
+    let now = state.clock.now();
+    let mut v = Validator::new(lang);
+    let mut account = Account::from(&user);
+    for (indice, imported) in &transactions {
+        if imported.realizado_em > now {
+            v.error(
+                &format!("[{indice}].realizado_em"),
+                "in_future",
+                "campo_no_futuro",
+            );
+        }
+        account = match domain::apply_transaction(account, &imported.transaction) {
+            Ok(account) => account,
+            Err(Rejection::Inactive) => return ImportResult::Gone,
+            // The balances after this one would be made up.
+            Err(Rejection::LimitExceeded | Rejection::Overflow) => {
+                v.error(
+                    &format!("[{indice}].valor"),
+                    "exceeds_limit",
+                    "campo_excede_limite",
+                );
+                break;
+            }
+        };
+    }
+    if let Err(errors) = v.finish() {
+        return ImportResult::Invalid(errors);
+    }
+
+    let transactions = transactions
+        .into_iter()
+        .map(|(_, imported)| (imported.transaction, imported.realizado_em))
+        .collect();
+    let (user, statements) = match state.writes.import(user_id, transactions).await {
+        Ok(imported) => imported,
+        Err(WriteError::Overloaded) => return ImportResult::Overloaded,
+        Err(WriteError::Transaction(err)) => {
+            // A storage error may leave the client stale in the cache.
+            state.extratos.invalidate(user_id);
+            let reason = match err {
+                TransactionError::Storage(err) => err.to_string(),
+                // Live traffic got in between.
+                _ => "rejected by storage".to_owned(),
+            };
+            error!("import into client {user_id} failed: {reason}");
+            return ImportResult::InternalError;
+        }
+    };
+
+    let report = ImportReport {
+        importadas: statements.len(),
+        limite: user.limite,
+        saldo: user.saldo,
+        ultima_sequencia: user.ultima_sequencia,
+    };
+    state.extratos.invalidate(user_id);
+    record_import(&state, user_id, &report);
+    ImportResult::Success(Json(report))
+}
+
+fn record_import(state: &AppState, user_id: i32, report: &ImportReport) {
+    state.audit.record(
+        "historico_importado",
+        state.tenant.as_deref(),
+        json!({
+            "cliente": user_id,
+            "importadas": report.importadas,
+            "saldo": report.saldo,
+        }),
+    );
+}
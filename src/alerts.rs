@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::info;
+
+use crate::{
+    domain,
+    metrics::{self, Counter},
+    models::{Statement, User},
+};
+
+const FIRED: &str = "alertas_total";
+
+/// Alerts a subscriber may fall behind by before it starts missing them.
+const BACKLOG: usize = 256;
+
+#[derive(Clone, Serialize)]
+pub struct Alert {
+    pub cliente: i32,
+    pub saldo: i32,
+    pub limite: i32,
+    pub alerta_percentual: i32,
+    pub sequencia: i64,
+}
+
+/// Whether a client at `saldo` has used at least `percentual` of its limite.
+fn over(saldo: i64, limite: i32, percentual: i32) -> bool {
+    let used = saldo.min(0).abs();
+    limite > 0 && used * 100 >= i64::from(percentual) * i64::from(limite)
+}
+
+pub fn in_alert(user: &User) -> bool {
+    user.alerta_percentual
+        .is_some_and(|percentual| over(user.saldo.into(), user.limite, percentual))
+}
+
+/// Fans alerts out to whoever is subscribed. Nothing is kept: an alert raised
+/// while nobody listens is only logged.
+pub struct Alerts {
+    sender: broadcast::Sender<Alert>,
+    fired: Arc<Counter>,
+}
+
+impl Alerts {
+    pub fn new() -> Self {
+        Alerts {
+            sender: broadcast::channel(BACKLOG).0,
+            fired: metrics::counter(FIRED, Vec::new()),
+        }
+    }
+
+    /// Raises an alert when `statement` is what took `user` past its
+    /// threshold. Staying above it, or going back under, raises nothing.
+    pub fn check(&self, user: &User, statement: &Statement) {
+        let Some(percentual) = user.alerta_percentual else {
+            return;
+        };
+
+        let before = i64::from(user.saldo) - domain::signed_valor(&statement.tipo, statement.valor);
+
+        if over(before, user.limite, percentual)
+            || !over(user.saldo.into(), user.limite, percentual)
+        {
+            return;
+        }
+
+        self.fired.inc();
+        info!(
+            cliente = user.id,
+            saldo = user.saldo,
+            "alert threshold crossed"
+        );
+
+        // No subscribers is not an error here.
+        let _ = self.sender.send(Alert {
+            cliente: user.id,
+            saldo: user.saldo,
+            limite: user.limite,
+            alerta_percentual: percentual,
+            sequencia: statement.sequencia,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Alert> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Alerts::new()
+    }
+}
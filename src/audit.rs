@@ -0,0 +1,248 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+
+use crate::{clock::Clock, settings::BoxError, AppState};
+
+/// What the first entry names as the one before it.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub sequencia: u64,
+    pub realizado_em: DateTime<Utc>,
+    pub acao: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub detalhes: Value,
+    pub hash_anterior: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// SHA-256 over every other field, `hash_anterior` included, so changing
+    /// or removing an entry breaks the chain from there on.
+    fn digest(&self) -> String {
+        let content = (
+            self.sequencia,
+            self.realizado_em,
+            &self.acao,
+            &self.tenant,
+            &self.detalhes,
+            &self.hash_anterior,
+        );
+        let bytes = serde_json::to_vec(&content).expect("audit entries serialize");
+        format!("{:x}", Sha256::digest(bytes))
+    }
+}
+
+/// The `sequencia` of the first entry that does not follow from the ones
+/// before it.
+fn first_break(entries: &[AuditEntry]) -> Option<u64> {
+    let mut previous = GENESIS;
+    for (position, entry) in entries.iter().enumerate() {
+        if entry.sequencia != position as u64 + 1
+            || entry.hash_anterior != previous
+            || entry.hash != entry.digest()
+        {
+            return Some(entry.sequencia);
+        }
+        previous = &entry.hash;
+    }
+    None
+}
+
+/// One entry per line. A line that does not parse is left out, which the
+/// chain then shows unless it was the last one.
+fn read_entries(path: &Path) -> Result<Vec<AuditEntry>, BoxError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => warn!("skipping line {} of the audit trail: {err}", number + 1),
+        }
+    }
+    Ok(entries)
+}
+
+struct Trail {
+    entries: Vec<AuditEntry>,
+    file: Option<File>,
+}
+
+/// Append-only record of the administrative actions taken, apart from the
+/// clients' transactions. Each entry carries the hash of the one before it,
+/// so an edited or deleted entry shows in `GET /admin/audit`; cutting off the
+/// newest ones is only caught against a copy of the last hash kept elsewhere.
+pub struct Audit {
+    clock: Arc<dyn Clock>,
+    trail: Mutex<Trail>,
+}
+
+impl Audit {
+    pub fn open(path: Option<&Path>, clock: Arc<dyn Clock>) -> Result<Self, BoxError> {
+        let (entries, file) = match path {
+            Some(path) => {
+                let entries = read_entries(path)?;
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                (entries, Some(file))
+            }
+            None => (Vec::new(), None),
+        };
+
+        if let Some(sequencia) = first_break(&entries) {
+            warn!(
+                sequencia,
+                "the audit trail was changed after it was written"
+            );
+        }
+
+        Ok(Audit {
+            clock,
+            trail: Mutex::new(Trail { entries, file }),
+        })
+    }
+
+    /// Failing to append to the file is logged and not passed on: the action
+    /// already happened, and the entry is still served until the restart.
+    pub fn record(&self, acao: &str, tenant: Option<&str>, detalhes: Value) {
+        let mut trail = self.trail.lock().unwrap();
+        let (sequencia, hash_anterior) = match trail.entries.last() {
+            Some(last) => (last.sequencia + 1, last.hash.clone()),
+            None => (1, GENESIS.to_owned()),
+        };
+
+        let mut entry = AuditEntry {
+            sequencia,
+            realizado_em: self.clock.now(),
+            acao: acao.to_owned(),
+            tenant: tenant.map(str::to_owned),
+            detalhes,
+            hash_anterior,
+            hash: String::new(),
+        };
+        entry.hash = entry.digest();
+
+        if let Some(file) = &mut trail.file {
+            let mut line = serde_json::to_string(&entry).expect("audit entries serialize");
+            line.push('\n');
+            if let Err(err) = file.write_all(line.as_bytes()) {
+                error!(sequencia, "failed to append to the audit trail: {err}");
+            }
+        }
+        trail.entries.push(entry);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    /// Only entries after this `sequencia`.
+    pub desde: Option<u64>,
+    pub acao: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AuditTrail {
+    /// Whether the whole chain checks out, not only the entries returned.
+    pub integra: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quebra_em: Option<u64>,
+    pub entradas: Vec<AuditEntry>,
+}
+
+pub async fn trail(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Json<AuditTrail> {
+    let trail = state.audit.trail.lock().unwrap();
+    let quebra_em = first_break(&trail.entries);
+    let entradas = trail
+        .entries
+        .iter()
+        .filter(|entry| query.desde.is_none_or(|desde| entry.sequencia > desde))
+        .filter(|entry| query.acao.as_ref().is_none_or(|acao| entry.acao == *acao))
+        .cloned()
+        .collect();
+
+    Json(AuditTrail {
+        integra: quebra_em.is_none(),
+        quebra_em,
+        entradas,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn entries() -> Vec<AuditEntry> {
+        let clock = Arc::new(ManualClock::new("2024-03-01T12:00:00Z".parse().unwrap()));
+        let audit = Audit::open(None, clock.clone()).unwrap();
+        for cliente in 1..=4 {
+            clock.set(clock.now() + chrono::TimeDelta::minutes(1));
+            audit.record("cliente_desativado", None, json!({ "cliente": cliente }));
+        }
+        let entries = audit.trail.lock().unwrap().entries.clone();
+        entries
+    }
+
+    #[test]
+    fn an_untouched_trail_has_no_break() {
+        assert_eq!(first_break(&entries()), None);
+        assert_eq!(first_break(&[]), None);
+    }
+
+    #[test]
+    fn an_edited_entry_breaks_the_chain_there() {
+        let mut entries = entries();
+        entries[1].detalhes = json!({ "cliente": 5 });
+        assert_eq!(first_break(&entries), Some(2));
+    }
+
+    #[test]
+    fn an_entry_edited_along_with_its_hash_breaks_the_chain_after_it() {
+        let mut entries = entries();
+        entries[1].detalhes = json!({ "cliente": 5 });
+        entries[1].hash = entries[1].digest();
+        assert_eq!(first_break(&entries), Some(3));
+    }
+
+    #[test]
+    fn a_deleted_entry_breaks_the_chain_at_the_next_one() {
+        let mut entries = entries();
+        entries.remove(1);
+        assert_eq!(first_break(&entries), Some(3));
+    }
+
+    #[test]
+    fn reordered_entries_break_the_chain_at_the_first_out_of_place() {
+        let mut entries = entries();
+        entries.swap(1, 2);
+        assert_eq!(first_break(&entries), Some(3));
+    }
+}
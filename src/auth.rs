@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// The dashboard page holds no data and asks for the token itself.
+const OPEN: &[&str] = &["/admin/ui"];
+
+/// The admin routes, and deactivating or changing a client, which are
+/// audited as admin actions too.
+fn is_admin(method: &Method, path: &str) -> bool {
+    let client = path
+        .strip_prefix("/clientes/")
+        .is_some_and(|id| !id.is_empty() && !id.contains('/'));
+    path == "/admin"
+        || path.starts_with("/admin/")
+        || (client && matches!(*method, Method::DELETE | Method::PATCH))
+}
+
+/// Compares in time that depends on the lengths only, so the token cannot be
+/// guessed a byte at a time.
+fn same_token(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |differ, (a, b)| differ | (a ^ b))
+            == 0
+}
+
+/// Turns away admin requests without `Authorization: Bearer` and the
+/// `--admin-token`, with a 401, or all of them with a 403 when no token is
+/// set.
+pub async fn require_admin(
+    State(token): State<Option<Arc<str>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    if !is_admin(request.method(), path) || OPEN.contains(&path) {
+        return next.run(request).await;
+    }
+    let Some(token) = token else {
+        return (
+            StatusCode::FORBIDDEN,
+            "admin routes are off, start with --admin-token to use them",
+        )
+            .into_response();
+    };
+
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if same_token(given.trim().as_bytes(), token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_changes_need_the_admin_token() {
+        assert!(is_admin(&Method::DELETE, "/clientes/1"));
+        assert!(is_admin(&Method::PATCH, "/clientes/1"));
+        assert!(!is_admin(&Method::GET, "/clientes/1/extrato"));
+        assert!(!is_admin(&Method::POST, "/clientes/1/transacoes"));
+        assert!(!is_admin(&Method::GET, "/clientes"));
+        assert!(is_admin(&Method::GET, "/admin/stats"));
+        assert!(!is_admin(&Method::GET, "/administrador"));
+    }
+
+    #[test]
+    fn tokens_must_match_whole() {
+        assert!(same_token(b"secreto", b"secreto"));
+        assert!(!same_token(b"secret", b"secreto"));
+        assert!(!same_token(b"secreta", b"secreto"));
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+
+/// Parses a `--base-path` such as `/banco/api`, without its trailing `/`.
+pub fn parse(path: &str) -> Result<String, String> {
+    let path = path.trim().trim_end_matches('/');
+    if path.is_empty() {
+        return Err("the base path must not be empty, leave it unset instead".to_owned());
+    }
+    if !path.starts_with('/') {
+        return Err(format!("base path `{path}` must start with /"));
+    }
+    if path.contains(['?', '#']) {
+        return Err(format!(
+            "base path `{path}` must not have a query or fragment"
+        ));
+    }
+    Ok(path.to_owned())
+}
+
+/// The part of `path` after `base`, when it lies under it.
+fn strip<'a>(base: &str, path: &'a str) -> Option<&'a str> {
+    match path.strip_prefix(base)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+async fn strip_base(State(base): State<Arc<str>>, mut request: Request, next: Next) -> Response {
+    let uri = request.uri();
+    let Some(path) = strip(&base, uri.path()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let stripped = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_owned(),
+    };
+    match stripped.parse::<Uri>() {
+        Ok(stripped) => *request.uri_mut() = stripped,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    }
+    next.run(request).await
+}
+
+/// Serves `app` under `base` only. The prefix comes off before `app` routes
+/// the request, so its routes, the rules matching them and the route labels
+/// in the metrics stay as they are without one; `OriginalUri` still has it.
+pub fn mount(base: &str, app: Router) -> Router {
+    Router::new()
+        .fallback_service(app)
+        .layer(middleware::from_fn_with_state(Arc::from(base), strip_base))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_paths_lose_their_trailing_slash() {
+        assert_eq!(parse("/banco/api/"), Ok("/banco/api".to_owned()));
+        assert_eq!(parse(" /api "), Ok("/api".to_owned()));
+    }
+
+    #[test]
+    fn malformed_base_paths_are_refused() {
+        for path in ["", "/", "api", "/api?x=1", "/api#top"] {
+            assert!(parse(path).is_err(), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn only_paths_under_the_base_are_stripped() {
+        assert_eq!(strip("/api", "/api"), Some("/"));
+        assert_eq!(strip("/api", "/api/"), Some("/"));
+        assert_eq!(
+            strip("/api", "/api/clientes/1/extrato"),
+            Some("/clientes/1/extrato")
+        );
+        assert_eq!(strip("/api", "/apix"), None);
+        assert_eq!(strip("/api", "/apix/clientes"), None);
+        assert_eq!(strip("/api", "/clientes"), None);
+        assert_eq!(strip("/banco/api", "/banco"), None);
+    }
+}
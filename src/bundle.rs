@@ -0,0 +1,452 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read},
+    path::Path as FsPath,
+};
+
+use axum::{
+    extract::Path,
+    http::{header, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::{
+    domain,
+    models::{BundleCheck, BundleFile, BundleManifest, Statement, User},
+    settings::BoxError,
+    tenants::Tenant,
+};
+
+const MANIFEST: &str = "manifesto.json";
+const HISTORY: &str = "historico.jsonl";
+
+/// Response header with the bundle's [`BundleManifest::endereco`].
+const ADDRESS: HeaderName = HeaderName::from_static("x-bundle-address");
+
+fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn address(manifest: &BundleManifest) -> String {
+    let content = (
+        manifest.cliente,
+        manifest.limite,
+        manifest.saldo_inicial,
+        manifest.saldo,
+        manifest.ultima_sequencia,
+        &manifest.arquivos,
+    );
+    sha256(&serde_json::to_vec(&content).expect("manifests serialize"))
+}
+
+/// A gzipped tar with the client's statements, one JSON per line and oldest
+/// first, and a manifest with their checksum and the balances they start
+/// from and lead to.
+/// Entries carry no timestamps or owners, so only the manifest's
+/// `exportado_em` tells two exports of the same history apart.
+fn build(
+    user: &User,
+    history: &[Statement],
+    exportado_em: DateTime<Utc>,
+) -> io::Result<(BundleManifest, Vec<u8>)> {
+    let mut lines = Vec::new();
+    for statement in history {
+        serde_json::to_writer(&mut lines, statement)?;
+        lines.push(b'\n');
+    }
+    let moved: i64 = history
+        .iter()
+        .map(|statement| domain::signed_valor(&statement.tipo, statement.valor))
+        .sum();
+
+    let mut manifest = BundleManifest {
+        endereco: String::new(),
+        cliente: user.id,
+        exportado_em,
+        limite: user.limite,
+        saldo_inicial: i64::from(user.saldo) - moved,
+        saldo: user.saldo,
+        ultima_sequencia: user.ultima_sequencia,
+        arquivos: vec![BundleFile {
+            nome: HISTORY.to_owned(),
+            sha256: sha256(&lines),
+            bytes: lines.len() as u64,
+        }],
+    };
+    manifest.endereco = address(&manifest);
+
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (name, contents) in [
+        (MANIFEST, serde_json::to_vec_pretty(&manifest)?),
+        (HISTORY, lines),
+    ] {
+        let mut entry = tar::Header::new_gnu();
+        entry.set_size(contents.len() as u64);
+        entry.set_mode(0o644);
+        entry.set_cksum();
+        archive.append_data(&mut entry, name, contents.as_slice())?;
+    }
+    let bundle = archive.into_inner()?.finish()?;
+
+    Ok((manifest, bundle))
+}
+
+enum ExportResult {
+    Success(BundleManifest, Vec<u8>),
+    NotFound,
+    InternalError,
+}
+
+impl IntoResponse for ExportResult {
+    fn into_response(self) -> Response {
+        match self {
+            ExportResult::Success(manifest, bundle) => {
+                let disposition = format!(r#"attachment; filename="{}.tar.gz""#, manifest.endereco);
+                (
+                    [
+                        (header::CONTENT_TYPE, "application/gzip".to_owned()),
+                        (header::CONTENT_DISPOSITION, disposition),
+                        (ADDRESS, manifest.endereco),
+                    ],
+                    bundle,
+                )
+                    .into_response()
+            }
+            ExportResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            ExportResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+/// The client's whole history as a bundle the `verify` subcommand can check,
+/// read as of one point in time together with the balance.
+pub async fn export(Tenant(state): Tenant, Path(user_id): Path<i32>) -> impl IntoResponse {
+    let (user, history) = match state.storage.history(user_id).await {
+        Ok(Some(found)) => found,
+        Ok(None) => return ExportResult::NotFound,
+        Err(err) => {
+            error!("failed to read history of client {user_id} for export: {err}");
+            return ExportResult::InternalError;
+        }
+    };
+
+    match build(&user, &history, state.clock.now()) {
+        Ok((manifest, bundle)) => {
+            state.audit.record(
+                "historico_exportado",
+                state.tenant.as_deref(),
+                json!({
+                    "cliente": user_id,
+                    "endereco": manifest.endereco,
+                    "transacoes": history.len(),
+                }),
+            );
+            ExportResult::Success(manifest, bundle)
+        }
+        Err(err) => {
+            error!("failed to bundle history of client {user_id}: {err}");
+            ExportResult::InternalError
+        }
+    }
+}
+
+/// What replaying `statements` from the manifest's opening balance finds
+/// wrong with the balance and sequence it claims. The limit is not checked:
+/// the client's may have been another when older statements were made.
+fn replay(manifest: &BundleManifest, statements: &[Statement]) -> Vec<String> {
+    let mut problemas = Vec::new();
+    let mut saldo = manifest.saldo_inicial;
+    let mut in_sequence = true;
+    for (position, statement) in statements.iter().enumerate() {
+        if statement.user_id != manifest.cliente {
+            problemas.push(format!(
+                "statement {} belongs to client {}",
+                statement.sequencia, statement.user_id
+            ));
+        }
+        // Only the first one out of place; the rest would follow from it.
+        if in_sequence && statement.sequencia != position as i64 + 1 {
+            problemas.push(format!(
+                "statement {} of {HISTORY} has sequencia {}",
+                position + 1,
+                statement.sequencia
+            ));
+            in_sequence = false;
+        }
+        saldo += domain::signed_valor(&statement.tipo, statement.valor);
+    }
+
+    let ultima_sequencia = statements.len() as i64;
+    if ultima_sequencia != manifest.ultima_sequencia {
+        problemas.push(format!(
+            "{HISTORY} ends at sequencia {ultima_sequencia}, the manifest at {}",
+            manifest.ultima_sequencia
+        ));
+    }
+    if saldo != i64::from(manifest.saldo) {
+        problemas.push(format!(
+            "{HISTORY} leads to saldo {saldo}, the manifest has {}",
+            manifest.saldo
+        ));
+    }
+    problemas
+}
+
+/// Checks every file of a bundle against the manifest, the manifest against
+/// its address, and the balance and sequence against the history replayed.
+/// Only a bundle that cannot be read at all is an error; anything else
+/// wrong is reported as a problem.
+pub fn verify(path: &FsPath) -> Result<BundleCheck, BoxError> {
+    let mut files = BTreeMap::new();
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.insert(name, contents);
+    }
+
+    let manifest: BundleManifest = match files.remove(MANIFEST) {
+        Some(manifest) => serde_json::from_slice(&manifest)?,
+        None => return Err(format!("the bundle has no {MANIFEST}").into()),
+    };
+
+    let mut problemas = Vec::new();
+    if address(&manifest) != manifest.endereco {
+        problemas.push("the manifest does not match its address".to_owned());
+    }
+    for file in &manifest.arquivos {
+        let Some(contents) = files.remove(&file.nome) else {
+            problemas.push(format!("{} is missing", file.nome));
+            continue;
+        };
+        if contents.len() as u64 != file.bytes || sha256(&contents) != file.sha256 {
+            problemas.push(format!("{} does not match its checksum", file.nome));
+        } else if file.nome == HISTORY {
+            let statements: Result<Vec<Statement>, _> = contents
+                .split(|&b| b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(serde_json::from_slice)
+                .collect();
+            match statements {
+                Ok(statements) => problemas.extend(replay(&manifest, &statements)),
+                Err(_) => problemas.push(format!("{HISTORY} has lines that are not statements")),
+            }
+        }
+    }
+    for name in files.keys() {
+        problemas.push(format!("{name} is not in the manifest"));
+    }
+
+    Ok(BundleCheck {
+        endereco: manifest.endereco,
+        integro: problemas.is_empty(),
+        problemas,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn history() -> (User, Vec<Statement>) {
+        let at: DateTime<Utc> = "2024-03-01T12:00:00Z".parse().unwrap();
+        let statement = |sequencia: i64, valor, tipo: &str| Statement {
+            id: sequencia as i32,
+            uuid: Uuid::from_u64_pair(0, sequencia as u64),
+            sequencia,
+            valor,
+            tipo: tipo.to_owned(),
+            descricao: "teste".to_owned(),
+            categoria: None,
+            tags: Vec::new(),
+            realizado_em: at + chrono::TimeDelta::minutes(sequencia),
+            user_id: 1,
+        };
+        let user = User {
+            id: 1,
+            limite: 1000,
+            saldo: 300,
+            ativo: true,
+            ultima_sequencia: 2,
+            alerta_percentual: None,
+        };
+        (user, vec![statement(1, 500, "c"), statement(2, 200, "d")])
+    }
+
+    fn unpack(bundle: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut archive = tar::Archive::new(GzDecoder::new(bundle));
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                (name, contents)
+            })
+            .collect()
+    }
+
+    fn pack(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (name, contents) in files {
+            let mut entry = tar::Header::new_gnu();
+            entry.set_size(contents.len() as u64);
+            entry.set_mode(0o644);
+            entry.set_cksum();
+            archive
+                .append_data(&mut entry, name, contents.as_slice())
+                .unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn check(name: &str, bundle: &[u8]) -> BundleCheck {
+        let path =
+            std::env::temp_dir().join(format!("bundle-{}-{name}.tar.gz", std::process::id()));
+        File::create(&path).unwrap().write_all(bundle).unwrap();
+        let check = verify(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        check
+    }
+
+    fn built() -> (BundleManifest, Vec<u8>) {
+        let (user, history) = history();
+        build(&user, &history, "2024-03-02T00:00:00Z".parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn a_bundle_as_built_verifies() {
+        let (manifest, bundle) = built();
+        let check = check("clean", &bundle);
+
+        assert!(check.integro, "{:?}", check.problemas);
+        assert_eq!(check.endereco, manifest.endereco);
+    }
+
+    #[test]
+    fn the_address_ignores_when_it_was_exported() {
+        let (user, history) = history();
+        let (a, _) = build(&user, &history, "2024-03-02T00:00:00Z".parse().unwrap()).unwrap();
+        let (b, _) = build(&user, &history, "2024-04-02T00:00:00Z".parse().unwrap()).unwrap();
+        assert_eq!(a.endereco, b.endereco);
+    }
+
+    #[test]
+    fn an_edited_history_fails_its_checksum() {
+        let (_, bundle) = built();
+        let mut files = unpack(&bundle);
+        let (_, history) = files.iter_mut().find(|(name, _)| name == HISTORY).unwrap();
+        let edited = String::from_utf8(history.clone())
+            .unwrap()
+            .replace("500", "900");
+        *history = edited.into_bytes();
+
+        let check = check("history", &pack(&files));
+        assert!(!check.integro);
+        assert_eq!(
+            check.problemas,
+            [format!("{HISTORY} does not match its checksum")]
+        );
+    }
+
+    #[test]
+    fn an_edited_manifest_no_longer_matches_its_address() {
+        let (mut manifest, bundle) = built();
+        manifest.saldo = 5000;
+        let mut files = unpack(&bundle);
+        files[0] = (MANIFEST.to_owned(), serde_json::to_vec(&manifest).unwrap());
+
+        let check = check("manifest", &pack(&files));
+        assert!(!check.integro);
+        assert!(check
+            .problemas
+            .contains(&"the manifest does not match its address".to_owned()));
+    }
+
+    #[test]
+    fn missing_and_extra_files_are_reported() {
+        let (_, bundle) = built();
+        let mut files = unpack(&bundle);
+        files.retain(|(name, _)| name != HISTORY);
+        files.push(("extra.txt".to_owned(), b"oi".to_vec()));
+
+        let check = check("files", &pack(&files));
+        assert_eq!(
+            check.problemas,
+            [
+                format!("{HISTORY} is missing"),
+                "extra.txt is not in the manifest".to_owned()
+            ]
+        );
+    }
+
+    /// Replaces the manifest with `manifest`, addressed again, and the
+    /// history with `history` when given, with the checksum to match.
+    fn forged(mut manifest: BundleManifest, history: Option<Vec<u8>>) -> Vec<u8> {
+        let mut files = Vec::new();
+        if let Some(history) = history {
+            manifest.arquivos[0].sha256 = sha256(&history);
+            manifest.arquivos[0].bytes = history.len() as u64;
+            files.push((HISTORY.to_owned(), history));
+        } else {
+            files.extend(
+                unpack(&built().1)
+                    .into_iter()
+                    .filter(|(name, _)| name == HISTORY),
+            );
+        }
+        manifest.endereco = address(&manifest);
+        files.insert(
+            0,
+            (MANIFEST.to_owned(), serde_json::to_vec(&manifest).unwrap()),
+        );
+        pack(&files)
+    }
+
+    #[test]
+    fn a_balance_the_history_does_not_lead_to_is_reported() {
+        let (mut manifest, _) = built();
+        manifest.saldo = 5000;
+
+        let check = check("saldo", &forged(manifest, None));
+        assert_eq!(
+            check.problemas,
+            [format!(
+                "{HISTORY} leads to saldo 300, the manifest has 5000"
+            )]
+        );
+    }
+
+    #[test]
+    fn a_dropped_statement_is_reported_against_the_sequence() {
+        let (manifest, bundle) = built();
+        let files = unpack(&bundle);
+        let (_, history) = files.iter().find(|(name, _)| name == HISTORY).unwrap();
+        let last = history.split(|&b| b == b'\n').nth(1).unwrap();
+        let history = [last, b"\n"].concat();
+
+        let check = check("sequence", &forged(manifest, Some(history)));
+        assert_eq!(
+            check.problemas,
+            [
+                format!("statement 1 of {HISTORY} has sequencia 2"),
+                format!("{HISTORY} ends at sequencia 1, the manifest at 2"),
+                format!("{HISTORY} leads to saldo -200, the manifest has 300"),
+            ]
+        );
+    }
+}
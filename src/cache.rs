@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::{
+    alerts,
+    models::{LastTransaction, User},
+};
+
+/// An extrato with everything but `data_extrato` already serialized, so a hit
+/// costs one `format!` instead of a storage read plus serialization.
+pub struct CachedStatement {
+    /// Newest transaction, as used for `Last-Modified`.
+    pub latest: Option<DateTime<Utc>>,
+    total: i32,
+    limite: i32,
+    alerta: bool,
+    ultimas_transacoes: String,
+}
+
+impl CachedStatement {
+    pub fn render(&self, data_extrato: DateTime<Utc>) -> String {
+        // Same format chrono's `Serialize` produces for the uncached response.
+        format!(
+            r#"{{"saldo":{{"total":{},"data_extrato":"{}","limite":{},"alerta":{}}},"ultimas_transacoes":{}}}"#,
+            self.total,
+            data_extrato.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            self.limite,
+            self.alerta,
+            self.ultimas_transacoes
+        )
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Bumped on every invalidation; an insert is dropped when it changed
+    /// since the caller read storage, so a stale read can't outlive a write.
+    generation: u64,
+    statements: HashMap<i32, Arc<CachedStatement>>,
+}
+
+/// Per-client extrato cache. Entries are only invalidated by writes made
+/// through this process, so it must stay disabled when several instances
+/// share a backend.
+pub struct StatementCache {
+    enabled: bool,
+    entries: RwLock<Entries>,
+}
+
+impl StatementCache {
+    pub fn new(enabled: bool) -> Self {
+        StatementCache {
+            enabled,
+            entries: RwLock::new(Entries::default()),
+        }
+    }
+
+    /// The current generation, or `None` when caching is disabled. Read it
+    /// before going to storage and hand it back to [`StatementCache::insert`].
+    pub fn generation(&self) -> Option<u64> {
+        self.enabled
+            .then(|| self.entries.read().unwrap().generation)
+    }
+
+    pub fn get(&self, user_id: i32) -> Option<Arc<CachedStatement>> {
+        if !self.enabled {
+            return None;
+        }
+
+        self.entries
+            .read()
+            .unwrap()
+            .statements
+            .get(&user_id)
+            .cloned()
+    }
+
+    pub fn insert(
+        &self,
+        generation: u64,
+        user: &User,
+        ultimas_transacoes: &[LastTransaction],
+        latest: Option<DateTime<Utc>>,
+    ) -> Result<Arc<CachedStatement>, serde_json::Error> {
+        let cached = Arc::new(CachedStatement {
+            latest,
+            total: user.saldo,
+            limite: user.limite,
+            alerta: alerts::in_alert(user),
+            ultimas_transacoes: serde_json::to_string(ultimas_transacoes)?,
+        });
+
+        let mut entries = self.entries.write().unwrap();
+        if entries.generation == generation {
+            entries.statements.insert(user.id, cached.clone());
+        }
+
+        Ok(cached)
+    }
+
+    pub fn invalidate(&self, user_id: i32) {
+        let mut entries = self.entries.write().unwrap();
+        entries.generation += 1;
+        entries.statements.remove(&user_id);
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.write().unwrap();
+        entries.generation += 1;
+        entries.statements.clear();
+    }
+}
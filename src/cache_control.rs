@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::cli::Config;
+
+/// Built in, and tried after any given with `--cache-control`. Transactions
+/// must never be replayed from a cache; extratos may be kept but have to be
+/// revalidated, which `If-Modified-Since` makes cheap.
+const DEFAULTS: &[&str] = &[
+    "POST /clientes/:id/transacoes=no-store",
+    "GET /clientes/:id/extrato*=private, no-cache",
+    "GET /clientes*=private, no-cache",
+    "/admin*=no-store",
+    "/metrics=no-store",
+];
+
+#[derive(Clone)]
+pub struct Rule {
+    method: Option<Method>,
+    route: String,
+    prefix: bool,
+    value: Option<HeaderValue>,
+}
+
+impl Rule {
+    fn matches(&self, method: &Method, route: &str) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method)
+            && if self.prefix {
+                route.starts_with(&self.route)
+            } else {
+                route == self.route
+            }
+    }
+}
+
+/// Parses `[METHOD ]ROUTE=VALUE`, e.g. `GET /clientes/:id/extrato=max-age=2`.
+pub fn parse_rule(rule: &str) -> Result<Rule, String> {
+    let (target, value) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("expected ROUTE=VALUE, got `{rule}`"))?;
+
+    let (method, route) = match target.trim().split_once(' ') {
+        Some((method, route)) => {
+            let method = method
+                .parse::<Method>()
+                .map_err(|_| format!("invalid method `{method}`"))?;
+            (Some(method), route.trim())
+        }
+        None => (None, target.trim()),
+    };
+
+    if !route.starts_with('/') {
+        return Err(format!("route `{route}` must start with /"));
+    }
+
+    let value = value.trim();
+    let value = if value.is_empty() {
+        None
+    } else {
+        Some(HeaderValue::from_str(value).map_err(|_| format!("invalid header value `{value}`"))?)
+    };
+
+    Ok(Rule {
+        method,
+        prefix: route.ends_with('*'),
+        route: route.trim_end_matches('*').to_owned(),
+        value,
+    })
+}
+
+/// The `Cache-Control` each route answers with, decided by the first rule
+/// matching its method and route pattern.
+pub struct CachePolicy {
+    rules: Vec<Rule>,
+}
+
+impl CachePolicy {
+    pub fn new(config: &Config) -> Self {
+        let defaults = DEFAULTS
+            .iter()
+            .map(|rule| parse_rule(rule).expect("built-in cache rules are valid"));
+
+        CachePolicy {
+            rules: config
+                .cache_control
+                .iter()
+                .cloned()
+                .chain(defaults)
+                .collect(),
+        }
+    }
+
+    fn value(&self, method: &Method, route: &str) -> Option<&HeaderValue> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(method, route))
+            .and_then(|rule| rule.value.as_ref())
+    }
+}
+
+/// Only successful and not-modified responses get the header, and never one
+/// a handler already set, such as the event streams' `no-cache`.
+pub async fn apply(
+    State(policy): State<Arc<CachePolicy>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned());
+
+    let mut response = next.run(request).await;
+
+    let status = response.status();
+    if !(status.is_success() || status.as_u16() == 304)
+        || response.headers().contains_key(header::CACHE_CONTROL)
+    {
+        return response;
+    }
+
+    if let Some(value) = route.and_then(|route| policy.value(&method, &route)) {
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, value.clone());
+    }
+    response
+}
@@ -0,0 +1,141 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{audit::Audit, cli::Config, metrics, validation::Valid};
+
+const INJECTED: &str = "chaos_injected_total";
+
+/// What to inject, each as a percentage of the client API requests. The
+/// draws are independent, so a request can be both delayed and failed.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChaosSettings {
+    #[serde(default)]
+    pub latencia_ms: u64,
+    #[serde(default)]
+    pub latencia_percentual: f64,
+    #[serde(default)]
+    pub erro_percentual: f64,
+    #[serde(default)]
+    pub queda_percentual: f64,
+}
+
+/// Fault injection for resilience tests. Only installed with `--chaos`; the
+/// admin routes stay untouched so it can always be turned back off.
+pub struct Chaos {
+    settings: RwLock<ChaosSettings>,
+    rng: AtomicU64,
+}
+
+impl Chaos {
+    pub fn new(config: &Config) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64);
+
+        Chaos {
+            settings: RwLock::new(ChaosSettings {
+                latencia_ms: config.chaos_latency_ms,
+                latencia_percentual: f64::from(config.chaos_latency_percent),
+                erro_percentual: f64::from(config.chaos_error_percent),
+                queda_percentual: f64::from(config.chaos_drop_percent),
+            }),
+            rng: AtomicU64::new(seed | 1),
+        }
+    }
+
+    /// xorshift64; good enough to pick victims, and avoids a dependency.
+    fn roll(&self, percent: f64) -> bool {
+        if percent <= 0.0 {
+            return false;
+        }
+
+        let mut x = self.rng.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.store(x, Ordering::Relaxed);
+
+        (x % 10_000) as f64 / 100.0 < percent
+    }
+}
+
+fn injected(kind: &'static str) {
+    metrics::counter(INJECTED, vec![("kind", kind.to_owned())]).inc();
+}
+
+/// A body that fails right after the headers, which makes hyper cut the
+/// connection instead of finishing the response.
+fn dropped_connection() -> Response {
+    let failing = stream::once(async {
+        Err::<Vec<u8>, _>(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "chaos: connection dropped",
+        ))
+    });
+    Response::new(Body::from_stream(failing))
+}
+
+pub async fn inject(State(chaos): State<Arc<Chaos>>, request: Request, next: Next) -> Response {
+    if !request.uri().path().starts_with("/clientes") {
+        return next.run(request).await;
+    }
+
+    let settings = chaos.settings.read().unwrap().clone();
+
+    if chaos.roll(settings.latencia_percentual) {
+        injected("latency");
+        tokio::time::sleep(Duration::from_millis(settings.latencia_ms)).await;
+    }
+    if chaos.roll(settings.queda_percentual) {
+        injected("drop");
+        return dropped_connection();
+    }
+    if chaos.roll(settings.erro_percentual) {
+        injected("error");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    next.run(request).await
+}
+
+pub async fn settings(State((chaos, _)): State<(Arc<Chaos>, Arc<Audit>)>) -> Json<ChaosSettings> {
+    Json(chaos.settings.read().unwrap().clone())
+}
+
+pub async fn configure(
+    State((chaos, audit)): State<(Arc<Chaos>, Arc<Audit>)>,
+    Valid(settings): Valid<ChaosSettings>,
+) -> Json<ChaosSettings> {
+    info!(
+        latencia_ms = settings.latencia_ms,
+        latencia_percentual = settings.latencia_percentual,
+        erro_percentual = settings.erro_percentual,
+        queda_percentual = settings.queda_percentual,
+        "chaos settings changed"
+    );
+    *chaos.settings.write().unwrap() = settings.clone();
+    audit.record(
+        "caos_configurado",
+        None,
+        serde_json::to_value(&settings).unwrap_or_default(),
+    );
+    Json(settings)
+}
@@ -0,0 +1,492 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    base_path,
+    cache_control::{self, Rule},
+    duplicates::DuplicateMode,
+    fields::FieldNames,
+    ids::{IdStrategy, MAX_NODE_ID},
+    interest,
+    lanes::ReadLane,
+    metrics::{http, statsd::Flavor},
+    quotas::{self, ClientQuota},
+    storage::Backend,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Rinha de Backend 2024/Q1 API")]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: Config,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Args, Clone)]
+pub struct Config {
+    /// Storage backend holding clients and transactions
+    #[arg(
+        long,
+        env = "BACKEND",
+        value_enum,
+        default_value = "memory",
+        global = true
+    )]
+    pub backend: Backend,
+
+    /// Also apply every write to this backend and count where it disagrees
+    /// with --backend; reads still come from --backend only
+    #[arg(long, env = "SHADOW_BACKEND", value_enum, global = true)]
+    pub shadow_backend: Option<Backend>,
+
+    /// Keep this many of each client's newest statements in memory and read
+    /// older ones from the backend only when asked for; off when unset. Only
+    /// safe when this is the sole instance writing to the backend
+    #[arg(
+        long,
+        env = "HOT_STATEMENTS",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        global = true
+    )]
+    pub hot_statements: Option<usize>,
+
+    /// When the backend fails a transaction of a client held by
+    /// --hot-statements, commit it against the held balance and write it to
+    /// the backend once it recovers, queueing at most this many; off when
+    /// unset
+    #[arg(
+        long,
+        env = "STATEMENT_REPLAY_CAPACITY",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        global = true
+    )]
+    pub statement_replay_capacity: Option<usize>,
+
+    /// Address the HTTP API listens on
+    #[arg(long, env = "BIND_ADDR", default_value = "0.0.0.0:3000", global = true)]
+    pub bind: SocketAddr,
+
+    /// Path prefix the API is served under, e.g. `/banco/api` for a gateway
+    /// mounting it there; requests outside it get a 404. Routes in other
+    /// settings, such as --cache-control, are still given without it
+    #[arg(long, env = "BASE_PATH", value_parser = base_path::parse, global = true)]
+    pub base_path: Option<String>,
+
+    /// Also accept HTTP/2 over cleartext with prior knowledge, for proxies
+    /// that multiplex requests over a few connections. HTTP/1.1 keeps working
+    #[arg(long, env = "HTTP2", global = true)]
+    pub http2: bool,
+
+    /// Bind with SO_REUSEPORT, so several processes can listen on --bind
+    #[arg(long, env = "REUSE_PORT", global = true)]
+    pub reuse_port: bool,
+
+    /// Instances served by this one process, the first on --bind and each
+    /// next one on the port after. They share the storage backend, metrics,
+    /// tenants and audit trail; otherwise each has its own caches, queues and
+    /// guards, like a separate process would, and the snowflake node ids
+    /// after --node-id. --max-connections and --max-in-flight apply to each
+    #[arg(
+        long,
+        env = "REPLICAS",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        global = true
+    )]
+    pub replicas: u16,
+
+    /// Have --replicas share their caches, queues and guards as well, and
+    /// the --max-in-flight and chaos settings, as if they were one instance
+    /// listening on several ports
+    #[arg(long, env = "SHARE_REPLICA_STATE", global = true)]
+    pub share_replica_state: bool,
+
+    /// Unix socket for zero-downtime restarts: a process started with the
+    /// same path takes over the running one's state and traffic. Implies
+    /// --reuse-port
+    #[arg(long, env = "HANDOFF_SOCKET", global = true)]
+    pub handoff_socket: Option<PathBuf>,
+
+    /// How long a process being taken over waits for its open connections
+    /// to finish, and then for its queued writes, in seconds. Past it, the
+    /// handoff is refused and the process keeps serving
+    #[arg(long, env = "HANDOFF_DRAIN_SECS", default_value_t = 10, global = true)]
+    pub handoff_drain_secs: u64,
+
+    /// Connections kept open at once; new ones beyond it are closed right
+    /// after being accepted. Unlimited when unset
+    #[arg(long, env = "MAX_CONNECTIONS", global = true)]
+    pub max_connections: Option<usize>,
+
+    /// Log filter directive, e.g. `info` or `rust_lang=debug`
+    #[arg(long, env = "LOG_LEVEL", default_value = "info", global = true)]
+    pub log_level: String,
+
+    /// JSON file with settings re-read on SIGHUP or `POST /admin/reload`
+    #[arg(long, env = "SETTINGS_FILE", global = true)]
+    pub settings_file: Option<PathBuf>,
+
+    /// JSON array of clients used for seeding; limits are re-applied on reload
+    #[arg(long, env = "CLIENTS_FILE", global = true)]
+    pub clients_file: Option<PathBuf>,
+
+    /// File the admin audit trail is appended to and read back from on
+    /// start; without it the trail only lasts as long as the process
+    #[arg(long, env = "AUDIT_FILE", global = true)]
+    pub audit_file: Option<PathBuf>,
+
+    /// Token admin requests, and DELETE or PATCH /clientes/:id, must send as
+    /// `Authorization: Bearer`; they are off without it
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true, global = true)]
+    pub admin_token: Option<String>,
+
+    /// Latency each request should stay under for the SLO, in milliseconds
+    #[arg(long, env = "SLO_LATENCY_MS", default_value_t = 10, global = true)]
+    pub slo_latency_ms: u64,
+
+    /// Fraction of requests that must meet the latency SLO
+    #[arg(
+        long,
+        env = "SLO_TARGET",
+        default_value_t = 0.99,
+        value_parser = http::parse_target,
+        global = true
+    )]
+    pub slo_target: f64,
+
+    /// Also push metrics over UDP to this statsd agent, e.g. `127.0.0.1:8125`
+    #[arg(long, env = "STATSD_ADDR", global = true)]
+    pub statsd_addr: Option<String>,
+
+    /// Dialect spoken to --statsd-addr
+    #[arg(
+        long,
+        env = "STATSD_FLAVOR",
+        value_enum,
+        default_value = "statsd",
+        global = true
+    )]
+    pub statsd_flavor: Flavor,
+
+    /// Prepended to every metric name sent to statsd, e.g. `api1`
+    #[arg(long, env = "STATSD_PREFIX", default_value = "", global = true)]
+    pub statsd_prefix: String,
+
+    /// Comma-separated tags added to every metric, e.g. `env:prod,instance:api1`;
+    /// only sent with the dogstatsd flavor
+    #[arg(long, env = "STATSD_TAGS", value_delimiter = ',', global = true)]
+    pub statsd_tags: Vec<String>,
+
+    /// How often metrics are pushed to statsd, in seconds
+    #[arg(
+        long,
+        env = "STATSD_INTERVAL_SECS",
+        default_value_t = 10,
+        global = true
+    )]
+    pub statsd_interval_secs: u64,
+
+    /// Cache-Control for matching routes as `[METHOD ]ROUTE=VALUE`, e.g.
+    /// `GET /clientes/:id/extrato=private, max-age=2`; a trailing `*` matches
+    /// by prefix and an empty value sends none. Separate rules with `;`. They
+    /// are tried in order, before the built-in ones
+    #[arg(
+        long,
+        env = "CACHE_CONTROL",
+        value_delimiter = ';',
+        value_parser = cache_control::parse_rule,
+        global = true
+    )]
+    pub cache_control: Vec<Rule>,
+
+    /// Base URL of a candidate build to mirror client API requests to, for
+    /// comparing its responses with this one's
+    #[arg(long, env = "MIRROR_URL", global = true)]
+    pub mirror_url: Option<String>,
+
+    /// Percentage of client API requests mirrored to --mirror-url
+    #[arg(
+        long,
+        env = "MIRROR_PERCENT",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        global = true
+    )]
+    pub mirror_percent: u8,
+
+    /// Transactions that may wait for the writer before new ones get a 503
+    #[arg(
+        long,
+        env = "WRITE_QUEUE_CAPACITY",
+        default_value_t = 1024,
+        global = true
+    )]
+    pub write_queue_capacity: usize,
+
+    /// Transactions one client may have waiting before its new ones get a
+    /// 503, so a hot client cannot take the whole queue; defaults to
+    /// --write-queue-capacity
+    #[arg(long, env = "WRITE_QUEUE_CLIENT_CAPACITY", global = true)]
+    pub write_queue_client_capacity: Option<usize>,
+
+    /// How transaction ids are made. Sequential ones only stay unique with a
+    /// single process writing to the backend; its --replicas share them
+    #[arg(
+        long,
+        env = "ID_STRATEGY",
+        value_enum,
+        default_value = "uuidv7",
+        global = true
+    )]
+    pub id_strategy: IdStrategy,
+
+    /// This instance's number in snowflake ids; give each instance its own
+    #[arg(
+        long,
+        env = "NODE_ID",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u16).range(..=i64::from(MAX_NODE_ID)),
+        global = true
+    )]
+    pub node_id: u16,
+
+    /// Flag a transaction identical to one the same client made within this
+    /// many milliseconds; disabled when unset
+    #[arg(long, env = "DUPLICATE_WINDOW_MS", global = true)]
+    pub duplicate_window_ms: Option<u64>,
+
+    /// What happens to a transaction flagged by --duplicate-window-ms
+    #[arg(
+        long,
+        env = "DUPLICATE_MODE",
+        value_enum,
+        default_value = "reject",
+        global = true
+    )]
+    pub duplicate_mode: DuplicateMode,
+
+    /// Transactions a client may make in any 24 hours before getting a 429;
+    /// unlimited when unset
+    #[arg(long, env = "DAILY_TRANSACTION_QUOTA", global = true)]
+    pub daily_transaction_quota: Option<u32>,
+
+    /// Sum of `valor` a client may move in any 24 hours, credits and debits
+    /// alike; unlimited when unset
+    #[arg(long, env = "DAILY_VOLUME_QUOTA", global = true)]
+    pub daily_volume_quota: Option<i64>,
+
+    /// Quotas for one client as `ID=TRANSACOES[/VOLUME]`, e.g. `1=100/500000`.
+    /// A limit left empty falls back to the daily ones above. Separate
+    /// clients with `,`
+    #[arg(
+        long,
+        env = "CLIENT_QUOTA",
+        value_delimiter = ',',
+        value_parser = quotas::parse_client_quota,
+        global = true
+    )]
+    pub client_quota: Vec<ClientQuota>,
+
+    /// Extratos and transactions served at once; the others wait for a slot
+    /// or are shed. Unlimited when unset
+    #[arg(
+        long,
+        env = "MAX_IN_FLIGHT",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        global = true
+    )]
+    pub max_in_flight: Option<usize>,
+
+    /// Let --max-in-flight be the ceiling of a limit that follows latency:
+    /// it shrinks while the --slo-target quantile of the time requests take
+    /// is over --slo-latency-ms, and grows back while within it
+    #[arg(long, env = "ADAPTIVE_CONCURRENCY", global = true)]
+    pub adaptive_concurrency: bool,
+
+    /// Percentage of --max-in-flight extratos may hold, keeping the rest for
+    /// transactions
+    #[arg(
+        long,
+        env = "READ_SHARE",
+        default_value_t = 50,
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        global = true
+    )]
+    pub read_share: u8,
+
+    /// What happens to an extrato that finds no free slot under
+    /// --max-in-flight
+    #[arg(
+        long,
+        env = "READ_LANE",
+        value_enum,
+        default_value = "queue",
+        global = true
+    )]
+    pub read_lane: ReadLane,
+
+    /// How long a request waits for a slot under --max-in-flight before a 503
+    #[arg(long, env = "LANE_WAIT_MS", default_value_t = 100, global = true)]
+    pub lane_wait_ms: u64,
+
+    /// How long the answer to a transaction sent with an Idempotency-Key is
+    /// replayed to retries
+    #[arg(
+        long,
+        env = "IDEMPOTENCY_TTL_SECS",
+        default_value_t = 24 * 60 * 60,
+        global = true
+    )]
+    pub idempotency_ttl_secs: u64,
+
+    /// Idempotency records held in memory, for backends that do not keep
+    /// them; the least recently used ones go first
+    #[arg(
+        long,
+        env = "IDEMPOTENCY_CACHE_CAPACITY",
+        default_value_t = 10_000,
+        global = true
+    )]
+    pub idempotency_cache_capacity: usize,
+
+    /// Names of the fields in JSON responses, unless a request asks for
+    /// others with X-Field-Names
+    #[arg(
+        long,
+        env = "FIELD_NAMES",
+        value_enum,
+        default_value = "pt-BR",
+        global = true
+    )]
+    pub field_names: FieldNames,
+
+    /// Largest `quantidade` accepted on the extrato endpoint
+    #[arg(
+        long,
+        env = "EXTRATO_MAX_QUANTIDADE",
+        default_value_t = 100,
+        global = true
+    )]
+    pub extrato_max_quantidade: usize,
+
+    /// Cache serialized extratos until the client's next transaction; only
+    /// safe when this is the sole instance writing to the backend
+    #[arg(long, env = "EXTRATO_CACHE", global = true)]
+    pub extrato_cache: bool,
+
+    /// Charge this fraction of negative balances as interest each period;
+    /// disabled when unset
+    #[arg(long, env = "INTEREST_RATE", value_parser = interest::parse_rate, global = true)]
+    pub interest_rate: Option<f64>,
+
+    /// How often interest is charged, in seconds
+    #[arg(
+        long,
+        env = "INTEREST_INTERVAL_SECS",
+        default_value_t = 86400,
+        global = true
+    )]
+    pub interest_interval_secs: u64,
+
+    /// Exercise the read paths before accepting connections
+    #[arg(long, env = "WARMUP", global = true)]
+    pub warmup: bool,
+
+    /// Inject faults into client API requests; tune them with the CHAOS_*
+    /// settings or PUT /admin/chaos
+    #[arg(long, env = "CHAOS", global = true)]
+    pub chaos: bool,
+
+    /// Delay added to requests picked for latency injection, in milliseconds
+    #[arg(long, env = "CHAOS_LATENCY_MS", default_value_t = 0, global = true)]
+    pub chaos_latency_ms: u64,
+
+    /// Percentage of requests delayed by --chaos-latency-ms
+    #[arg(
+        long,
+        env = "CHAOS_LATENCY_PERCENT",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        global = true
+    )]
+    pub chaos_latency_percent: u8,
+
+    /// Percentage of requests answered with a 500
+    #[arg(
+        long,
+        env = "CHAOS_ERROR_PERCENT",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        global = true
+    )]
+    pub chaos_error_percent: u8,
+
+    /// Percentage of requests whose connection is cut mid-response
+    #[arg(
+        long,
+        env = "CHAOS_DROP_PERCENT",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        global = true
+    )]
+    pub chaos_drop_percent: u8,
+
+    /// Freeze the clock at this RFC 3339 time; it then only moves when set
+    /// through PUT /admin/clock. For deterministic test runs
+    #[arg(long, env = "CLOCK_START", global = true)]
+    pub clock_start: Option<DateTime<Utc>>,
+
+    /// Apply pending migrations before serving
+    #[arg(long, env = "AUTO_MIGRATE", global = true)]
+    pub auto_migrate: bool,
+
+    /// Connection string for the postgres backend
+    #[cfg(feature = "backend-postgres")]
+    #[arg(long, env = "DATABASE_URL", global = true)]
+    pub database_url: Option<String>,
+
+    /// Connection string for the redis backend
+    #[cfg(feature = "backend-redis")]
+    #[arg(
+        long,
+        env = "REDIS_URL",
+        default_value = "redis://127.0.0.1:6379",
+        global = true
+    )]
+    pub redis_url: String,
+
+    /// Directory holding the embedded rocksdb or sled database
+    #[cfg(any(feature = "backend-rocksdb", feature = "backend-sled"))]
+    #[arg(long, env = "DATA_DIR", default_value = "data", global = true)]
+    pub data_dir: PathBuf,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the HTTP API (default when no subcommand is given)
+    Serve,
+    /// Load clients into the configured backend
+    Seed {
+        /// JSON array of clients; defaults to --clients-file, then the five rinha clients
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Run schema migrations against the configured backend
+    Migrate,
+    /// Export clients and transactions as JSON
+    Dump {
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Check an export bundle from GET /admin/clientes/:id/exportar against
+    /// its manifest, without touching the backend
+    Verify {
+        /// The bundle's .tar.gz
+        bundle: PathBuf,
+    },
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+
+use crate::models::{ClientPatch, NewTransaction, StatementResponse, TransactionResponse, User};
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The API answered with something other than success.
+    Status {
+        status: StatusCode,
+        body: String,
+    },
+    Http(reqwest::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Status { status, body } if body.is_empty() => write!(f, "{status}"),
+            ClientError::Status { status, body } => write!(f, "{status}: {body}"),
+            ClientError::Http(err) => write!(f, "request failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        ClientError::Http(err)
+    }
+}
+
+impl ClientError {
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::Status { status, .. } => Some(*status),
+            ClientError::Http(err) => err.status(),
+        }
+    }
+}
+
+/// Typed calls against a running instance, e.g. `http://localhost:9999`.
+#[derive(Clone)]
+pub struct RinhaClient {
+    http: Client,
+    base: String,
+    tenant: Option<String>,
+}
+
+impl RinhaClient {
+    pub fn new(base: &str) -> Self {
+        RinhaClient {
+            http: Client::new(),
+            base: base.trim_end_matches('/').to_owned(),
+            tenant: None,
+        }
+    }
+
+    /// The same client, addressing the named tenant.
+    pub fn with_tenant(&self, tenant: &str) -> Self {
+        RinhaClient {
+            tenant: Some(tenant.to_owned()),
+            ..self.clone()
+        }
+    }
+
+    fn request(&self, builder: RequestBuilder) -> RequestBuilder {
+        match &self.tenant {
+            Some(tenant) => builder.header("x-tenant", tenant),
+            None => builder,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base)
+    }
+
+    async fn checked(response: Response) -> Result<Response, ClientError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        Err(ClientError::Status {
+            status,
+            body: response.text().await.unwrap_or_default(),
+        })
+    }
+
+    async fn json<T: DeserializeOwned>(builder: RequestBuilder) -> Result<T, ClientError> {
+        let response = Self::checked(builder.send().await?).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn create_transaction(
+        &self,
+        id: i32,
+        transaction: &NewTransaction,
+    ) -> Result<TransactionResponse, ClientError> {
+        let url = self.url(&format!("/clientes/{id}/transacoes"));
+        Self::json(self.request(self.http.post(url).json(transaction))).await
+    }
+
+    pub async fn get_statement(&self, id: i32) -> Result<StatementResponse, ClientError> {
+        let url = self.url(&format!("/clientes/{id}/extrato"));
+        Self::json(self.request(self.http.get(url))).await
+    }
+
+    pub async fn list_clients(&self, incluir_inativos: bool) -> Result<Vec<User>, ClientError> {
+        let url = self.url("/clientes");
+        let query = [("incluir_inativos", incluir_inativos)];
+        Self::json(self.request(self.http.get(url).query(&query))).await
+    }
+
+    pub async fn update_client(&self, id: i32, patch: &ClientPatch) -> Result<User, ClientError> {
+        let url = self.url(&format!("/clientes/{id}"));
+        Self::json(self.request(self.http.patch(url).json(patch))).await
+    }
+
+    pub async fn deactivate_client(&self, id: i32) -> Result<(), ClientError> {
+        let url = self.url(&format!("/clientes/{id}"));
+        Self::checked(self.request(self.http.delete(url)).send().await?).await?;
+        Ok(())
+    }
+}
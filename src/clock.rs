@@ -0,0 +1,64 @@
+use std::sync::{Arc, RwLock};
+
+use axum::{extract::State, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::audit::Audit;
+
+/// Source of the timestamps the API hands out: `realizado_em`,
+/// `data_extrato` and what is derived from them.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stands still until it is set, for runs whose output must not
+/// depend on when they happen.
+pub struct ManualClock {
+    now: RwLock<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        ManualClock {
+            now: RwLock::new(start),
+        }
+    }
+
+    pub fn set(&self, at: DateTime<Utc>) {
+        *self.now.write().unwrap() = at;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.read().unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClockTime {
+    pub agora: DateTime<Utc>,
+}
+
+pub async fn current(State((clock, _)): State<(Arc<ManualClock>, Arc<Audit>)>) -> Json<ClockTime> {
+    Json(ClockTime { agora: clock.now() })
+}
+
+pub async fn set(
+    State((clock, audit)): State<(Arc<ManualClock>, Arc<Audit>)>,
+    Json(time): Json<ClockTime>,
+) -> Json<ClockTime> {
+    clock.set(time.agora);
+    audit.record("relogio_ajustado", None, json!({ "agora": time.agora }));
+    Json(time)
+}
@@ -0,0 +1,207 @@
+use std::{
+    env,
+    future::Future,
+    io,
+    net::SocketAddr,
+    os::fd::FromRawFd,
+    pin::Pin,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::Router;
+use hyper::server::conn::http1;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::{
+    net::{TcpListener, TcpSocket},
+    sync::watch,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{cli::Config, metrics};
+
+const OPEN: &str = "http_open_connections";
+const ACCEPTED: &str = "http_connections_total";
+const REFUSED: &str = "http_connections_refused_total";
+
+/// First descriptor passed by socket activation, after stdin, stdout and stderr.
+const LISTEN_FDS_START: i32 = 3;
+
+/// Set once the activated socket has been wrapped, so it only ever has one
+/// owner.
+static ACTIVATED_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether `LISTEN_FDS` names a socket for this process.
+pub fn socket_activated() -> bool {
+    let passed = || -> Option<bool> {
+        let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+        let fds: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+        Some(pid == process::id() && fds >= 1)
+    };
+    passed().unwrap_or(false)
+}
+
+/// The socket passed by systemd-style socket activation, the first time it
+/// is asked for.
+fn activated() -> Option<std::net::TcpListener> {
+    if !socket_activated() || ACTIVATED_TAKEN.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+
+    // SAFETY: the activating process hands this descriptor over to us, and
+    // `ACTIVATED_TAKEN` makes this the only place in the process wrapping it.
+    Some(unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// The socket passed by socket activation, or else `addr`, which other
+/// processes may listen on as well when `reuse_port` is set.
+pub fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    if let Some(listener) = activated() {
+        info!("listening on the socket passed by the service manager");
+        listener.set_nonblocking(true)?;
+        return TcpListener::from_std(listener);
+    }
+
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(reuse_port)?;
+    socket.bind(addr)?;
+    info!("listening on {addr}");
+    socket.listen(1024)
+}
+
+/// Holds one slot of the open-connection count until the connection ends.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let open = self.0.fetch_sub(1, Ordering::Relaxed) - 1;
+        metrics::gauge(OPEN, Vec::new()).set(open as f64);
+    }
+}
+
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Serves `connection` until it ends or `closing` changes, then lets it
+/// finish its current request.
+async fn drive<C: Future>(
+    mut connection: Pin<&mut C>,
+    mut closing: watch::Receiver<bool>,
+    graceful_shutdown: impl FnOnce(Pin<&mut C>),
+) {
+    // Errors here are clients going away mid-request.
+    tokio::select! {
+        _ = connection.as_mut() => return,
+        _ = closing.changed() => {}
+    }
+    graceful_shutdown(connection.as_mut());
+    connection.await;
+}
+
+/// Like `axum::serve`, but counts open connections and, past
+/// `--max-connections`, closes new ones as soon as they are accepted instead
+/// of letting them pile up until file descriptors run out. Speaks HTTP/1.1,
+/// and h2c too with `--http2`.
+///
+/// Once `shutdown` resolves, stops accepting, asks every connection to close
+/// after its current request and waits up to `--handoff-drain-secs` for
+/// them, then returns what `shutdown` resolved to and whether they all
+/// closed.
+pub async fn serve<T>(
+    listener: TcpListener,
+    app: Router,
+    config: &Config,
+    shutdown: impl Future<Output = T>,
+) -> (T, bool) {
+    let max_connections = config.max_connections;
+    let http2 = config.http2;
+    let open = Arc::new(AtomicUsize::new(0));
+    metrics::gauge(OPEN, Vec::new()).set(0.0);
+    let (closing, closing_receiver) = watch::channel(false);
+
+    tokio::pin!(shutdown);
+    let outcome = loop {
+        let accepted = tokio::select! {
+            outcome = &mut shutdown => break outcome,
+            accepted = listener.accept() => accepted,
+        };
+
+        let (stream, remote) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) if is_connection_error(&err) => continue,
+            Err(err) => {
+                // Most likely out of file descriptors; give connections a
+                // moment to close before trying again.
+                error!("accept error: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let current = open.fetch_add(1, Ordering::Relaxed) + 1;
+        let slot = Slot(open.clone());
+        if max_connections.is_some_and(|max| current > max) {
+            metrics::counter(REFUSED, Vec::new()).inc();
+            debug!(%remote, "refusing connection, {} already open", current - 1);
+            drop(stream);
+            continue;
+        }
+        metrics::counter(ACCEPTED, Vec::new()).inc();
+        metrics::gauge(OPEN, Vec::new()).set(current as f64);
+
+        let service = TowerToHyperService::new(app.clone());
+        let closing = closing_receiver.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            let io = TokioIo::new(stream);
+
+            if http2 {
+                // Tells HTTP/2 prior knowledge apart from HTTP/1.1 by the
+                // connection preface.
+                let builder = Builder::new(TokioExecutor::new());
+                let connection = builder.serve_connection_with_upgrades(io, service);
+                tokio::pin!(connection);
+                drive(connection, closing, |c| c.graceful_shutdown()).await;
+            } else {
+                let connection = http1::Builder::new()
+                    .serve_connection(io, service)
+                    .with_upgrades();
+                tokio::pin!(connection);
+                drive(connection, closing, |c| c.graceful_shutdown()).await;
+            }
+        });
+    };
+
+    drop(listener);
+    let _ = closing.send(true);
+
+    let deadline = Instant::now() + Duration::from_secs(config.handoff_drain_secs);
+    while open.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    let left = open.load(Ordering::Relaxed);
+    if left > 0 {
+        warn!("{left} connections still open after draining");
+    }
+
+    (outcome, left == 0)
+}
@@ -0,0 +1,181 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    i18n::{Lang, Message},
+    metrics,
+    validation::{ValidationErrors, Validator},
+};
+
+/// Request header with the RFC 3339 time after which the caller no longer
+/// wants the answer.
+pub const HEADER: &str = "x-request-deadline";
+
+/// gRPC's relative form: up to 8 digits and a unit, e.g. `250m`.
+pub const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+const EXCEEDED: &str = "deadline_exceeded_total";
+
+/// When the request's budget runs out, set on arrival.
+#[derive(Clone, Copy)]
+struct Deadline(Instant);
+
+fn grpc_timeout(value: &str) -> Option<Duration> {
+    let unit = value.chars().last()?;
+    let digits = &value[..value.len() - unit.len_utf8()];
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    Some(match unit {
+        'H' => Duration::from_secs(amount * 3600),
+        'M' => Duration::from_secs(amount * 60),
+        'S' => Duration::from_secs(amount),
+        'm' => Duration::from_millis(amount),
+        'u' => Duration::from_micros(amount),
+        'n' => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// The deadline the headers give, the earlier one when both are sent.
+fn from_headers(
+    headers: &HeaderMap,
+    arrived: Instant,
+) -> Result<Option<Deadline>, ValidationErrors> {
+    let mut v = Validator::new(Lang::from_headers(headers));
+    let mut budgets = Vec::new();
+
+    if let Some(value) = headers.get(HEADER) {
+        match value
+            .to_str()
+            .ok()
+            .and_then(|value| DateTime::parse_from_rfc3339(value.trim()).ok())
+        {
+            // Past deadlines leave no budget at all.
+            Some(at) => budgets.push(
+                (at.with_timezone(&Utc) - Utc::now())
+                    .to_std()
+                    .unwrap_or_default(),
+            ),
+            None => v.error("X-Request-Deadline", "invalid", "prazo_invalido"),
+        }
+    }
+    if let Some(value) = headers.get(GRPC_TIMEOUT) {
+        match value.to_str().ok().and_then(grpc_timeout) {
+            Some(budget) => budgets.push(budget),
+            None => v.error("grpc-timeout", "invalid", "grpc_timeout_invalido"),
+        }
+    }
+    v.finish()?;
+
+    Ok(budgets
+        .into_iter()
+        .min()
+        .map(|budget| Deadline(arrived.checked_add(budget).unwrap_or(arrived))))
+}
+
+/// Reads the caller's deadline as the request comes in, so the time it then
+/// spends waiting in the layers below counts against it. Malformed ones are
+/// answered like invalid fields.
+pub async fn read_deadline(mut request: Request, next: Next) -> Response {
+    let arrived = Instant::now();
+    match from_headers(request.headers(), arrived) {
+        Ok(Some(deadline)) => {
+            request.extensions_mut().insert(deadline);
+        }
+        Ok(None) => {}
+        Err(errors) => return errors.into_response(),
+    }
+    next.run(request).await
+}
+
+/// Answers with a 504, before any work is done, the requests whose deadline
+/// already passed by the time they reach their handler.
+pub async fn enforce_deadline(request: Request, next: Next) -> Response {
+    let expired = request
+        .extensions()
+        .get::<Deadline>()
+        .is_some_and(|Deadline(at)| Instant::now() >= *at);
+    if !expired {
+        return next.run(request).await;
+    }
+
+    metrics::counter(EXCEEDED, Vec::new()).inc();
+    let lang = Lang::from_headers(request.headers());
+    (
+        StatusCode::GATEWAY_TIMEOUT,
+        Message::new("prazo_esgotado").localize(lang),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn grpc_timeouts_take_every_unit() {
+        assert_eq!(grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(grpc_timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(grpc_timeout("1S"), Some(Duration::from_secs(1)));
+        assert_eq!(grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(grpc_timeout("10u"), Some(Duration::from_micros(10)));
+        assert_eq!(
+            grpc_timeout("99999999n"),
+            Some(Duration::from_nanos(99_999_999))
+        );
+    }
+
+    #[test]
+    fn malformed_grpc_timeouts_are_refused() {
+        for value in [
+            "",
+            "m",
+            "100",
+            "123456789m",
+            "10s",
+            "-1m",
+            "1.5S",
+            "+5m",
+            " 5m",
+            "5é",
+        ] {
+            assert_eq!(grpc_timeout(value), None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn the_earlier_deadline_wins() {
+        let arrived = Instant::now();
+        let mut headers = HeaderMap::new();
+        headers.insert(GRPC_TIMEOUT, HeaderValue::from_static("100m"));
+        let later = (Utc::now() + chrono::TimeDelta::hours(1)).to_rfc3339();
+        headers.insert(HEADER, HeaderValue::from_str(&later).unwrap());
+
+        let Ok(Some(Deadline(at))) = from_headers(&headers, arrived) else {
+            panic!("expected a deadline");
+        };
+        assert_eq!(at, arrived + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn an_invalid_header_is_an_error() {
+        let mut headers = HeaderMap::new();
+        headers.insert(GRPC_TIMEOUT, HeaderValue::from_static("soon"));
+        assert!(from_headers(&headers, Instant::now()).is_err());
+        assert!(matches!(
+            from_headers(&HeaderMap::new(), Instant::now()),
+            Ok(None)
+        ));
+    }
+}
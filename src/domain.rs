@@ -0,0 +1,148 @@
+use crate::models::{NewTransaction, User};
+
+/// The part of a client the rules look at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub saldo: i32,
+    pub limite: i32,
+    pub ativo: bool,
+}
+
+impl From<&User> for Account {
+    fn from(user: &User) -> Self {
+        Account {
+            saldo: user.saldo,
+            limite: user.limite,
+            ativo: user.ativo,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    Inactive,
+    LimitExceeded,
+    /// The balance would no longer fit the stored integer.
+    Overflow,
+}
+
+/// How a transaction moves the balance: debits subtract, credits add.
+pub fn signed_valor(tipo: &str, valor: i32) -> i64 {
+    if tipo == "d" {
+        -i64::from(valor)
+    } else {
+        i64::from(valor)
+    }
+}
+
+/// The account after `transaction`, or why it is refused. A refused
+/// transaction changes nothing. Backends that check the limit in SQL or Lua
+/// instead must agree with this.
+pub fn apply_transaction(
+    account: Account,
+    transaction: &NewTransaction,
+) -> Result<Account, Rejection> {
+    if !account.ativo {
+        return Err(Rejection::Inactive);
+    }
+
+    let saldo = i64::from(account.saldo) + signed_valor(&transaction.tipo, transaction.valor);
+    if saldo < -i64::from(account.limite) {
+        return Err(Rejection::LimitExceeded);
+    }
+
+    Ok(Account {
+        saldo: i32::try_from(saldo).map_err(|_| Rejection::Overflow)?,
+        ..account
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn account() -> impl Strategy<Value = Account> {
+        (0..=i32::MAX / 2).prop_flat_map(|limite| {
+            (-limite..=i32::MAX, Just(limite)).prop_map(|(saldo, limite)| Account {
+                saldo,
+                limite,
+                ativo: true,
+            })
+        })
+    }
+
+    fn transaction() -> impl Strategy<Value = NewTransaction> {
+        (1..=i32::MAX, prop_oneof![Just("c"), Just("d")]).prop_map(|(valor, tipo)| NewTransaction {
+            valor,
+            tipo: tipo.to_owned(),
+            descricao: "teste".to_owned(),
+            categoria: None,
+            tags: Vec::new(),
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn balance_never_drops_below_limit(
+            start in account(),
+            transactions in prop::collection::vec(transaction(), 0..200),
+        ) {
+            let mut current = start;
+            for transaction in &transactions {
+                if let Ok(next) = apply_transaction(current, transaction) {
+                    current = next;
+                }
+                prop_assert!(i64::from(current.saldo) >= -i64::from(current.limite));
+                prop_assert_eq!(current.limite, start.limite);
+            }
+        }
+
+        #[test]
+        fn accepted_credits_and_debits_reconcile(
+            start in account(),
+            transactions in prop::collection::vec(transaction(), 0..200),
+        ) {
+            let mut current = start;
+            let mut credits = 0i64;
+            let mut debits = 0i64;
+
+            for transaction in &transactions {
+                match apply_transaction(current, transaction) {
+                    Ok(next) => {
+                        current = next;
+                        match transaction.tipo.as_str() {
+                            "d" => debits += i64::from(transaction.valor),
+                            _ => credits += i64::from(transaction.valor),
+                        }
+                    }
+                    Err(Rejection::LimitExceeded) => {
+                        prop_assert_eq!(transaction.tipo.as_str(), "d");
+                    }
+                    Err(Rejection::Overflow) => {
+                        prop_assert_eq!(transaction.tipo.as_str(), "c");
+                    }
+                    Err(Rejection::Inactive) => prop_assert!(false, "account is active"),
+                }
+            }
+
+            prop_assert_eq!(
+                i64::from(current.saldo),
+                i64::from(start.saldo) + credits - debits
+            );
+        }
+
+        #[test]
+        fn inactive_accounts_refuse_everything(
+            start in account(),
+            transaction in transaction(),
+        ) {
+            let inactive = Account { ativo: false, ..start };
+            prop_assert_eq!(
+                apply_transaction(inactive, &transaction),
+                Err(Rejection::Inactive)
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::ValueEnum;
+use uuid::Uuid;
+
+use crate::models::NewTransaction;
+
+/// Response header naming the transaction a request looked like a repeat of.
+pub const HEADER: &str = "x-duplicate-of";
+
+/// How many remembered transactions trigger a sweep of the expired ones.
+const SWEEP_AT: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DuplicateMode {
+    /// Refuse the repeat with a 409
+    Reject,
+    /// Apply it anyway and point at the earlier one in a response header
+    Annotate,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    cliente: i32,
+    valor: i32,
+    tipo: String,
+    descricao: String,
+}
+
+struct Seen {
+    at: DateTime<Utc>,
+    /// `None` while the first one is still on its way to storage.
+    id: Option<Uuid>,
+}
+
+pub enum Check {
+    /// Nothing alike in the window; `confirm` or `release` it once the write
+    /// is done.
+    New(Fingerprint),
+    Duplicate {
+        fingerprint: Fingerprint,
+        of: Option<Uuid>,
+    },
+}
+
+/// Flags a transaction identical to one accepted for the same client within
+/// the window, to catch clients that submit twice by accident. Off when no
+/// window is configured.
+pub struct DuplicateGuard {
+    window: Option<TimeDelta>,
+    mode: DuplicateMode,
+    seen: Mutex<HashMap<Fingerprint, Seen>>,
+}
+
+impl DuplicateGuard {
+    pub fn new(window_ms: Option<u64>, mode: DuplicateMode) -> Self {
+        DuplicateGuard {
+            window: window_ms.map(|ms| TimeDelta::milliseconds(ms as i64)),
+            mode,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn mode(&self) -> DuplicateMode {
+        self.mode
+    }
+
+    /// Looks `transaction` up and, when it is new, reserves it right away so
+    /// a concurrent identical request is caught before either commits.
+    pub fn check(
+        &self,
+        cliente: i32,
+        transaction: &NewTransaction,
+        now: DateTime<Utc>,
+    ) -> Option<Check> {
+        let window = self.window?;
+        let fingerprint = Fingerprint {
+            cliente,
+            valor: transaction.valor,
+            tipo: transaction.tipo.clone(),
+            descricao: transaction.descricao.clone(),
+        };
+
+        let mut seen = self.seen.lock().unwrap();
+        if seen.len() >= SWEEP_AT {
+            seen.retain(|_, entry| now - entry.at < window);
+        }
+
+        match seen.get(&fingerprint) {
+            Some(entry) if now - entry.at < window => Some(Check::Duplicate {
+                of: entry.id,
+                fingerprint,
+            }),
+            _ => {
+                seen.insert(fingerprint.clone(), Seen { at: now, id: None });
+                Some(Check::New(fingerprint))
+            }
+        }
+    }
+
+    /// Records the committed transaction; the window restarts from it.
+    pub fn confirm(&self, fingerprint: Fingerprint, id: Uuid, at: DateTime<Utc>) {
+        self.seen
+            .lock()
+            .unwrap()
+            .insert(fingerprint, Seen { at, id: Some(id) });
+    }
+
+    /// Forgets a reservation whose transaction was not applied.
+    pub fn release(&self, fingerprint: &Fingerprint) {
+        let mut seen = self.seen.lock().unwrap();
+        if seen
+            .get(fingerprint)
+            .is_some_and(|entry| entry.id.is_none())
+        {
+            seen.remove(fingerprint);
+        }
+    }
+}
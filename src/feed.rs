@@ -0,0 +1,182 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use chrono::{DateTime, Utc};
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use crate::{
+    ids::{Ulid, UlidGenerator},
+    models::{Statement, User},
+};
+
+/// Transactions a subscriber may fall behind by before it starts missing
+/// them, and how far back one can resume.
+const BACKLOG: usize = 1024;
+
+pub type EventStream = Sse<BoxStream<'static, Result<Event, axum::Error>>>;
+
+#[derive(Clone, Serialize)]
+pub struct TransactionEvent {
+    /// Sorts the events in the order they were committed; also their SSE id.
+    pub evento: Ulid,
+    pub cliente: i32,
+    pub id: Uuid,
+    pub sequencia: i64,
+    pub valor: i32,
+    pub tipo: String,
+    pub descricao: String,
+    pub realizado_em: DateTime<Utc>,
+    pub saldo: i32,
+    pub limite: i32,
+}
+
+/// Sent first when resuming from further back than the feed remembers.
+/// Nothing after `perdidos_ate` is missing.
+#[derive(Serialize)]
+struct Gap {
+    desde: Ulid,
+    perdidos_ate: Ulid,
+}
+
+struct Log {
+    ids: UlidGenerator,
+    recent: VecDeque<TransactionEvent>,
+    /// The newest event pushed out of `recent`.
+    forgotten: Option<Ulid>,
+    /// Older than every event of this feed. Events before it, such as those
+    /// of a previous process, were never kept here.
+    started: Ulid,
+}
+
+/// Feed of committed transactions, in commit order: only the writer
+/// publishes, and ids are handed out and events sent under one lock. The
+/// last [`BACKLOG`] are kept for subscribers resuming after a disconnect.
+pub struct Feed {
+    sender: broadcast::Sender<TransactionEvent>,
+    log: Mutex<Log>,
+}
+
+impl Feed {
+    pub fn new() -> Self {
+        let mut ids = UlidGenerator::default();
+        let started = ids.next();
+        Feed {
+            sender: broadcast::channel(BACKLOG).0,
+            log: Mutex::new(Log {
+                ids,
+                recent: VecDeque::new(),
+                forgotten: None,
+                started,
+            }),
+        }
+    }
+
+    pub fn publish(&self, user: &User, statement: &Statement) {
+        let mut log = self.log.lock().unwrap();
+        let event = TransactionEvent {
+            evento: log.ids.next(),
+            cliente: user.id,
+            id: statement.uuid,
+            sequencia: statement.sequencia,
+            valor: statement.valor,
+            tipo: statement.tipo.clone(),
+            descricao: statement.descricao.clone(),
+            realizado_em: statement.realizado_em,
+            saldo: user.saldo,
+            limite: user.limite,
+        };
+
+        if log.recent.len() == BACKLOG {
+            log.forgotten = log.recent.pop_front().map(|event| event.evento);
+        }
+        log.recent.push_back(event.clone());
+        // No subscribers is not an error here.
+        let _ = self.sender.send(event);
+    }
+
+    /// Server-sent `transacao` events, starting right after `after` when
+    /// given and otherwise with the next commit. A subscriber falling too
+    /// far behind is disconnected rather than skipped ahead, so that it
+    /// reconnects with `Last-Event-ID` and misses nothing still kept.
+    pub fn stream(&self, after: Option<Ulid>) -> EventStream {
+        let (receiver, replay) = {
+            let log = self.log.lock().unwrap();
+            // Subscribing under the lock leaves no event between the replay
+            // and the live ones.
+            let receiver = self.sender.subscribe();
+
+            let mut replay = Vec::new();
+            if let Some(after) = after {
+                let kept_after = log.forgotten.unwrap_or(log.started);
+                if kept_after > after {
+                    let gap = Gap {
+                        desde: after,
+                        perdidos_ate: kept_after,
+                    };
+                    replay.push(Event::default().event("lacuna").json_data(&gap));
+                }
+                replay.extend(
+                    log.recent
+                        .iter()
+                        .filter(|event| event.evento > after)
+                        .map(transaction_event),
+                );
+            }
+            (receiver, replay)
+        };
+
+        let live = stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((transaction_event(&event), receiver)),
+                Err(RecvError::Lagged(_) | RecvError::Closed) => None,
+            }
+        });
+
+        Sse::new(stream::iter(replay).chain(live).boxed()).keep_alive(KeepAlive::default())
+    }
+}
+
+fn transaction_event(event: &TransactionEvent) -> Result<Event, axum::Error> {
+    Event::default()
+        .event("transacao")
+        .id(event.evento.to_string())
+        .json_data(event)
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        Feed::new()
+    }
+}
+
+/// Server-sent events named `name` for every message `keep` accepts. A
+/// subscriber that falls too far behind skips what it missed.
+pub fn event_stream<T>(
+    receiver: broadcast::Receiver<T>,
+    name: &'static str,
+    keep: impl Fn(&T) -> bool + Send + 'static,
+) -> EventStream
+where
+    T: Clone + Serialize + Send + 'static,
+{
+    let events = stream::unfold((receiver, keep), move |(mut receiver, keep)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(message) if keep(&message) => {
+                    let event = Event::default().event(name).json_data(&message);
+                    return Some((event, (receiver, keep)));
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(events.boxed()).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,151 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use tracing::warn;
+
+/// Request header picking the names for one response, `pt-BR` or `en`.
+pub const HEADER: &str = "x-field-names";
+
+static EN: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
+    serde_json::from_str(include_str!("../assets/fields/en.json"))
+        .expect("the field name table is valid JSON")
+});
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum FieldNames {
+    /// The rinha's own: saldo, limite, valor...
+    #[default]
+    #[value(name = "pt-BR")]
+    PtBr,
+    /// balance, limit, amount...
+    En,
+}
+
+impl FieldNames {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(HEADER)?.to_str().ok()?;
+        FieldNames::from_str(value.trim(), true).ok()
+    }
+}
+
+/// Fields holding free-form JSON, such as the `detalhes` of an audit entry:
+/// their own name is renamed, what they hold is kept as written, since the
+/// audit chain hashes it.
+const VERBATIM: &[&str] = &["detalhes"];
+
+fn rename(value: Value, names: &HashMap<String, String>) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = if VERBATIM.contains(&key.as_str()) {
+                        value
+                    } else {
+                        rename(value, names)
+                    };
+                    let key = names.get(&key).cloned().unwrap_or(key);
+                    (key, value)
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(|v| rename(v, names)).collect()),
+        other => other,
+    }
+}
+
+fn is_json(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+/// Renames the fields of JSON responses when English names are asked for by
+/// `X-Field-Names` or, without it, by `--field-names`. The handlers and
+/// their types only ever know the pt-BR names. Streamed bodies, such as the
+/// complete extrato and the event feeds, are left as they are.
+pub async fn rename_fields(
+    State(default): State<FieldNames>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let names = FieldNames::from_headers(request.headers()).unwrap_or(default);
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static(HEADER));
+
+    if names == FieldNames::PtBr
+        || !is_json(&response)
+        || response.body().size_hint().exact().is_none()
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            warn!("failed to read a response to rename its fields: {err}");
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let renamed = match serde_json::from_slice(&bytes) {
+        Ok(value) => serde_json::to_vec(&rename(value, &EN)).unwrap_or_else(|_| bytes.to_vec()),
+        Err(_) => bytes.to_vec(),
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(renamed))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn nested_fields_are_renamed() {
+        let statement = json!({
+            "saldo": { "total": 10, "limite": 100 },
+            "ultimas_transacoes": [{ "valor": 5, "tipo": "c" }],
+        });
+        assert_eq!(
+            rename(statement, &EN),
+            json!({
+                "balance": { "total": 10, "limit": 100 },
+                "last_transactions": [{ "amount": 5, "type": "c" }],
+            })
+        );
+    }
+
+    #[test]
+    fn audit_details_are_kept_as_written() {
+        let entry = json!({ "acao": "cliente_desativado", "detalhes": { "cliente": 5 } });
+        assert_eq!(
+            rename(entry, &EN),
+            json!({ "action": "cliente_desativado", "details": { "cliente": 5 } })
+        );
+    }
+
+    #[test]
+    fn names_come_from_the_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(FieldNames::from_headers(&headers), None);
+        headers.insert(HEADER, HeaderValue::from_static(" EN "));
+        assert_eq!(FieldNames::from_headers(&headers), Some(FieldNames::En));
+        headers.insert(HEADER, HeaderValue::from_static("fr"));
+        assert_eq!(FieldNames::from_headers(&headers), None);
+    }
+}
@@ -0,0 +1,903 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{AppendHeaders, IntoResponse},
+    Json,
+};
+use chrono::{DateTime, Datelike, DurationRound, NaiveDate, TimeDelta, Utc};
+use futures_util::stream;
+use serde_json::json;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    alerts,
+    domain::{self, Account, Rejection},
+    duplicates::{self, Check, DuplicateMode},
+    feed::{self, EventStream},
+    i18n::{Lang, Localized, Message},
+    idempotency::{self, Lookup},
+    ids::Ulid,
+    models::{
+        Balance, CategoryTotals, ClientPatch, FeedQuery, GroupedStatementQuery,
+        GroupedStatementResponse, HistoryQuery, LastTransaction, ListClientsQuery, NewTransaction,
+        PeriodTotals, Periodo, SearchQuery, SearchResponse, SimulationResponse, StatementQuery,
+        StatementResponse, Totals, TransactionQuery, TransactionResponse, User,
+    },
+    quotas::QuotaExceeded,
+    storage::{IdempotencyRecord, StorageError, TransactionError},
+    tenants::Tenant,
+    validation::{Valid, ValidationErrors, Validator, TIPOS},
+    writer::WriteError,
+    AppState,
+};
+
+enum StatementResult {
+    Success(Json<StatementResponse>, Option<String>),
+    Cached(String, Option<String>),
+    NotModified(String),
+    InvalidQuery(ValidationErrors),
+    NotFound,
+    InternalError,
+}
+
+fn with_last_modified(
+    mut response: axum::response::Response,
+    last_modified: Option<String>,
+) -> axum::response::Response {
+    if let Some(value) = last_modified.and_then(|v| v.parse().ok()) {
+        response.headers_mut().insert(header::LAST_MODIFIED, value);
+    }
+    response
+}
+
+impl IntoResponse for StatementResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            StatementResult::Success(json, last_modified) => {
+                with_last_modified(json.into_response(), last_modified)
+            }
+            StatementResult::Cached(body, last_modified) => with_last_modified(
+                ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+                last_modified,
+            ),
+            StatementResult::NotModified(last_modified) => with_last_modified(
+                StatusCode::NOT_MODIFIED.into_response(),
+                Some(last_modified),
+            ),
+            StatementResult::InvalidQuery(errors) => errors.into_response(),
+            StatementResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            StatementResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+enum GroupedResult {
+    Success(Json<GroupedStatementResponse>),
+    NotFound,
+    InternalError,
+}
+
+impl IntoResponse for GroupedResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            GroupedResult::Success(json) => json.into_response(),
+            GroupedResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            GroupedResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+enum SearchResult {
+    Success(Json<SearchResponse>),
+    InvalidQuery(ValidationErrors),
+    NotFound,
+    InternalError,
+}
+
+impl IntoResponse for SearchResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            SearchResult::Success(json) => json.into_response(),
+            SearchResult::InvalidQuery(errors) => errors.into_response(),
+            SearchResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            SearchResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+/// Header `EventSource` reconnects with, naming the last event it got.
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// Set on extratos that may miss transactions the backend does not have yet.
+const STALE: &str = "x-statement-stale";
+
+fn stale_mark(
+    state: &AppState,
+    user_id: i32,
+) -> AppendHeaders<Option<(&'static str, &'static str)>> {
+    AppendHeaders(state.storage.replaying(user_id).then_some((STALE, "true")))
+}
+
+/// What the rinha spec returns; the only size the extrato cache holds.
+const DEFAULT_STATEMENT_LEN: usize = 10;
+
+/// Statements fetched per storage round-trip while streaming a full history.
+const HISTORY_PAGE_LEN: usize = 500;
+
+enum HistoryResult {
+    Stream(Body),
+    NotFound,
+    InternalError,
+}
+
+impl IntoResponse for HistoryResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            HistoryResult::Stream(body) => {
+                ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+            }
+            HistoryResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            HistoryResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+enum TransactionResult {
+    /// With the earlier transaction this one repeats, when annotating.
+    Success(Json<TransactionResponse>, Option<Uuid>),
+    Simulated(Json<SimulationResponse>),
+    NotFound,
+    Gone,
+    UnprocessableEntity,
+    Duplicate(Option<Uuid>),
+    OverQuota(QuotaExceeded),
+    /// The answer an earlier request with the same `Idempotency-Key` got.
+    Replayed(IdempotencyRecord),
+    InvalidKey(ValidationErrors),
+    KeyReused(Localized),
+    KeyInUse(Localized),
+    Overloaded,
+    InternalError,
+}
+
+impl TransactionResult {
+    /// The status and body to replay later, for the answers a retry should
+    /// get again; `None` for those worth retrying.
+    fn to_record(&self) -> Option<(StatusCode, Option<String>)> {
+        match self {
+            TransactionResult::Success(Json(response), _) => {
+                Some((StatusCode::OK, serde_json::to_string(response).ok()))
+            }
+            TransactionResult::NotFound => Some((StatusCode::NOT_FOUND, None)),
+            TransactionResult::Gone => Some((StatusCode::GONE, None)),
+            TransactionResult::UnprocessableEntity => {
+                Some((StatusCode::UNPROCESSABLE_ENTITY, None))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn with_duplicate_of(
+    mut response: axum::response::Response,
+    of: Option<Uuid>,
+) -> axum::response::Response {
+    if let Some(value) = of.and_then(|of| HeaderValue::from_str(&of.to_string()).ok()) {
+        response.headers_mut().insert(duplicates::HEADER, value);
+    }
+    response
+}
+
+impl IntoResponse for TransactionResult {
+    fn into_response(self) -> axum::response::Response<Body> {
+        match self {
+            TransactionResult::Success(json, duplicate_of) => {
+                with_duplicate_of(json.into_response(), duplicate_of)
+            }
+            TransactionResult::Simulated(json) => json.into_response(),
+            TransactionResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            TransactionResult::Gone => StatusCode::GONE.into_response(),
+            TransactionResult::UnprocessableEntity => {
+                StatusCode::UNPROCESSABLE_ENTITY.into_response()
+            }
+            TransactionResult::Duplicate(of) => {
+                with_duplicate_of(StatusCode::CONFLICT.into_response(), of)
+            }
+            TransactionResult::OverQuota(exceeded) => exceeded.into_response(),
+            TransactionResult::Replayed(record) => {
+                let status = StatusCode::from_u16(record.status)
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                let replayed = [(idempotency::REPLAYED, "true")];
+                match record.corpo {
+                    Some(corpo) => (
+                        status,
+                        replayed,
+                        [(header::CONTENT_TYPE, "application/json")],
+                        corpo,
+                    )
+                        .into_response(),
+                    None => (status, replayed).into_response(),
+                }
+            }
+            TransactionResult::InvalidKey(errors) => errors.into_response(),
+            TransactionResult::KeyReused(message) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+            TransactionResult::KeyInUse(message) => (StatusCode::CONFLICT, message).into_response(),
+            TransactionResult::Overloaded => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            TransactionResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+enum ClientsResult {
+    Success(Json<Vec<User>>),
+    InternalError,
+}
+
+impl IntoResponse for ClientsResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ClientsResult::Success(json) => json.into_response(),
+            ClientsResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+enum UpdateClientResult {
+    Success(Json<User>),
+    NotFound,
+    InternalError,
+}
+
+impl IntoResponse for UpdateClientResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            UpdateClientResult::Success(json) => json.into_response(),
+            UpdateClientResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            UpdateClientResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+enum AlertsResult {
+    Stream(EventStream),
+    NotFound,
+    InternalError,
+}
+
+impl IntoResponse for AlertsResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            AlertsResult::Stream(sse) => sse.into_response(),
+            AlertsResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            AlertsResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+enum DeactivateResult {
+    Success,
+    NotFound,
+    InternalError,
+}
+
+impl IntoResponse for DeactivateResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            DeactivateResult::Success => StatusCode::NO_CONTENT.into_response(),
+            DeactivateResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+            DeactivateResult::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+pub async fn list_clients(
+    Tenant(state): Tenant,
+    Query(query): Query<ListClientsQuery>,
+) -> impl IntoResponse {
+    match state.storage.list_clients(query.incluir_inativos).await {
+        Ok(clients) => ClientsResult::Success(Json(clients)),
+        Err(err) => {
+            error!("failed to list clients: {err}");
+            ClientsResult::InternalError
+        }
+    }
+}
+
+pub async fn deactivate_client(
+    Tenant(state): Tenant,
+    Path(user_id): Path<i32>,
+) -> impl IntoResponse {
+    match state.storage.deactivate(user_id).await {
+        Ok(true) => {
+            state.audit.record(
+                "cliente_desativado",
+                state.tenant.as_deref(),
+                json!({ "cliente": user_id }),
+            );
+            DeactivateResult::Success
+        }
+        Ok(false) => DeactivateResult::NotFound,
+        Err(err) => {
+            error!("failed to deactivate client {user_id}: {err}");
+            DeactivateResult::InternalError
+        }
+    }
+}
+
+enum FeedResult {
+    Stream(EventStream),
+    InvalidQuery(ValidationErrors),
+}
+
+impl IntoResponse for FeedResult {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            FeedResult::Stream(sse) => sse.into_response(),
+            FeedResult::InvalidQuery(errors) => errors.into_response(),
+        }
+    }
+}
+
+/// Server-sent events for every transaction committed from now on, or since
+/// the event named by `Last-Event-ID` or else `?desde=`.
+pub async fn stream_transactions(
+    Tenant(state): Tenant,
+    Query(query): Query<FeedQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let last_event_id = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(|id| ("Last-Event-ID", id));
+    let resume = last_event_id.or(query.desde.as_deref().map(|id| ("desde", id)));
+
+    let mut v = Validator::new(Lang::from_headers(&headers));
+    let after = resume.and_then(|(field, id)| {
+        let after = id.parse::<Ulid>().ok();
+        if after.is_none() {
+            v.error(field, "invalid", "ulid_invalido");
+        }
+        after
+    });
+    if let Err(errors) = v.finish() {
+        return FeedResult::InvalidQuery(errors);
+    }
+
+    FeedResult::Stream(state.feed.stream(after))
+}
+
+pub async fn update_client(
+    Tenant(state): Tenant,
+    Path(user_id): Path<i32>,
+    Valid(patch): Valid<ClientPatch>,
+) -> impl IntoResponse {
+    let result = match patch.alerta_percentual {
+        Some(alerta_percentual) => state.storage.set_alert(user_id, alerta_percentual).await,
+        None => state.storage.client(user_id).await,
+    };
+
+    match result {
+        Ok(Some(user)) => {
+            state.extratos.invalidate(user_id);
+            if let Some(alerta_percentual) = patch.alerta_percentual {
+                state.audit.record(
+                    "alerta_alterado",
+                    state.tenant.as_deref(),
+                    json!({ "cliente": user_id, "alerta_percentual": alerta_percentual }),
+                );
+            }
+            UpdateClientResult::Success(Json(user))
+        }
+        Ok(None) => UpdateClientResult::NotFound,
+        Err(err) => {
+            error!("failed to update client {user_id}: {err}");
+            UpdateClientResult::InternalError
+        }
+    }
+}
+
+/// Server-sent events for each alert the client raises from now on.
+pub async fn stream_alerts(Tenant(state): Tenant, Path(user_id): Path<i32>) -> impl IntoResponse {
+    match state.storage.client(user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return AlertsResult::NotFound,
+        Err(err) => {
+            error!("failed to read client {user_id}: {err}");
+            return AlertsResult::InternalError;
+        }
+    }
+
+    AlertsResult::Stream(feed::event_stream(
+        state.alerts.subscribe(),
+        "alerta",
+        move |alert| alert.cliente == user_id,
+    ))
+}
+
+/// Timestamp of the newest transaction, truncated to the second resolution of
+/// HTTP dates.
+fn latest_transaction(statements: &[LastTransaction]) -> Option<DateTime<Utc>> {
+    statements
+        .iter()
+        .map(|s| s.realizado_em)
+        .max()
+        .and_then(|at| at.duration_trunc(TimeDelta::seconds(1)).ok())
+}
+
+/// The `Last-Modified` value to send, if any. A timestamp in the current
+/// second is withheld: another transaction could still land in that second
+/// and a client holding it would then be told nothing changed.
+fn last_modified(latest: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Option<String> {
+    latest
+        .filter(|at| now - *at >= TimeDelta::seconds(1))
+        .map(|at| at.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+}
+
+fn not_modified(headers: &HeaderMap, latest: Option<DateTime<Utc>>) -> bool {
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+
+    match (since, latest) {
+        (Some(since), Some(latest)) => latest <= since,
+        _ => false,
+    }
+}
+
+fn page_len(v: &mut Validator, state: &AppState, quantidade: Option<i64>) -> usize {
+    let Some(n) = quantidade else {
+        return DEFAULT_STATEMENT_LEN;
+    };
+    let max = i64::try_from(state.extrato_max_quantidade).unwrap_or(i64::MAX);
+    v.range("quantidade", n, 1, max);
+    n.clamp(1, max) as usize
+}
+
+pub async fn get_bank_statement(
+    Tenant(state): Tenant,
+    Path(user_id): Path<i32>,
+    Query(query): Query<StatementQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let result = bank_statement(&state, user_id, query, &headers).await;
+    (stale_mark(&state, user_id), result)
+}
+
+async fn bank_statement(
+    state: &AppState,
+    user_id: i32,
+    query: StatementQuery,
+    headers: &HeaderMap,
+) -> StatementResult {
+    let now = state.clock.now();
+    let lang = Lang::from_headers(headers);
+
+    let mut v = Validator::new(lang);
+    let quantidade = page_len(&mut v, state, query.quantidade);
+    if let Some(tipo) = &query.tipo {
+        v.one_of("tipo", tipo, &TIPOS);
+    }
+    if let Err(errors) = v.finish() {
+        return StatementResult::InvalidQuery(errors);
+    }
+    let cacheable = quantidade == DEFAULT_STATEMENT_LEN && query.tipo.is_none();
+
+    if let Some(cached) = cacheable.then(|| state.extratos.get(user_id)).flatten() {
+        let last_modified = last_modified(cached.latest, now);
+        if let (true, Some(value)) = (not_modified(headers, cached.latest), &last_modified) {
+            return StatementResult::NotModified(value.clone());
+        }
+        return StatementResult::Cached(cached.render(now), last_modified);
+    }
+
+    let generation = state.extratos.generation().filter(|_| cacheable);
+    let (user, statements) = match state
+        .storage
+        .statement(user_id, quantidade, query.tipo.as_deref())
+        .await
+    {
+        Ok(Some(found)) => found,
+        Ok(None) => return StatementResult::NotFound,
+        Err(err) => {
+            error!("failed to read statement for client {user_id}: {err}");
+            return StatementResult::InternalError;
+        }
+    };
+
+    let balance = Balance {
+        total: user.saldo,
+        data_extrato: now,
+        limite: user.limite,
+        alerta: alerts::in_alert(&user),
+    };
+
+    let last_transactions: Vec<LastTransaction> =
+        statements.into_iter().map(LastTransaction::from).collect();
+
+    let latest = latest_transaction(&last_transactions);
+    let last_modified_value = last_modified(latest, now);
+    if let (true, Some(value)) = (not_modified(headers, latest), &last_modified_value) {
+        return StatementResult::NotModified(value.clone());
+    }
+
+    if let Some(generation) = generation {
+        return match state
+            .extratos
+            .insert(generation, &user, &last_transactions, latest)
+        {
+            Ok(cached) => {
+                StatementResult::Cached(cached.render(balance.data_extrato), last_modified_value)
+            }
+            Err(err) => {
+                error!("failed to cache statement for client {user_id}: {err}");
+                StatementResult::InternalError
+            }
+        };
+    }
+
+    StatementResult::Success(
+        Json(StatementResponse {
+            saldo: balance,
+            ultimas_transacoes: last_transactions,
+        }),
+        last_modified_value,
+    )
+}
+
+fn period_start(at: DateTime<Utc>, periodo: Periodo) -> NaiveDate {
+    let day = at.date_naive();
+    match periodo {
+        Periodo::Dia => day,
+        Periodo::Mes => day.with_day(1).unwrap_or(day),
+    }
+}
+
+async fn group_history(
+    state: &AppState,
+    user_id: i32,
+    periodo: Periodo,
+    filtro: &HistoryQuery,
+) -> Result<Vec<PeriodTotals>, StorageError> {
+    type Group = (Totals, BTreeMap<String, Totals>);
+    let mut groups: BTreeMap<NaiveDate, Group> = BTreeMap::new();
+    let mut cursor = Some(0);
+
+    while let Some(position) = cursor {
+        let (page, next) = state
+            .storage
+            .history_page(user_id, position, HISTORY_PAGE_LEN)
+            .await?;
+
+        for statement in page.iter().filter(|s| filtro.matches(s)) {
+            let inicio = period_start(statement.realizado_em, periodo);
+            let (totais, categorias) = groups.entry(inicio).or_default();
+
+            totais.add(statement);
+            if let Some(categoria) = &statement.categoria {
+                categorias
+                    .entry(categoria.clone())
+                    .or_default()
+                    .add(statement);
+            }
+        }
+        cursor = next;
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(inicio, (totais, categorias))| PeriodTotals {
+            inicio,
+            totais,
+            categorias: categorias
+                .into_iter()
+                .map(|(categoria, totais)| CategoryTotals { categoria, totais })
+                .collect(),
+        })
+        .collect())
+}
+
+/// Credits, debits and counts per calendar day or month, oldest first, and
+/// per `categoria` within each. The history is read page by page, so only the
+/// totals are held in memory.
+pub async fn get_grouped_statement(
+    Tenant(state): Tenant,
+    Path(user_id): Path<i32>,
+    Query(query): Query<GroupedStatementQuery>,
+) -> impl IntoResponse {
+    match state.storage.client(user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return GroupedResult::NotFound,
+        Err(err) => {
+            error!("failed to read history of client {user_id}: {err}");
+            return GroupedResult::InternalError;
+        }
+    }
+
+    match group_history(&state, user_id, query.periodo, &query.filtro).await {
+        Ok(grupos) => GroupedResult::Success(Json(GroupedStatementResponse {
+            periodo: query.periodo,
+            grupos,
+        })),
+        Err(err) => {
+            error!("failed to group history of client {user_id}: {err}");
+            GroupedResult::InternalError
+        }
+    }
+}
+
+pub async fn search_transactions(
+    Tenant(state): Tenant,
+    Path(user_id): Path<i32>,
+    Query(query): Query<SearchQuery>,
+    lang: Lang,
+) -> impl IntoResponse {
+    let mut v = Validator::new(lang);
+    let quantidade = page_len(&mut v, &state, query.quantidade);
+    if let Err(errors) = v.finish() {
+        return SearchResult::InvalidQuery(errors);
+    }
+
+    let found = match state
+        .storage
+        .search(user_id, &query.descricao, query.antes, quantidade)
+        .await
+    {
+        Ok(Some(found)) => found,
+        Ok(None) => return SearchResult::NotFound,
+        Err(err) => {
+            error!("failed to search transactions of client {user_id}: {err}");
+            return SearchResult::InternalError;
+        }
+    };
+
+    let proxima = match found.last() {
+        Some(last) if found.len() == quantidade => Some(last.sequencia),
+        _ => None,
+    };
+
+    SearchResult::Success(Json(SearchResponse {
+        transacoes: found.into_iter().map(LastTransaction::from).collect(),
+        proxima,
+    }))
+}
+
+struct HistoryCursor {
+    next: Option<u64>,
+    opened: bool,
+    written: bool,
+}
+
+/// The client's whole history as a JSON array, oldest first. Pages are read
+/// and serialized one at a time, so memory stays flat however long it is.
+pub async fn get_full_statement(
+    Tenant(state): Tenant,
+    Path(user_id): Path<i32>,
+    Query(filtro): Query<HistoryQuery>,
+) -> impl IntoResponse {
+    let stale = stale_mark(&state, user_id);
+    (stale, full_statement(state, user_id, filtro).await)
+}
+
+async fn full_statement(state: AppState, user_id: i32, filtro: HistoryQuery) -> HistoryResult {
+    let filtro = Arc::new(filtro);
+    match state.storage.client(user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HistoryResult::NotFound,
+        Err(err) => {
+            error!("failed to read history of client {user_id}: {err}");
+            return HistoryResult::InternalError;
+        }
+    }
+
+    let start = HistoryCursor {
+        next: Some(0),
+        opened: false,
+        written: false,
+    };
+
+    let chunks = stream::try_unfold(start, move |mut cursor| {
+        let storage = state.storage.clone();
+        let filtro = filtro.clone();
+        async move {
+            if cursor.opened && cursor.next.is_none() {
+                return Ok::<_, StorageError>(None);
+            }
+
+            let mut chunk = Vec::new();
+            if !cursor.opened {
+                chunk.push(b'[');
+                cursor.opened = true;
+            }
+
+            if let Some(position) = cursor.next {
+                let (page, next) = storage
+                    .history_page(user_id, position, HISTORY_PAGE_LEN)
+                    .await
+                    .inspect_err(|err| {
+                        error!("failed to stream history of client {user_id}: {err}")
+                    })?;
+
+                for statement in page.into_iter().filter(|s| filtro.matches(s)) {
+                    if cursor.written {
+                        chunk.push(b',');
+                    }
+                    serde_json::to_writer(&mut chunk, &LastTransaction::from(statement))?;
+                    cursor.written = true;
+                }
+                cursor.next = next;
+            }
+
+            if cursor.next.is_none() {
+                chunk.push(b']');
+            }
+
+            Ok(Some((chunk, cursor)))
+        }
+    });
+
+    HistoryResult::Stream(Body::from_stream(chunks))
+}
+
+/// Runs the checks `apply_transaction` would against the current balance.
+/// Another write may land in between, so a successful simulation is no
+/// promise the real transaction will pass.
+async fn simulate(
+    state: &AppState,
+    user_id: i32,
+    transaction: &NewTransaction,
+) -> TransactionResult {
+    let user = match state.storage.client(user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return TransactionResult::NotFound,
+        Err(err) => {
+            error!("failed to simulate transaction for client {user_id}: {err}");
+            return TransactionResult::InternalError;
+        }
+    };
+
+    match domain::apply_transaction(Account::from(&user), transaction) {
+        Ok(account) => TransactionResult::Simulated(Json(SimulationResponse {
+            limite: account.limite,
+            saldo: account.saldo,
+            simulada: true,
+        })),
+        Err(Rejection::Inactive) => TransactionResult::Gone,
+        Err(Rejection::LimitExceeded | Rejection::Overflow) => {
+            TransactionResult::UnprocessableEntity
+        }
+    }
+}
+
+/// With an `Idempotency-Key`, a retry of a transaction that was answered
+/// gets the same answer instead of being applied again.
+pub async fn create_transaction(
+    Tenant(state): Tenant,
+    Path(user_id): Path<i32>,
+    Query(query): Query<TransactionQuery>,
+    headers: HeaderMap,
+    Valid(new_statement): Valid<NewTransaction>,
+) -> impl IntoResponse {
+    if query.simular {
+        return simulate(&state, user_id, &new_statement).await;
+    }
+
+    let key = match idempotency::key_from_headers(&headers) {
+        Ok(Some(key)) => key,
+        Ok(None) => return apply(&state, user_id, new_statement).await,
+        Err(errors) => return TransactionResult::InvalidKey(errors),
+    };
+    let lang = Lang::from_headers(&headers);
+
+    let lookup = state
+        .idempotency
+        .begin(user_id, &key, &new_statement, state.clock.now())
+        .await;
+    let claim = match lookup {
+        Ok(Lookup::New(claim)) => claim,
+        Ok(Lookup::Replay(record)) => return TransactionResult::Replayed(record),
+        Ok(Lookup::Reused) => {
+            return TransactionResult::KeyReused(
+                Message::new("chave_idempotencia_reutilizada").localize(lang),
+            )
+        }
+        Ok(Lookup::InProgress) => {
+            return TransactionResult::KeyInUse(
+                Message::new("chave_idempotencia_em_uso").localize(lang),
+            )
+        }
+        Err(err) => {
+            error!("failed to look up idempotency key for client {user_id}: {err}");
+            return TransactionResult::InternalError;
+        }
+    };
+
+    let result = apply(&state, user_id, new_statement).await;
+    if let Some((status, corpo)) = result.to_record() {
+        state
+            .idempotency
+            .finish(claim, status, corpo, state.clock.now())
+            .await;
+    }
+    result
+}
+
+async fn apply(state: &AppState, user_id: i32, new_statement: NewTransaction) -> TransactionResult {
+    let (fingerprint, duplicate_of) =
+        match state
+            .duplicates
+            .check(user_id, &new_statement, state.clock.now())
+        {
+            None => (None, None),
+            Some(Check::New(fingerprint)) => (Some(fingerprint), None),
+            Some(Check::Duplicate { of, .. })
+                if state.duplicates.mode() == DuplicateMode::Reject =>
+            {
+                return TransactionResult::Duplicate(of);
+            }
+            Some(Check::Duplicate { fingerprint, of }) => (Some(fingerprint), of),
+        };
+
+    let reservation = match state
+        .quotas
+        .reserve(user_id, new_statement.valor, state.clock.now())
+    {
+        Ok(reservation) => reservation,
+        Err(exceeded) => {
+            if let Some(fingerprint) = &fingerprint {
+                state.duplicates.release(fingerprint);
+            }
+            return TransactionResult::OverQuota(exceeded);
+        }
+    };
+
+    let result = state.writes.submit(user_id, new_statement).await;
+    if let (Err(_), Some(reservation)) = (&result, reservation) {
+        state.quotas.release(reservation);
+    }
+    if let Some(fingerprint) = fingerprint {
+        match &result {
+            Ok((_, statement)) => {
+                state
+                    .duplicates
+                    .confirm(fingerprint, statement.uuid, statement.realizado_em)
+            }
+            Err(_) => state.duplicates.release(&fingerprint),
+        }
+    }
+
+    match result {
+        Ok((user, statement)) => {
+            state.committed(&user, &statement);
+            TransactionResult::Success(
+                Json(TransactionResponse {
+                    limite: user.limite,
+                    saldo: user.saldo,
+                    id: statement.uuid,
+                    sequencia: statement.sequencia,
+                }),
+                duplicate_of,
+            )
+        }
+        Err(WriteError::Overloaded) => TransactionResult::Overloaded,
+        Err(WriteError::Transaction(TransactionError::NotFound)) => TransactionResult::NotFound,
+        Err(WriteError::Transaction(TransactionError::Inactive)) => TransactionResult::Gone,
+        Err(WriteError::Transaction(TransactionError::Overflow)) => {
+            TransactionResult::UnprocessableEntity
+        }
+        Err(WriteError::Transaction(TransactionError::LimitExceeded)) => {
+            state.stats.record_rejected_debit();
+            TransactionResult::UnprocessableEntity
+        }
+        Err(WriteError::Transaction(TransactionError::Storage(err))) => {
+            error!("failed to apply transaction for client {user_id}: {err}");
+            TransactionResult::InternalError
+        }
+    }
+}
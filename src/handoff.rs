@@ -0,0 +1,151 @@
+use std::{
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+use tracing::{info, warn};
+
+use crate::{
+    settings::BoxError,
+    storage::{Dump, IdempotencyRecord},
+    tenants::TenantSnapshot,
+    AppState,
+};
+
+/// What a process hands to the one replacing it.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// The default tenant, when its storage lives in the process; other
+    /// backends keep their data where it is.
+    padrao: Option<Dump>,
+    /// The default tenant's records held in memory, so a retry sent to the
+    /// successor is not applied again.
+    idempotencia: Vec<(String, IdempotencyRecord)>,
+    tenants: Vec<TenantSnapshot>,
+}
+
+/// The answer a successor gets.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "resultado", rename_all = "snake_case")]
+enum Handover {
+    Aceito(Snapshot),
+    /// The state could not be settled in time, so this process keeps
+    /// serving and the successor should give up.
+    Recusado {
+        motivo: String,
+    },
+}
+
+/// Takes over from the process listening on `path`, if any: it stops taking
+/// new connections, lets the open ones finish and then sends its state, which
+/// replaces ours. Connections arriving meanwhile wait in our backlog. Then
+/// listens on `path` for whichever process comes to replace this one.
+pub async fn take_over(path: &Path, state: &AppState) -> Result<UnixListener, BoxError> {
+    match UnixStream::connect(path).await {
+        Ok(mut predecessor) => {
+            info!("taking over from the process at {}", path.display());
+
+            let mut body = Vec::new();
+            predecessor.read_to_end(&mut body).await?;
+            let snapshot = match serde_json::from_slice(&body)? {
+                Handover::Aceito(snapshot) => snapshot,
+                Handover::Recusado { motivo } => {
+                    return Err(format!("the running process refused the handoff: {motivo}").into())
+                }
+            };
+
+            let clientes = snapshot.padrao.as_ref().map_or(0, |d| d.clientes.len());
+            if let Some(dump) = snapshot.padrao {
+                state.storage.restore(dump).await?;
+            }
+            state.idempotency.hold(snapshot.idempotencia);
+            let tenants = snapshot.tenants.len();
+            for tenant in snapshot.tenants {
+                state.tenants.restore(tenant).await?;
+            }
+
+            info!(clientes, tenants, "state taken over");
+        }
+        // Nobody there: a first start, or the previous process is gone.
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    // The predecessor keeps its own listener open until it exits, but the
+    // path now belongs to us.
+    let _ = std::fs::remove_file(path);
+    Ok(UnixListener::bind(path)?)
+}
+
+/// Resolves once a successor connects.
+pub async fn successor(listener: &UnixListener) -> UnixStream {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => return stream,
+            Err(err) => {
+                warn!("failed to accept a handoff connection: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Sends our state to `successor` once it is settled: `drained` says whether
+/// every connection closed, and then every write queue must empty by
+/// `deadline`. Otherwise refuses, so no acknowledged write is left behind,
+/// and returns `false` for this process to keep serving. `in_process` says
+/// whether the default tenant's storage goes along.
+pub async fn hand_over(
+    mut successor: UnixStream,
+    state: &AppState,
+    in_process: bool,
+    drained: bool,
+    deadline: Instant,
+) -> Result<bool, BoxError> {
+    let settled = drained && {
+        while !state.writes_idle() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        state.writes_idle()
+    };
+
+    let handover = if settled {
+        Handover::Aceito(Snapshot {
+            padrao: if in_process {
+                Some(state.storage.dump().await?)
+            } else {
+                None
+            },
+            idempotencia: state.idempotency.held(state.clock.now()),
+            tenants: state.tenants.snapshot().await?,
+        })
+    } else if drained {
+        Handover::Recusado {
+            motivo: "writes still queued".to_owned(),
+        }
+    } else {
+        Handover::Recusado {
+            motivo: "requests still in flight".to_owned(),
+        }
+    };
+
+    successor.write_all(&serde_json::to_vec(&handover)?).await?;
+    successor.shutdown().await?;
+
+    if settled {
+        info!("state handed over");
+    } else {
+        warn!("refused the handoff, still serving");
+    }
+    Ok(settled)
+}
@@ -0,0 +1,203 @@
+use std::{collections::HashMap, convert::Infallible, fmt::Display, sync::LazyLock};
+
+use async_trait::async_trait;
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap},
+    response::{IntoResponse, Response},
+};
+
+type Catalog = HashMap<String, String>;
+
+fn catalog(source: &str) -> Catalog {
+    serde_json::from_str(source).expect("message catalogs are valid JSON")
+}
+
+static PT_BR: LazyLock<Catalog> =
+    LazyLock::new(|| catalog(include_str!("../assets/i18n/pt-BR.json")));
+static EN: LazyLock<Catalog> = LazyLock::new(|| catalog(include_str!("../assets/i18n/en.json")));
+
+/// Language error messages are written in. Brazilian Portuguese unless the
+/// request's `Accept-Language` prefers English.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Lang {
+    #[default]
+    PtBr,
+    En,
+}
+
+impl Lang {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Lang::PtBr => "pt-BR",
+            Lang::En => "en",
+        }
+    }
+
+    fn catalog(self) -> &'static Catalog {
+        match self {
+            Lang::PtBr => &PT_BR,
+            Lang::En => &EN,
+        }
+    }
+
+    /// The supported language with the highest `q` in `Accept-Language`; the
+    /// first one listed wins a tie.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(accepted) = headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Lang::default();
+        };
+
+        let mut best: Option<(Lang, f32)> = None;
+        for range in accepted.split(',') {
+            let mut parts = range.split(';');
+            let tag = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let lang = match tag.split('-').next() {
+                Some("pt") | Some("*") => Lang::PtBr,
+                Some("en") => Lang::En,
+                _ => continue,
+            };
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((lang, q));
+            }
+        }
+
+        best.map_or_else(Lang::default, |(lang, _)| lang)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Lang {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Lang::from_headers(&parts.headers))
+    }
+}
+
+/// A catalog key and the values for its `{placeholders}`.
+pub struct Message {
+    key: &'static str,
+    args: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    pub fn new(key: &'static str) -> Self {
+        Message {
+            key,
+            args: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, name: &'static str, value: impl Display) -> Self {
+        self.args.push((name, value.to_string()));
+        self
+    }
+
+    /// Falls back to the key itself if a catalog lacks it.
+    pub fn localize(&self, lang: Lang) -> Localized {
+        let mut text = lang
+            .catalog()
+            .get(self.key)
+            .cloned()
+            .unwrap_or_else(|| self.key.to_owned());
+        for (name, value) in &self.args {
+            text = text.replace(&format!("{{{name}}}"), value);
+        }
+
+        Localized { lang, text }
+    }
+}
+
+/// A message in the language it was asked for, sent as a plain text body.
+pub struct Localized {
+    lang: Lang,
+    text: String,
+}
+
+impl Localized {
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+impl IntoResponse for Localized {
+    fn into_response(self) -> Response {
+        ([(header::CONTENT_LANGUAGE, self.lang.tag())], self.text).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn lang(accept_language: &'static str) -> Lang {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_LANGUAGE,
+            HeaderValue::from_static(accept_language),
+        );
+        Lang::from_headers(&headers)
+    }
+
+    #[test]
+    fn portuguese_unless_english_is_preferred() {
+        assert_eq!(Lang::from_headers(&HeaderMap::new()), Lang::PtBr);
+        assert_eq!(lang("en"), Lang::En);
+        assert_eq!(lang("EN-us"), Lang::En);
+        assert_eq!(lang("pt-BR"), Lang::PtBr);
+        assert_eq!(lang("fr, de"), Lang::PtBr);
+        assert_eq!(lang("*"), Lang::PtBr);
+    }
+
+    #[test]
+    fn the_highest_q_wins_and_the_first_one_a_tie() {
+        assert_eq!(lang("pt;q=0.5, en;q=0.8"), Lang::En);
+        assert_eq!(lang("en;q=0.5, pt-BR"), Lang::PtBr);
+        assert_eq!(lang("en, pt"), Lang::En);
+        assert_eq!(lang("pt;q=0.7, en;q=0.7"), Lang::PtBr);
+        assert_eq!(lang("fr, en;q=0.1"), Lang::En);
+    }
+
+    #[test]
+    fn refused_and_malformed_ranges_are_skipped() {
+        assert_eq!(lang("en;q=0, pt;q=0.1"), Lang::PtBr);
+        assert_eq!(lang("en;q=0"), Lang::PtBr);
+        assert_eq!(lang("en;q=high"), Lang::En);
+        assert_eq!(lang(",,;"), Lang::PtBr);
+    }
+
+    #[test]
+    fn both_catalogs_have_the_same_keys() {
+        let mut pt: Vec<_> = PT_BR.keys().collect();
+        let mut en: Vec<_> = EN.keys().collect();
+        pt.sort();
+        en.sort();
+        assert_eq!(pt, en);
+    }
+
+    #[test]
+    fn placeholders_are_filled_in_and_unknown_keys_fall_back() {
+        let message = Message::new("campo_curto")
+            .with("campo", "descricao")
+            .with("min", 1);
+        assert_eq!(
+            message.localize(Lang::En).into_text(),
+            "descricao must have at least 1 characters"
+        );
+        assert_eq!(
+            Message::new("nao_existe").localize(Lang::En).into_text(),
+            "nao_existe"
+        );
+    }
+}
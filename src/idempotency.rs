@@ -0,0 +1,224 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use axum::http::{HeaderMap, StatusCode};
+use chrono::{DateTime, TimeDelta, Utc};
+use tracing::warn;
+
+use crate::{
+    i18n::Lang,
+    models::NewTransaction,
+    storage::{IdempotencyRecord, Storage, StorageError},
+    validation::{ValidationErrors, Validator},
+};
+
+/// Request header a client sends to make retrying a transaction safe.
+pub const HEADER: &str = "idempotency-key";
+
+/// Response header set on an answer replayed from an earlier request.
+pub const REPLAYED: &str = "idempotent-replayed";
+
+/// The header as named in validation errors.
+const FIELD: &str = "Idempotency-Key";
+
+const MAX_KEY_LEN: usize = 255;
+
+/// The key a request was sent with, if any; problems with it are answered
+/// the way body fields' are.
+pub fn key_from_headers(headers: &HeaderMap) -> Result<Option<String>, ValidationErrors> {
+    let Some(value) = headers.get(HEADER) else {
+        return Ok(None);
+    };
+
+    let mut v = Validator::new(Lang::from_headers(headers));
+    match value.to_str() {
+        Ok(key) => v.length(FIELD, key, 1, MAX_KEY_LEN),
+        Err(_) => v.error(FIELD, "invalid", "chave_idempotencia_invalida"),
+    }
+    v.finish()?;
+
+    Ok(value.to_str().ok().map(str::to_owned))
+}
+
+/// The records of backends that do not keep them, dropping the least
+/// recently used ones past `capacity`.
+struct Recent {
+    capacity: usize,
+    tick: u64,
+    records: HashMap<String, (u64, IdempotencyRecord)>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Recent {
+    fn get(&mut self, key: &str, now: DateTime<Utc>) -> Option<IdempotencyRecord> {
+        let (tick, record) = self.records.get_mut(key)?;
+        self.order.remove(tick);
+
+        if record.expira_em <= now {
+            self.records.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        *tick = self.tick;
+        let record = record.clone();
+        self.order.insert(self.tick, key.to_owned());
+        Some(record)
+    }
+
+    fn insert(&mut self, key: String, record: IdempotencyRecord) {
+        self.tick += 1;
+        if let Some((previous, _)) = self.records.insert(key.clone(), (self.tick, record)) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(self.tick, key);
+
+        while self.records.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.records.remove(&oldest);
+        }
+    }
+}
+
+pub enum Lookup {
+    /// Nothing recorded under the key; hand the claim back to
+    /// [`IdempotencyStore::finish`] with the answer.
+    New(Claim),
+    Replay(IdempotencyRecord),
+    /// The key was first used for another transaction.
+    Reused,
+    /// A request with the same key is still being answered.
+    InProgress,
+}
+
+/// Holds a key while its request is answered. Dropping it without
+/// finishing, as when the answer is worth retrying, records nothing.
+pub struct Claim {
+    key: String,
+    request: NewTransaction,
+    in_flight: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Answers to the requests sent with an `Idempotency-Key`, per client and
+/// for `ttl`. Saved to storage when the backend keeps them, so they survive a
+/// restart, and held in memory otherwise.
+pub struct IdempotencyStore {
+    storage: Option<Arc<dyn Storage>>,
+    ttl: TimeDelta,
+    recent: Mutex<Recent>,
+    in_flight: Arc<Mutex<HashSet<String>>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(storage: &Arc<dyn Storage>, capacity: usize, ttl_secs: u64) -> Self {
+        IdempotencyStore {
+            storage: storage.keeps_idempotency_records().then(|| storage.clone()),
+            ttl: TimeDelta::seconds(ttl_secs as i64),
+            recent: Mutex::new(Recent {
+                capacity,
+                tick: 0,
+                records: HashMap::new(),
+                order: BTreeMap::new(),
+            }),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// The records held in memory that have not expired, least recently used
+    /// first, to hand them to another process. Backends that keep records
+    /// hold none here.
+    pub fn held(&self, now: DateTime<Utc>) -> Vec<(String, IdempotencyRecord)> {
+        let recent = self.recent.lock().unwrap();
+        recent
+            .order
+            .values()
+            .filter_map(|key| {
+                let (_, record) = &recent.records[key];
+                (record.expira_em > now).then(|| (key.clone(), record.clone()))
+            })
+            .collect()
+    }
+
+    /// Takes over records [`held`](Self::held) by another process.
+    pub fn hold(&self, records: Vec<(String, IdempotencyRecord)>) {
+        let mut recent = self.recent.lock().unwrap();
+        for (key, record) in records {
+            recent.insert(key, record);
+        }
+    }
+
+    pub async fn begin(
+        &self,
+        cliente: i32,
+        key: &str,
+        request: &NewTransaction,
+        now: DateTime<Utc>,
+    ) -> Result<Lookup, StorageError> {
+        let key = format!("{cliente}/{key}");
+        if !self.in_flight.lock().unwrap().insert(key.clone()) {
+            return Ok(Lookup::InProgress);
+        }
+        let claim = Claim {
+            key,
+            request: request.clone(),
+            in_flight: self.in_flight.clone(),
+        };
+
+        let record = match &self.storage {
+            Some(storage) => storage.idempotency_record(&claim.key, now).await?,
+            None => {
+                let mut recent = self.recent.lock().unwrap();
+                recent.get(&claim.key, now)
+            }
+        };
+
+        Ok(match record {
+            None => Lookup::New(claim),
+            Some(record) if record.requisicao == *request => Lookup::Replay(record),
+            Some(_) => Lookup::Reused,
+        })
+    }
+
+    /// Records the answer the claimed request got. Failing to save it only
+    /// costs the guarantee for later retries, so it is logged and not passed
+    /// on.
+    pub async fn finish(
+        &self,
+        claim: Claim,
+        status: StatusCode,
+        corpo: Option<String>,
+        now: DateTime<Utc>,
+    ) {
+        let record = IdempotencyRecord {
+            requisicao: claim.request.clone(),
+            status: status.as_u16(),
+            corpo,
+            expira_em: now + self.ttl,
+        };
+
+        match &self.storage {
+            Some(storage) => {
+                if let Err(err) = storage
+                    .save_idempotency_record(&claim.key, &record, now)
+                    .await
+                {
+                    warn!("failed to save the idempotency record {}: {err}", claim.key);
+                }
+            }
+            None => {
+                let mut recent = self.recent.lock().unwrap();
+                recent.insert(claim.key.clone(), record);
+            }
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+use uuid::Uuid;
+
+/// Highest `--node-id` a snowflake has room for.
+pub const MAX_NODE_ID: u16 = (1 << NODE_BITS) - 1;
+
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+
+/// Snowflake timestamps count milliseconds from 2024-01-01T00:00:00Z, which
+/// leaves room for about 69 years of them in 41 bits.
+const EPOCH_MS: u64 = 1_704_067_200_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IdStrategy {
+    /// Random, time-ordered UUIDs; nothing to coordinate
+    Uuidv7,
+    /// 1, 2, 3... carried on from the highest one stored; one writer per
+    /// backend only
+    Sequential,
+    /// Time, --node-id and a per-millisecond counter, so instances with
+    /// distinct node ids never collide
+    Snowflake,
+}
+
+/// Issues the ids transactions are known by. Whatever the strategy, an id is
+/// a UUID on the wire; the integer ones are zero in their upper 64 bits, e.g.
+/// `00000000-0000-0000-0000-00000000002a` for 42.
+pub trait IdGenerator: Send + Sync {
+    fn next(&self) -> Uuid;
+
+    /// Whether `resume` must be called, with the highest integer id already
+    /// stored, before the first id is issued.
+    fn resumes(&self) -> bool {
+        false
+    }
+
+    fn resume(&self, _highest: u64) {}
+}
+
+/// The integer `id` stands for, when an integer strategy issued it.
+pub fn integer(id: Uuid) -> Option<u64> {
+    match id.as_u64_pair() {
+        (0, n) => Some(n),
+        _ => None,
+    }
+}
+
+pub struct UuidV7;
+
+impl IdGenerator for UuidV7 {
+    fn next(&self) -> Uuid {
+        Uuid::now_v7()
+    }
+}
+
+#[derive(Default)]
+pub struct Sequential {
+    last: AtomicU64,
+}
+
+impl IdGenerator for Sequential {
+    fn next(&self) -> Uuid {
+        Uuid::from_u64_pair(0, self.last.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    fn resumes(&self) -> bool {
+        true
+    }
+
+    fn resume(&self, highest: u64) {
+        self.last.fetch_max(highest, Ordering::Relaxed);
+    }
+}
+
+pub struct Snowflake {
+    node: u64,
+    /// Millisecond and counter of the last id issued.
+    last: Mutex<(u64, u64)>,
+}
+
+impl Snowflake {
+    pub fn new(node: u16) -> Self {
+        Snowflake {
+            node: u64::from(node.min(MAX_NODE_ID)),
+            last: Mutex::new((0, 0)),
+        }
+    }
+}
+
+fn millis_since_epoch() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    now.saturating_sub(EPOCH_MS)
+}
+
+impl IdGenerator for Snowflake {
+    /// A wall clock stepping back keeps using the last millisecond seen; one
+    /// whose counter ran out borrows the next.
+    fn next(&self) -> Uuid {
+        let mut last = self.last.lock().unwrap();
+        let (mut millis, mut sequence) = (millis_since_epoch().max(last.0), 0);
+        if millis == last.0 {
+            sequence = last.1 + 1;
+            if sequence >> SEQUENCE_BITS != 0 {
+                millis += 1;
+                sequence = 0;
+            }
+        }
+        *last = (millis, sequence);
+
+        let id = millis << (NODE_BITS + SEQUENCE_BITS) | self.node << SEQUENCE_BITS | sequence;
+        Uuid::from_u64_pair(0, id)
+    }
+}
+
+/// A generator for one backend's transactions; `node_id` only matters to
+/// snowflakes.
+pub fn generator(strategy: IdStrategy, node_id: u16) -> Arc<dyn IdGenerator> {
+    match strategy {
+        IdStrategy::Uuidv7 => Arc::new(UuidV7),
+        IdStrategy::Sequential => Arc::new(Sequential::default()),
+        IdStrategy::Snowflake => Arc::new(Snowflake::new(node_id)),
+    }
+}
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// 48 bits of milliseconds since the Unix epoch and 80 random ones, written
+/// as 26 Crockford base32 characters that sort the same way the ids do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ulid(u128);
+
+impl fmt::Display for Ulid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = [0u8; 26];
+        for (i, c) in text.iter_mut().enumerate() {
+            let shift = 5 * (25 - i);
+            *c = CROCKFORD[((self.0 >> shift) & 0x1f) as usize];
+        }
+        f.write_str(std::str::from_utf8(&text).unwrap())
+    }
+}
+
+impl FromStr for Ulid {
+    type Err = ();
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text.len() != 26 || text.as_bytes()[0] > b'7' {
+            return Err(());
+        }
+        text.bytes()
+            .try_fold(0u128, |ulid, c| {
+                let c = c.to_ascii_uppercase();
+                let value = CROCKFORD.iter().position(|&d| d == c).ok_or(())?;
+                Ok(ulid << 5 | value as u128)
+            })
+            .map(Ulid)
+    }
+}
+
+impl Serialize for Ulid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Hands out strictly increasing ULIDs: within a millisecond, or with the
+/// wall clock stepping back, each is the previous one plus one.
+#[derive(Default)]
+pub struct UlidGenerator {
+    last: Ulid,
+}
+
+impl UlidGenerator {
+    pub fn next(&mut self) -> Ulid {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        self.last = if millis > self.last.0 >> 80 {
+            let random = Uuid::now_v7().as_u128() & ((1 << 80) - 1);
+            Ulid(millis << 80 | random)
+        } else {
+            Ulid(self.last.0 + 1)
+        };
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_of(id: Uuid) -> u64 {
+        (id.as_u64_pair().1 >> SEQUENCE_BITS) & u64::from(MAX_NODE_ID)
+    }
+
+    #[test]
+    fn snowflakes_increase_past_the_per_millisecond_counter() {
+        let snowflake = Snowflake::new(7);
+        let ids: Vec<_> = (0..3 << SEQUENCE_BITS).map(|_| snowflake.next()).collect();
+
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids.iter().all(|id| id.as_u64_pair().0 == 0));
+        assert!(ids.iter().all(|&id| node_of(id) == 7));
+    }
+
+    #[test]
+    fn snowflakes_of_distinct_nodes_never_collide() {
+        let (a, b) = (Snowflake::new(1), Snowflake::new(2));
+        let mut ids: Vec<_> = (0..1000).flat_map(|_| [a.next(), b.next()]).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 2000);
+    }
+
+    #[test]
+    fn snowflake_nodes_are_capped_to_their_bits() {
+        assert_eq!(
+            node_of(Snowflake::new(u16::MAX).next()),
+            u64::from(MAX_NODE_ID)
+        );
+    }
+
+    #[test]
+    fn sequential_ids_carry_on_from_the_highest_integer_stored() {
+        let sequential = Sequential::default();
+        sequential.resume(41);
+        sequential.resume(3);
+        assert_eq!(sequential.next(), Uuid::from_u64_pair(0, 42));
+    }
+
+    #[test]
+    fn only_ids_of_integer_strategies_are_integers() {
+        assert_eq!(integer(Uuid::from_u64_pair(0, 42)), Some(42));
+        assert_eq!(integer(Snowflake::new(1).next()).map(|n| n > 0), Some(true));
+        assert_eq!(integer(Uuid::now_v7()), None);
+    }
+
+    #[test]
+    fn ulids_increase_and_sort_as_text() {
+        let mut generator = UlidGenerator::default();
+        let ids: Vec<_> = (0..1000).map(|_| generator.next()).collect();
+
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids
+            .windows(2)
+            .all(|pair| pair[0].to_string() < pair[1].to_string()));
+    }
+
+    #[test]
+    fn ulids_parse_back_from_text() {
+        let ulid = UlidGenerator::default().next();
+        let text = ulid.to_string();
+
+        assert_eq!(text.len(), 26);
+        assert_eq!(text.parse::<Ulid>(), Ok(ulid));
+        assert_eq!(text.to_lowercase().parse::<Ulid>(), Ok(ulid));
+        assert!("8ZZZZZZZZZZZZZZZZZZZZZZZZZ".parse::<Ulid>().is_err());
+        assert!("01HQ".parse::<Ulid>().is_err());
+    }
+}
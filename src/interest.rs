@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use tokio::time::{interval_at, Instant};
+use tracing::{error, info, warn};
+
+use crate::{models::NewTransaction, storage::TransactionError, writer::WriteError, AppState};
+
+/// What accrued interest shows up as in the extrato.
+const DESCRICAO: &str = "juros";
+
+/// Parses `--interest-rate`: a fraction, finite and not negative.
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid rate `{value}`"))?;
+    if !rate.is_finite() || rate < 0.0 {
+        return Err(format!(
+            "the rate must be a finite fraction of 0 or more, got `{value}`"
+        ));
+    }
+    Ok(rate)
+}
+
+/// Interest owed on `saldo` at `rate`, rounded up and capped so the debit never
+/// takes the client past its limite.
+fn interest_due(saldo: i32, limite: i32, rate: f64) -> i32 {
+    if saldo >= 0 {
+        return 0;
+    }
+
+    let due = (f64::from(saldo).abs() * rate).ceil() as i64;
+    let headroom = i64::from(limite) + i64::from(saldo);
+    due.min(headroom).max(0) as i32
+}
+
+/// Debits interest from every active client with a negative balance once per
+/// `period`. Each running instance charges on its own, so enable it on one.
+pub async fn accrue_interest(state: AppState, rate: f64, period: Duration) {
+    let mut ticker = interval_at(Instant::now() + period, period);
+
+    loop {
+        ticker.tick().await;
+
+        if state.maintenance.active() {
+            info!("interest run skipped, in maintenance");
+            continue;
+        }
+
+        let clientes = match state.storage.list_clients(false).await {
+            Ok(clientes) => clientes,
+            Err(err) => {
+                error!("interest run skipped, cannot list clients: {err}");
+                continue;
+            }
+        };
+
+        let mut charged = 0;
+        for user in clientes {
+            let valor = interest_due(user.saldo, user.limite, rate);
+            if valor == 0 {
+                continue;
+            }
+
+            let transaction = NewTransaction {
+                valor,
+                tipo: "d".to_owned(),
+                descricao: DESCRICAO.to_owned(),
+                categoria: None,
+                tags: Vec::new(),
+            };
+
+            match state.writes.submit(user.id, transaction).await {
+                Ok((user, statement)) => {
+                    state.committed(&user, &statement);
+                    charged += 1;
+                }
+                // The balance moved since it was read; next run catches up.
+                Err(WriteError::Transaction(TransactionError::LimitExceeded)) => {}
+                Err(WriteError::Overloaded) => {
+                    warn!(cliente = user.id, "interest not charged, write queue full")
+                }
+                Err(WriteError::Transaction(TransactionError::Storage(err))) => {
+                    error!("failed to charge interest to client {}: {err}", user.id)
+                }
+                Err(WriteError::Transaction(_)) => {}
+            }
+        }
+
+        info!(clientes = charged, "interest charged");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_are_finite_and_not_negative() {
+        assert_eq!(parse_rate("0.02"), Ok(0.02));
+        assert_eq!(parse_rate("0"), Ok(0.0));
+        for value in ["-0.1", "NaN", "inf", "-inf", "", "dois"] {
+            assert!(parse_rate(value).is_err(), "{value:?}");
+        }
+    }
+
+    #[test]
+    fn interest_never_takes_a_client_past_its_limit() {
+        assert_eq!(interest_due(100, 1000, 0.1), 0);
+        assert_eq!(interest_due(-101, 1000, 0.1), 11);
+        assert_eq!(interest_due(-995, 1000, 0.1), 5);
+        assert_eq!(interest_due(-1000, 1000, 0.1), 0);
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use clap::ValueEnum;
+use tokio::sync::oneshot;
+
+use crate::{cli::Config, metrics};
+
+const IN_FLIGHT: &str = "lane_in_flight";
+const SHED: &str = "lane_shed_total";
+const LIMIT: &str = "lane_limit";
+
+/// Latencies the adaptive limit looks at before moving, unless a window of
+/// time passes first.
+const WINDOW_SAMPLES: usize = 200;
+const WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReadLane {
+    /// Reads wait for a slot like writes do, but behind every waiting write
+    Queue,
+    /// Reads that find no free slot are refused right away
+    Shed,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Lane {
+    Read,
+    Write,
+}
+
+impl Lane {
+    /// Only extratos and new transactions take a lane; everything else is
+    /// let through as before.
+    fn of(request: &Request) -> Option<Lane> {
+        let path = request.uri().path();
+        if !path.starts_with("/clientes/") {
+            return None;
+        }
+        match *request.method() {
+            Method::POST if path.ends_with("/transacoes") => Some(Lane::Write),
+            Method::GET if path.contains("/extrato") => Some(Lane::Read),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Lane::Read => "read",
+            Lane::Write => "write",
+        }
+    }
+}
+
+/// What the adaptive limit has seen since it last moved.
+struct Window {
+    started: Instant,
+    latencies: Vec<Duration>,
+    /// Most requests in flight at once.
+    busiest: usize,
+}
+
+impl Window {
+    fn new() -> Self {
+        Window {
+            started: Instant::now(),
+            latencies: Vec::with_capacity(WINDOW_SAMPLES),
+            busiest: 0,
+        }
+    }
+
+    fn due(&self) -> bool {
+        self.latencies.len() >= WINDOW_SAMPLES
+            || (!self.latencies.is_empty() && self.started.elapsed() >= WINDOW)
+    }
+
+    fn quantile(&mut self, q: f64) -> Duration {
+        self.latencies.sort_unstable();
+        let rank = (self.latencies.len() as f64 * q).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+struct Slots {
+    /// Requests let in at once, of which reads may only hold `read_limit`.
+    limit: usize,
+    read_limit: usize,
+    reads: usize,
+    writes: usize,
+    /// Told when a slot was taken on their behalf.
+    waiting_reads: VecDeque<oneshot::Sender<()>>,
+    waiting_writes: VecDeque<oneshot::Sender<()>>,
+    /// Only with an adaptive limit.
+    window: Option<Window>,
+}
+
+impl Slots {
+    fn in_flight(&self) -> usize {
+        self.reads + self.writes
+    }
+
+    fn take(&mut self, lane: Lane) {
+        match lane {
+            Lane::Read => self.reads += 1,
+            Lane::Write => self.writes += 1,
+        }
+        let in_flight = self.in_flight();
+        if let Some(window) = &mut self.window {
+            window.busiest = window.busiest.max(in_flight);
+        }
+    }
+
+    fn give_back(&mut self, lane: Lane) {
+        match lane {
+            Lane::Read => self.reads -= 1,
+            Lane::Write => self.writes -= 1,
+        }
+    }
+}
+
+/// Admits a limited number of reads and writes at once, of which reads may
+/// only hold their share. A freed slot goes to the oldest waiting write
+/// before any read, since a failed transaction costs more than a slow
+/// extrato.
+///
+/// The limit is `max` unless adaptive: then, after every window, it shrinks
+/// by a tenth while the `quantile` of the latencies seen is over `target`,
+/// and grows by one while within it and the whole limit was in use, never
+/// past `max`.
+pub struct Lanes {
+    max: usize,
+    read_share: usize,
+    target: Duration,
+    quantile: f64,
+    read_lane: ReadLane,
+    wait: Duration,
+    slots: Mutex<Slots>,
+}
+
+/// One admitted request's slot, handed on to the next waiter when dropped.
+pub struct Permit {
+    lanes: Arc<Lanes>,
+    lane: Lane,
+    admitted: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.lanes.release(self.lane, Some(self.admitted.elapsed()));
+    }
+}
+
+fn read_limit(limit: usize, read_share: usize) -> usize {
+    (limit * read_share / 100).max(1)
+}
+
+impl Lanes {
+    pub fn new(config: &Config, max: usize) -> Self {
+        let read_share = usize::from(config.read_share);
+        metrics::gauge(LIMIT, Vec::new()).set(max as f64);
+        Lanes {
+            max,
+            read_share,
+            target: Duration::from_millis(config.slo_latency_ms),
+            quantile: config.slo_target,
+            read_lane: config.read_lane,
+            wait: Duration::from_millis(config.lane_wait_ms),
+            slots: Mutex::new(Slots {
+                limit: max,
+                read_limit: read_limit(max, read_share),
+                reads: 0,
+                writes: 0,
+                waiting_reads: VecDeque::new(),
+                waiting_writes: VecDeque::new(),
+                window: config.adaptive_concurrency.then(Window::new),
+            }),
+        }
+    }
+
+    fn fits(slots: &Slots, lane: Lane) -> bool {
+        slots.in_flight() < slots.limit && (lane == Lane::Write || slots.reads < slots.read_limit)
+    }
+
+    fn permit(self: &Arc<Self>, lane: Lane) -> Permit {
+        Permit {
+            lanes: self.clone(),
+            lane,
+            admitted: Instant::now(),
+        }
+    }
+
+    /// Moves the limit once the window is due.
+    fn adapt(&self, slots: &mut Slots, latency: Duration) {
+        let Some(window) = &mut slots.window else {
+            return;
+        };
+        window.latencies.push(latency);
+        if !window.due() {
+            return;
+        }
+
+        let observed = window.quantile(self.quantile);
+        let saturated = window.busiest >= slots.limit;
+        *window = Window::new();
+
+        let limit = if observed > self.target {
+            (slots.limit - slots.limit.div_ceil(10)).max(1)
+        } else if saturated {
+            (slots.limit + 1).min(self.max)
+        } else {
+            slots.limit
+        };
+        if limit != slots.limit {
+            slots.limit = limit;
+            slots.read_limit = read_limit(limit, self.read_share);
+            metrics::gauge(LIMIT, Vec::new()).set(limit as f64);
+        }
+    }
+
+    fn gauges(slots: &Slots) {
+        metrics::gauge(IN_FLIGHT, vec![("lane", "read".to_owned())]).set(slots.reads as f64);
+        metrics::gauge(IN_FLIGHT, vec![("lane", "write".to_owned())]).set(slots.writes as f64);
+    }
+
+    /// A slot right away, a receiver to wait on for one, or `None` when the
+    /// request is to be shed.
+    fn try_admit(self: &Arc<Self>, lane: Lane) -> Option<Result<Permit, oneshot::Receiver<()>>> {
+        let mut slots = self.slots.lock().unwrap();
+        // Reads never jump ahead of waiting writes, but writes that gave up
+        // no longer count.
+        slots.waiting_writes.retain(|waiter| !waiter.is_closed());
+        let writes_waiting = !slots.waiting_writes.is_empty();
+        if Self::fits(&slots, lane) && (lane == Lane::Write || !writes_waiting) {
+            slots.take(lane);
+            Self::gauges(&slots);
+            return Some(Ok(self.permit(lane)));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        match lane {
+            Lane::Read if self.read_lane == ReadLane::Shed => return None,
+            Lane::Read => slots.waiting_reads.push_back(sender),
+            Lane::Write => slots.waiting_writes.push_back(sender),
+        }
+        Some(Err(receiver))
+    }
+
+    /// `latency` is how long the request held the slot; `None` for slots
+    /// taken for a waiter that had given up.
+    fn release(&self, lane: Lane, latency: Option<Duration>) {
+        let mut slots = self.slots.lock().unwrap();
+        slots.give_back(lane);
+        if let Some(latency) = latency {
+            self.adapt(&mut slots, latency);
+        }
+
+        for next in [Lane::Write, Lane::Read] {
+            while Self::fits(&slots, next) {
+                let waiting = match next {
+                    Lane::Read => &mut slots.waiting_reads,
+                    Lane::Write => &mut slots.waiting_writes,
+                };
+                let Some(waiter) = waiting.pop_front() else {
+                    break;
+                };
+
+                slots.take(next);
+                if waiter.send(()).is_err() {
+                    // That waiter already gave up.
+                    slots.give_back(next);
+                }
+            }
+        }
+        Self::gauges(&slots);
+    }
+
+    async fn admit(self: &Arc<Self>, lane: Lane) -> Option<Permit> {
+        let mut receiver = match self.try_admit(lane)? {
+            Ok(permit) => return Some(permit),
+            Err(receiver) => receiver,
+        };
+
+        if let Ok(Ok(())) = tokio::time::timeout(self.wait, &mut receiver).await {
+            return Some(self.permit(lane));
+        }
+        // A slot may have been taken for us just as the wait ran out.
+        receiver.close();
+        if receiver.try_recv().is_ok() {
+            self.release(lane, None);
+        }
+        None
+    }
+}
+
+/// Turns away with a 503 the requests that found no slot within
+/// `--lane-wait-ms`.
+pub async fn admit(State(lanes): State<Arc<Lanes>>, request: Request, next: Next) -> Response {
+    let Some(lane) = Lane::of(&request) else {
+        return next.run(request).await;
+    };
+
+    match lanes.admit(lane).await {
+        Some(_permit) => next.run(request).await,
+        None => {
+            metrics::counter(SHED, vec![("lane", lane.name().to_owned())]).inc();
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::cli::Cli;
+
+    fn lanes(args: &[&str]) -> Arc<Lanes> {
+        let cli =
+            Cli::try_parse_from([&["rust-lang", "--slo-latency-ms=10"], args].concat()).unwrap();
+        let max = cli.config.max_in_flight.unwrap();
+        Arc::new(Lanes::new(&cli.config, max))
+    }
+
+    fn adaptive(max: usize) -> Arc<Lanes> {
+        lanes(&[&format!("--max-in-flight={max}"), "--adaptive-concurrency"])
+    }
+
+    fn limit(lanes: &Lanes) -> usize {
+        lanes.slots.lock().unwrap().limit
+    }
+
+    /// One window's worth of writes, `at_once` of them in flight together,
+    /// each holding its slot for `latency`.
+    fn window(lanes: &Lanes, at_once: usize, latency: Duration) {
+        let mut released = 0;
+        while released < WINDOW_SAMPLES {
+            let batch = at_once.min(WINDOW_SAMPLES - released);
+            for _ in 0..batch {
+                lanes.slots.lock().unwrap().take(Lane::Write);
+            }
+            for _ in 0..batch {
+                lanes.release(Lane::Write, Some(latency));
+            }
+            released += batch;
+        }
+    }
+
+    #[test]
+    fn the_limit_shrinks_by_a_tenth_while_over_the_target() {
+        let lanes = adaptive(20);
+        window(&lanes, 1, Duration::from_millis(50));
+        assert_eq!(limit(&lanes), 18);
+        window(&lanes, 1, Duration::from_millis(50));
+        assert_eq!(limit(&lanes), 16);
+    }
+
+    #[test]
+    fn the_limit_never_drops_to_zero() {
+        let lanes = adaptive(3);
+        for _ in 0..10 {
+            window(&lanes, 1, Duration::from_millis(50));
+        }
+        assert_eq!(limit(&lanes), 1);
+    }
+
+    #[test]
+    fn the_limit_grows_back_only_while_saturated_and_never_past_the_max() {
+        let lanes = adaptive(20);
+        window(&lanes, 1, Duration::from_millis(50));
+        assert_eq!(limit(&lanes), 18);
+
+        window(&lanes, 5, Duration::from_millis(1));
+        assert_eq!(limit(&lanes), 18);
+        for expected in [19, 20, 20] {
+            window(&lanes, limit(&lanes), Duration::from_millis(1));
+            assert_eq!(limit(&lanes), expected);
+        }
+    }
+
+    #[test]
+    fn a_fixed_limit_never_moves() {
+        let lanes = lanes(&["--max-in-flight=20"]);
+        window(&lanes, 1, Duration::from_millis(50));
+        assert_eq!(limit(&lanes), 20);
+    }
+
+    #[test]
+    fn freed_slots_go_to_waiting_writes_before_reads() {
+        let lanes = lanes(&["--max-in-flight=1"]);
+        let held = lanes.try_admit(Lane::Write).unwrap().ok().unwrap();
+        let Some(Err(mut read)) = lanes.try_admit(Lane::Read) else {
+            panic!("the read should wait");
+        };
+        let Some(Err(mut write)) = lanes.try_admit(Lane::Write) else {
+            panic!("the write should wait");
+        };
+
+        drop(held);
+        assert!(write.try_recv().is_ok());
+        assert!(read.try_recv().is_err());
+    }
+}
@@ -0,0 +1,6 @@
+//! The API's request and response types, and with the `client` feature a
+//! typed HTTP client for it. The server itself is the binary.
+
+#[cfg(feature = "client")]
+pub mod client;
+pub mod models;
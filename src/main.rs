@@ -1,262 +1,555 @@
-use std::{collections::HashMap, sync::Arc};
+mod admin;
+mod alerts;
+mod audit;
+mod auth;
+mod base_path;
+mod bundle;
+mod cache;
+mod cache_control;
+mod chaos;
+mod cli;
+mod clock;
+mod connections;
+mod deadline;
+mod domain;
+mod duplicates;
+mod feed;
+mod fields;
+mod handlers;
+mod handoff;
+mod i18n;
+mod idempotency;
+mod ids;
+mod interest;
+mod lanes;
+mod maintenance;
+mod metrics;
+mod mirror;
+mod quotas;
+mod settings;
+mod stats;
+mod storage;
+mod tenants;
+mod validation;
+mod warmup;
+mod writer;
+
+use std::{
+    fs,
+    io::{self, Write},
+    iter,
+    net::SocketAddr,
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
-    body::Body,
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
+    middleware,
+    routing::{delete, get, post, put},
+    Router,
 };
-use chrono::Utc;
-use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct Statement {
-    pub id: i32,
-    pub valor: i32,
-    pub tipo: String,
-    pub descricao: String,
-    pub realizado_em: String,
-    pub user_id: i32,
-}
+use clap::Parser;
+use futures_util::future::join_all;
+use serde_json::json;
+use tracing::{error, info};
 
-#[derive(Serialize, Deserialize)]
-pub struct LastTransaction {
-    pub valor: i32,
-    pub tipo: String,
-    pub descricao: String,
-    pub realizado_em: String,
-}
+use alerts::Alerts;
+use audit::Audit;
+use cache::StatementCache;
+use cache_control::CachePolicy;
+use chaos::Chaos;
+use cli::{Cli, Command, Config};
+use clock::{Clock, ManualClock, SystemClock};
+use duplicates::DuplicateGuard;
+use feed::Feed;
+use handlers::{
+    create_transaction, deactivate_client, get_bank_statement, get_full_statement,
+    get_grouped_statement, list_clients, search_transactions, stream_alerts, stream_transactions,
+    update_client,
+};
+use idempotency::IdempotencyStore;
+use ids::{IdGenerator, IdStrategy};
+use lanes::Lanes;
+use maintenance::Maintenance;
+use metrics::{http::HttpMetrics, statsd::Statsd};
+use mirror::Mirror;
+use quotas::{QuotaGuard, QuotaLimits};
+use rust_lang::models::{self, Statement, User};
+use settings::{BoxError, Reloader};
+use stats::Stats;
+use storage::{Backend, Storage};
+use tenants::Tenants;
+use writer::WriteQueue;
 
-#[derive(Serialize, Deserialize)]
-pub struct Balance {
-    pub total: i32,
-    pub data_extrato: String,
-    pub limite: i32,
+#[derive(Clone)]
+pub struct AppState {
+    storage: Arc<dyn Storage>,
+    reloader: Arc<Reloader>,
+    stats: Arc<Stats>,
+    http_metrics: Arc<HttpMetrics>,
+    writes: Arc<WriteQueue>,
+    extratos: Arc<StatementCache>,
+    alerts: Arc<Alerts>,
+    feed: Arc<Feed>,
+    duplicates: Arc<DuplicateGuard>,
+    quotas: Arc<QuotaGuard>,
+    idempotency: Arc<IdempotencyStore>,
+    maintenance: Arc<Maintenance>,
+    audit: Arc<Audit>,
+    tenants: Arc<Tenants>,
+    /// Set by the [`tenants::Tenant`] extractor for requests addressed to one.
+    tenant: Option<String>,
+    extrato_max_quantidade: usize,
+    clock: Arc<dyn Clock>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct StatementResponse {
-    pub saldo: Balance,
-    pub ultimas_transacoes: Vec<LastTransaction>,
-}
+impl AppState {
+    fn new(
+        config: &Config,
+        storage: Arc<dyn Storage>,
+        reloader: Arc<Reloader>,
+        clock: Arc<dyn Clock>,
+        audit: Arc<Audit>,
+        ids: Arc<dyn IdGenerator>,
+    ) -> Self {
+        let feed = Arc::new(Feed::new());
+        AppState {
+            writes: Arc::new(WriteQueue::spawn(
+                storage.clone(),
+                config.write_queue_capacity,
+                config.write_queue_client_capacity,
+                clock.clone(),
+                ids,
+                feed.clone(),
+            )),
+            idempotency: Arc::new(IdempotencyStore::new(
+                &storage,
+                config.idempotency_cache_capacity,
+                config.idempotency_ttl_secs,
+            )),
+            storage,
+            reloader,
+            extratos: Arc::new(StatementCache::new(config.extrato_cache)),
+            alerts: Arc::new(Alerts::new()),
+            feed,
+            duplicates: Arc::new(DuplicateGuard::new(
+                config.duplicate_window_ms,
+                config.duplicate_mode,
+            )),
+            quotas: Arc::new(QuotaGuard::new(QuotaLimits::new(config))),
+            maintenance: Arc::new(Maintenance::new()),
+            audit,
+            tenants: Arc::new(Tenants::new(config, clock.clone())),
+            tenant: None,
+            extrato_max_quantidade: config.extrato_max_quantidade,
+            clock,
+            stats: Arc::new(Stats::new()),
+            http_metrics: Arc::new(HttpMetrics::new(
+                Duration::from_millis(config.slo_latency_ms),
+                config.slo_target,
+            )),
+        }
+    }
 
-#[derive(Serialize, Deserialize)]
-pub struct TransactionResponse {
-    pub limite: i32,
-    pub saldo: i32,
-}
+    /// Replica `index` of this state: another instance over the same backend,
+    /// with its own caches, queues and guards but the metrics, tenants and
+    /// audit trail of the process. Sequential ids come from one counter for
+    /// all of them, or each replica would carry on from the same stored one.
+    fn replica(&self, config: &Config, index: u16) -> Self {
+        let config = Config {
+            node_id: config.node_id + index,
+            ..config.clone()
+        };
+        let ids = match config.id_strategy {
+            IdStrategy::Sequential => self.writes.ids(),
+            strategy => ids::generator(strategy, config.node_id),
+        };
+        AppState {
+            stats: self.stats.clone(),
+            http_metrics: self.http_metrics.clone(),
+            tenants: self.tenants.clone(),
+            ..AppState::new(
+                &config,
+                self.storage.clone(),
+                self.reloader.clone(),
+                self.clock.clone(),
+                self.audit.clone(),
+                ids,
+            )
+        }
+    }
 
-#[derive(Serialize, Deserialize)]
-pub struct NewTransaction {
-    pub valor: i32,
-    pub tipo: String,
-    pub descricao: String,
-}
+    /// Whether no tenant, the default one included, has writes waiting or
+    /// being written.
+    fn writes_idle(&self) -> bool {
+        self.writes.idle() && self.tenants.writes_idle()
+    }
 
-#[derive(Clone)]
-pub struct User {
-    pub id: i32,
-    pub limite: i32,
-    pub saldo: i32,
+    /// Everything that follows a committed transaction, whoever submitted it.
+    /// The feed is not here: the writer publishes to it, in commit order.
+    fn committed(&self, user: &User, statement: &Statement) {
+        self.extratos.invalidate(user.id);
+        self.alerts.check(user, statement);
+        self.stats
+            .record_transaction(&statement.tipo, statement.valor);
+    }
 }
 
-enum StatementResult {
-    Success(Json<StatementResponse>),
-    NotFound,
-}
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
 
-impl IntoResponse for StatementResult {
-    fn into_response(self) -> axum::response::Response {
-        match self {
-            StatementResult::Success(json) => json.into_response(),
-            StatementResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+    let reloader = match settings::init_logging(cli.config.clone()) {
+        Ok(reloader) => Arc::new(reloader),
+        Err(err) => {
+            eprintln!("error: {err}");
+            process::exit(1);
         }
+    };
+
+    if let Err(err) = run(cli, reloader).await {
+        error!("{err}");
+        process::exit(1);
     }
 }
 
-enum TransactionResult {
-    Success(Json<TransactionResponse>),
-    NotFound,
-    UnprocessableEntity,
-}
+async fn run(cli: Cli, reloader: Arc<Reloader>) -> Result<(), BoxError> {
+    let command = cli.command.unwrap_or(Command::Serve);
+    if let Command::Verify { bundle } = &command {
+        let check = bundle::verify(bundle)?;
+        serde_json::to_writer_pretty(io::stdout(), &check)?;
+        println!();
+        if !check.integro {
+            return Err(format!("bundle {} failed verification", check.endereco).into());
+        }
+        return Ok(());
+    }
 
-impl IntoResponse for TransactionResult {
-    fn into_response(self) -> axum::response::Response<Body> {
-        match self {
-            TransactionResult::Success(json) => json.into_response(),
-            TransactionResult::NotFound => StatusCode::NOT_FOUND.into_response(),
-            TransactionResult::UnprocessableEntity => {
-                StatusCode::UNPROCESSABLE_ENTITY.into_response()
+    let storage = storage::open(&cli.config).await?;
+    match command {
+        Command::Serve => serve(cli.config, storage, reloader).await?,
+        Command::Seed { file } => {
+            let users =
+                settings::load_users(file.as_deref().or(cli.config.clients_file.as_deref()))?;
+            storage.seed(&users).await?;
+            info!("seeded {} clients", users.len());
+        }
+        Command::Migrate => {
+            storage.migrate().await?;
+            info!("schema is up to date");
+        }
+        Command::Dump { output } => {
+            let dump = serde_json::to_vec_pretty(&storage.dump().await?)?;
+            match output {
+                Some(path) => fs::write(path, dump)?,
+                None => io::stdout().write_all(&dump)?,
             }
         }
+        Command::Verify { .. } => unreachable!("verified before opening the backend"),
     }
+
+    Ok(())
 }
 
-type ArcState = Arc<Mutex<HashMap<i32, User>>>;
-type StatementState = Arc<Mutex<HashMap<i32, Statement>>>;
+async fn serve(
+    config: Config,
+    storage: Arc<dyn Storage>,
+    reloader: Arc<Reloader>,
+) -> Result<(), BoxError> {
+    if config.adaptive_concurrency && config.max_in_flight.is_none() {
+        return Err("--adaptive-concurrency needs --max-in-flight as its ceiling".into());
+    }
+    if config.replicas > 1 {
+        if config.handoff_socket.is_some() {
+            return Err("--handoff-socket takes over a single instance, not --replicas".into());
+        }
+        if connections::socket_activated() {
+            return Err(
+                "socket activation passes a single listener, not one per --replicas".into(),
+            );
+        }
+        if config.extrato_cache && !config.share_replica_state {
+            return Err(
+                "--extrato-cache needs --share-replica-state with --replicas, or each \
+                 replica would miss the others' transactions"
+                    .into(),
+            );
+        }
+        if u32::from(config.node_id) + u32::from(config.replicas) - 1 > u32::from(ids::MAX_NODE_ID)
+        {
+            return Err("--replicas runs past the last snowflake node id".into());
+        }
+    }
+    if config.auto_migrate {
+        storage.migrate().await?;
+    }
+    storage::ensure_schema(storage.as_ref()).await?;
 
-#[derive(Clone)]
-pub struct AppState {
-    user_state: ArcState,
-    statement_state: StatementState,
-}
+    let users = settings::load_users(config.clients_file.as_deref())?;
+    let seeded = storage.seed_missing(&users).await?;
+    let found: Vec<i32> = users
+        .iter()
+        .map(|u| u.id)
+        .filter(|id| !seeded.contains(id))
+        .collect();
+    info!(?seeded, ?found, "seeded clients missing from storage");
 
-impl AppState {
-    fn new() -> Self {
-        let mut hash_user: HashMap<i32, User> = HashMap::new();
-        let hash_statement: HashMap<i32, Statement> = HashMap::new();
-
-        hash_user.insert(
-            1,
-            User {
-                id: 1,
-                limite: 100000,
-                saldo: 0,
-            },
-        );
-        hash_user.insert(
-            2,
-            User {
-                id: 2,
-                limite: 80000,
-                saldo: 0,
-            },
-        );
-        hash_user.insert(
-            3,
-            User {
-                id: 3,
-                limite: 1000000,
-                saldo: 0,
-            },
-        );
-        hash_user.insert(
-            4,
-            User {
-                id: 4,
-                limite: 10000000,
-                saldo: 0,
-            },
-        );
-        hash_user.insert(
-            5,
-            User {
-                id: 5,
-                limite: 500000,
-                saldo: 0,
-            },
+    let manual_clock = config
+        .clock_start
+        .map(|start| Arc::new(ManualClock::new(start)));
+    let clock: Arc<dyn Clock> = match &manual_clock {
+        Some(clock) => clock.clone(),
+        None => Arc::new(SystemClock),
+    };
+
+    let audit = Arc::new(Audit::open(config.audit_file.as_deref(), clock.clone())?);
+    if !seeded.is_empty() {
+        audit.record("clientes_criados", None, json!({ "clientes": seeded }));
+    }
+
+    let ids = ids::generator(config.id_strategy, config.node_id);
+    let app_state: AppState = AppState::new(&config, storage, reloader, clock, audit, ids);
+    tokio::spawn(settings::reload_on_sighup(
+        app_state.reloader.clone(),
+        app_state.storage.clone(),
+        app_state.extratos.clone(),
+        app_state.audit.clone(),
+    ));
+    tokio::spawn(stats::track_rps(app_state.stats.clone()));
+    if let Some(statsd) = Statsd::new(&config) {
+        tokio::spawn(metrics::statsd::export(
+            statsd,
+            app_state.http_metrics.clone(),
+            app_state.stats.clone(),
+        ));
+    }
+    if let Some(rate) = config.interest_rate {
+        tokio::spawn(interest::accrue_interest(
+            app_state.clone(),
+            rate,
+            Duration::from_secs(config.interest_interval_secs.max(1)),
+        ));
+    }
+
+    if let Some(clock) = &manual_clock {
+        info!(
+            "clock starts at {} and only moves through /admin/clock",
+            clock.now()
         );
+    }
+    if let Some(base) = &config.mirror_url {
+        info!("mirroring {}% of requests to {base}", config.mirror_percent);
+    }
+    if let Some(limit) = config.max_in_flight {
+        if config.adaptive_concurrency {
+            info!(
+                "serving up to {limit} extratos and transactions at once, writes first, \
+                 fewer while p{} latency is over {}ms",
+                config.slo_target * 100.0,
+                config.slo_latency_ms
+            );
+        } else {
+            info!("serving at most {limit} extratos and transactions at once, writes first");
+        }
+    }
 
-        AppState {
-            user_state: Arc::new(Mutex::new(hash_user)),
-            statement_state: Arc::new(Mutex::new(hash_statement)),
+    // Shared state is one instance listening on every replica's port.
+    let instances: Vec<AppState> = if config.share_replica_state {
+        vec![app_state.clone()]
+    } else {
+        iter::once(app_state.clone())
+            .chain((1..config.replicas).map(|index| app_state.replica(&config, index)))
+            .collect()
+    };
+    let apps: Vec<Router> = instances
+        .iter()
+        .map(|state| router(&config, state, manual_clock.as_ref()))
+        .collect();
+
+    // Bound before taking over, so connections wait in the backlog while a
+    // predecessor drains instead of being refused.
+    let mut listeners = Vec::new();
+    for index in 0..config.replicas {
+        let port = config
+            .bind
+            .port()
+            .checked_add(index)
+            .ok_or("--replicas runs past the last port")?;
+        listeners.push(connections::bind(
+            SocketAddr::new(config.bind.ip(), port),
+            config.reuse_port || config.handoff_socket.is_some(),
+        )?);
+    }
+    let handoff = match &config.handoff_socket {
+        Some(path) => Some(handoff::take_over(path, &app_state).await?),
+        None => None,
+    };
+
+    if config.warmup {
+        for state in &instances {
+            let report = warmup::warm_up(state).await?;
+            info!(
+                clientes = report.clientes,
+                leituras = report.leituras,
+                "warmed up in {}ms",
+                report.duracao_ms
+            );
         }
     }
-}
 
-#[tokio::main]
-async fn main() {
-    let app_state: AppState = AppState::new();
+    if config.replicas > 1 {
+        info!(
+            "serving {} replicas on ports {} to {}",
+            config.replicas,
+            config.bind.port(),
+            config.bind.port() + config.replicas - 1
+        );
+        let replicas = listeners.into_iter().enumerate().map(|(index, listener)| {
+            let app = apps[index % apps.len()].clone();
+            connections::serve(listener, app, &config, std::future::pending::<()>())
+        });
+        join_all(replicas).await;
+        return Ok(());
+    }
 
-    let app = Router::new()
-        .route("/clientes/:id/transacoes", post(create_transaction))
-        .route("/clientes/:id/extrato", get(get_bank_statement))
-        .with_state(app_state.clone());
+    let mut listener = listeners.pop().expect("one listener per replica");
+    let app = apps.into_iter().next().expect("one router per instance");
+    loop {
+        let successor = async {
+            match &handoff {
+                Some(listener) => Some(handoff::successor(listener).await),
+                None => std::future::pending().await,
+            }
+        };
+        let (successor, drained) =
+            connections::serve(listener, app.clone(), &config, successor).await;
+        let Some(successor) = successor else {
+            return Ok(());
+        };
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+        let in_process = config.backend == Backend::Memory && config.shadow_backend.is_none();
+        let deadline = Instant::now() + Duration::from_secs(config.handoff_drain_secs);
+        if handoff::hand_over(successor, &app_state, in_process, drained, deadline).await? {
+            return Ok(());
+        }
+        // The successor gives up, so take connections again.
+        listener = connections::bind(config.bind, true)?;
+    }
 }
 
-async fn get_bank_statement(
-    State(state): State<AppState>,
-    Path(user_id): Path<i32>,
-) -> impl IntoResponse {
-    let users = state.user_state.lock().await;
-    let statements = state.statement_state.lock().await;
-
-    if let Some(user) = users.get(&user_id) {
-        let balance = Balance {
-            total: user.saldo,
-            data_extrato: Utc::now().to_rfc3339(),
-            limite: user.limite,
-        };
+/// The API as served by one replica.
+fn router(
+    config: &Config,
+    app_state: &AppState,
+    manual_clock: Option<&Arc<ManualClock>>,
+) -> Router {
+    let mut app = Router::new()
+        .route("/clientes", get(list_clients))
+        .route(
+            "/clientes/:id",
+            delete(deactivate_client).patch(update_client),
+        )
+        .route("/clientes/:id/alertas", get(stream_alerts))
+        .route("/transacoes/eventos", get(stream_transactions))
+        .route(
+            "/clientes/:id/transacoes",
+            get(search_transactions).post(create_transaction),
+        )
+        .route("/clientes/:id/extrato", get(get_bank_statement))
+        .route("/clientes/:id/extrato/completo", get(get_full_statement))
+        .route("/clientes/:id/extrato/agrupado", get(get_grouped_statement))
+        .route("/admin/reload", post(admin::reload))
+        .route("/admin/audit", get(audit::trail))
+        .route("/admin/stats", get(admin::stats))
+        .route("/admin/ui", get(admin::dashboard))
+        .route("/admin/warmup", post(admin::warmup))
+        .route(
+            "/admin/maintenance",
+            get(maintenance::status).post(maintenance::toggle),
+        )
+        .route("/admin/tenants", get(admin::list_tenants))
+        .route(
+            "/admin/tenants/:tenant",
+            put(admin::reset_tenant).delete(admin::delete_tenant),
+        )
+        .route("/metrics", get(admin::metrics))
+        .route("/admin/reconciliacao", get(admin::reconciliation))
+        .route("/admin/clientes/:id/importar", post(admin::import_history))
+        .route("/admin/clientes/:id/exportar", get(bundle::export))
+        .route(
+            "/admin/reconciliacao/:id",
+            get(admin::client_reconciliation),
+        );
 
-        let mut last_transactions: Vec<LastTransaction> = Vec::new();
+    if let Some(clock) = manual_clock {
+        app = app.route(
+            "/admin/clock",
+            get(clock::current)
+                .put(clock::set)
+                .with_state((clock.clone(), app_state.audit.clone())),
+        );
+    }
 
-        for (_, statement) in statements.iter().filter(|(_, s)| s.user_id == user_id) {
-            if last_transactions.len() >= 10 {
-                break;
-            }
+    if config.chaos {
+        let chaos = Arc::new(Chaos::new(config));
+        app = app
+            .route(
+                "/admin/chaos",
+                get(chaos::settings)
+                    .put(chaos::configure)
+                    .with_state((chaos.clone(), app_state.audit.clone())),
+            )
+            .layer(middleware::from_fn_with_state(chaos, chaos::inject));
+    }
 
-            last_transactions.push(LastTransaction {
-                valor: statement.valor,
-                tipo: statement.tipo.to_string(),
-                descricao: statement.descricao.clone(),
-                realizado_em: statement.realizado_em.clone(),
-            })
-        }
+    app = app.layer(middleware::from_fn(deadline::enforce_deadline));
 
-        StatementResult::Success(Json(StatementResponse {
-            saldo: balance,
-            ultimas_transacoes: last_transactions,
-        }))
-    } else {
-        StatementResult::NotFound
+    if let Some(base) = &config.mirror_url {
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::new(Mirror::new(base, config.mirror_percent)),
+            mirror::mirror_requests,
+        ));
     }
-}
 
-async fn create_transaction(
-    State(state): State<AppState>,
-    Path(user_id): Path<i32>,
-    Json(new_statement): Json<NewTransaction>,
-) -> impl IntoResponse {
-    let mut users = state.user_state.lock().await;
-    let mut statements = state.statement_state.lock().await;
-
-    if let Some(user) = users.get_mut(&user_id) {
-        let new_balance = match new_statement.tipo.as_str() {
-            "c" => {
-                let balance = user.saldo + new_statement.valor;
-                user.saldo = balance;
-
-                let hack_id: i32 = (statements.len() + 1) as i32;
-
-                statements.insert(
-                    hack_id,
-                    Statement {
-                        id: hack_id,
-                        valor: new_statement.valor,
-                        tipo: new_statement.tipo,
-                        descricao: new_statement.descricao,
-                        realizado_em: Utc::now().to_rfc3339(),
-                        user_id: user_id,
-                    },
-                );
-
-                TransactionResult::Success(Json(TransactionResponse {
-                    limite: user.limite,
-                    saldo: balance,
-                }))
-            }
-            "d" => {
-                let new_balance = user.saldo - new_statement.valor;
-                if new_balance < -user.limite {
-                    return TransactionResult::UnprocessableEntity;
-                } else {
-                    user.saldo = new_balance;
-                    TransactionResult::Success(Json(TransactionResponse {
-                        limite: user.limite,
-                        saldo: new_balance,
-                    }))
-                }
-            }
-            _ => TransactionResult::UnprocessableEntity,
-        };
-        new_balance
-    } else {
-        TransactionResult::NotFound
+    if let Some(limit) = config.max_in_flight {
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::new(Lanes::new(config, limit)),
+            lanes::admit,
+        ));
+    }
+
+    app = app.layer(middleware::from_fn_with_state(
+        config.admin_token.as_deref().map(Arc::<str>::from),
+        auth::require_admin,
+    ));
+
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            config.field_names,
+            fields::rename_fields,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.maintenance.clone(),
+            maintenance::reject_writes,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(CachePolicy::new(config)),
+            cache_control::apply,
+        ))
+        .layer(middleware::from_fn(deadline::read_deadline))
+        .layer(middleware::from_fn_with_state(
+            app_state.http_metrics.clone(),
+            metrics::http::track_latency,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.stats.clone(),
+            stats::count_requests,
+        ))
+        .with_state(app_state.clone());
+
+    match &config.base_path {
+        Some(base) => base_path::mount(base, app),
+        None => app,
     }
 }
@@ -0,0 +1,167 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    i18n::{Lang, Message},
+    metrics, AppState,
+};
+
+const ACTIVE: &str = "maintenance_mode";
+
+/// How long switching on waits for queued writes to land.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_retry_after_secs() -> u64 {
+    30
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceRequest {
+    pub ativo: bool,
+    /// Sent as `Retry-After` with every rejected write.
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct MaintenanceStatus {
+    pub ativo: bool,
+    pub desde: Option<DateTime<Utc>>,
+    pub retry_after_secs: u64,
+    /// Whether every queued write had landed when maintenance began.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub escritas_concluidas: Option<bool>,
+}
+
+/// Read-only mode: while on, requests that would change a client are turned
+/// away with a 503 and reads go on as usual. Admin routes other than the
+/// import stay open, so it can be switched off again.
+pub struct Maintenance {
+    status: RwLock<MaintenanceStatus>,
+}
+
+impl Maintenance {
+    pub fn new() -> Self {
+        metrics::gauge(ACTIVE, Vec::new()).set(0.0);
+        Maintenance {
+            status: RwLock::new(MaintenanceStatus {
+                ativo: false,
+                desde: None,
+                retry_after_secs: default_retry_after_secs(),
+                escritas_concluidas: None,
+            }),
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.status.read().unwrap().ativo
+    }
+
+    fn status(&self) -> MaintenanceStatus {
+        self.status.read().unwrap().clone()
+    }
+}
+
+fn changes_clients(request: &Request) -> bool {
+    let path = request.uri().path();
+    !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) && (path.starts_with("/clientes") || path.starts_with("/admin/clientes"))
+}
+
+pub async fn reject_writes(
+    State(maintenance): State<Arc<Maintenance>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !changes_clients(&request) {
+        return next.run(request).await;
+    }
+
+    let status = maintenance.status();
+    if !status.ativo {
+        return next.run(request).await;
+    }
+
+    let lang = Lang::from_headers(request.headers());
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, status.retry_after_secs.to_string())],
+        Message::new("em_manutencao").localize(lang),
+    )
+        .into_response()
+}
+
+pub async fn status(State(state): State<AppState>) -> Json<MaintenanceStatus> {
+    Json(state.maintenance.status())
+}
+
+/// Switching on answers once the writes already queued for every tenant have
+/// landed, or the drain timed out, so a snapshot taken after it sees all of
+/// them.
+pub async fn toggle(
+    State(state): State<AppState>,
+    Json(request): Json<MaintenanceRequest>,
+) -> Json<MaintenanceStatus> {
+    let desde = {
+        let mut status = state.maintenance.status.write().unwrap();
+        if request.ativo != status.ativo {
+            status.desde = request.ativo.then(|| state.clock.now());
+        }
+        status.ativo = request.ativo;
+        status.retry_after_secs = request.retry_after_secs;
+        status.escritas_concluidas = None;
+        status.desde
+    };
+    metrics::gauge(ACTIVE, Vec::new()).set(if request.ativo { 1.0 } else { 0.0 });
+
+    let mut status = state.maintenance.status();
+    if request.ativo {
+        let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+            while !state.writes_idle() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .is_ok();
+        if !drained {
+            warn!("maintenance began with writes still queued");
+        }
+
+        status = {
+            let mut current = state.maintenance.status.write().unwrap();
+            // Unless it was switched off, or on again, while draining.
+            if current.ativo && current.desde == desde {
+                current.escritas_concluidas = Some(drained);
+            }
+            current.clone()
+        };
+        info!(
+            retry_after_secs = request.retry_after_secs,
+            "maintenance mode on"
+        );
+    } else {
+        info!("maintenance mode off");
+    }
+    state.audit.record(
+        "manutencao",
+        None,
+        serde_json::to_value(&status).unwrap_or_default(),
+    );
+
+    Json(status)
+}
@@ -0,0 +1,168 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+const DURATION: &str = "http_request_duration_seconds";
+const SLOW: &str = "http_requests_slow_total";
+const QUANTILE: &str = "http_request_duration_quantile_seconds";
+const BURN_RATE: &str = "http_slo_burn_rate";
+
+const QUANTILES: &[(f64, &str)] = &[(0.5, "0.5"), (0.95, "0.95"), (0.99, "0.99")];
+
+/// How far back the burn rate looks: the short window of a burn-rate alert.
+const BURN_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// A route's request and slow request counts at one refresh.
+#[derive(Clone, Copy)]
+struct Sample {
+    at: Instant,
+    total: u64,
+    slow: u64,
+}
+
+/// Parses `--slo-target`, a fraction strictly between 0 and 1: all of the
+/// requests leaves no error budget to burn, and none is no objective.
+pub fn parse_target(value: &str) -> Result<f64, String> {
+    let target: f64 = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid fraction `{value}`"))?;
+    if !(target > 0.0 && target < 1.0) {
+        return Err(format!("the target must be between 0 and 1, got `{value}`"));
+    }
+    Ok(target)
+}
+
+/// Latency SLO: at least `target` of the requests of each route must finish
+/// within `threshold`.
+pub struct HttpMetrics {
+    threshold: Duration,
+    target: f64,
+    started: Instant,
+    /// Per route, the refreshes of the last [`BURN_WINDOW`] and the newest
+    /// one before it.
+    samples: Mutex<HashMap<super::Labels, VecDeque<Sample>>>,
+}
+
+impl HttpMetrics {
+    pub fn new(threshold: Duration, target: f64) -> Self {
+        HttpMetrics {
+            threshold,
+            target,
+            started: Instant::now(),
+            samples: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The share of slow requests over the last [`BURN_WINDOW`], or since
+    /// startup when it has not been that long, over the share allowed.
+    fn burn_rate(&self, labels: &super::Labels, now: Sample) -> f64 {
+        let mut samples = self.samples.lock().unwrap();
+        let samples = samples.entry(labels.clone()).or_insert_with(|| {
+            // Routes show up with their first request.
+            VecDeque::from([Sample {
+                at: self.started,
+                total: 0,
+                slow: 0,
+            }])
+        });
+        samples.push_back(now);
+        let since = now.at.checked_sub(BURN_WINDOW);
+        while samples.len() > 1 && since.is_some_and(|since| samples[1].at <= since) {
+            samples.pop_front();
+        }
+
+        // Refreshes racing each other may push their samples out of order.
+        let before = samples[0];
+        let total = now.total.saturating_sub(before.total);
+        if total == 0 {
+            return 0.0;
+        }
+        let bad_ratio = now.slow.saturating_sub(before.slow) as f64 / total as f64;
+        bad_ratio / (1.0 - self.target)
+    }
+
+    /// Recomputes the percentile and burn-rate gauges from the raw histograms;
+    /// called right before the registry is exported.
+    pub fn refresh(&self) {
+        let at = Instant::now();
+        for (labels, histogram) in super::histograms(DURATION) {
+            for (q, name) in QUANTILES {
+                let mut quantile_labels = labels.clone();
+                quantile_labels.push(("quantile", (*name).to_owned()));
+                super::gauge(QUANTILE, quantile_labels).set(histogram.quantile(*q));
+            }
+
+            let now = Sample {
+                at,
+                total: histogram.count(),
+                slow: super::counter(SLOW, labels.clone()).get(),
+            };
+            let burn_rate = self.burn_rate(&labels, now);
+            super::gauge(BURN_RATE, labels).set(burn_rate);
+        }
+    }
+}
+
+pub async fn track_latency(
+    State(http): State<Arc<HttpMetrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().as_str().to_owned();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_owned(), |path| path.as_str().to_owned());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = started.elapsed();
+
+    let labels = vec![("method", method), ("route", route)];
+    if elapsed > http.threshold {
+        super::counter(SLOW, labels.clone()).inc();
+    }
+    super::histogram(DURATION, labels).observe(elapsed);
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_burn_rate_only_counts_the_last_window() {
+        let http = HttpMetrics::new(Duration::from_millis(10), 0.75);
+        let labels = vec![("route", "/clientes/:id/extrato".to_owned())];
+        let sample = |minutes: u64, total, slow| Sample {
+            at: http.started + Duration::from_secs(minutes * 60),
+            total,
+            slow,
+        };
+
+        assert_eq!(http.burn_rate(&labels, sample(1, 100, 25)), 1.0);
+        // Measured from minute 1, the newest sample at or before the window.
+        assert_eq!(http.burn_rate(&labels, sample(10, 200, 25)), 0.0);
+        assert_eq!(http.burn_rate(&labels, sample(11, 300, 125)), 2.0);
+        // And from minute 11 now, with nothing since.
+        assert_eq!(http.burn_rate(&labels, sample(16, 300, 125)), 0.0);
+    }
+
+    #[test]
+    fn targets_lie_strictly_between_0_and_1() {
+        assert_eq!(parse_target("0.99"), Ok(0.99));
+        for value in ["0", "1", "1.5", "-0.1", "NaN", "inf", ""] {
+            assert!(parse_target(value).is_err(), "{value:?}");
+        }
+    }
+}
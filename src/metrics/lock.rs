@@ -0,0 +1,42 @@
+use std::{sync::Arc, time::Instant};
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::Histogram;
+
+const LOCK_WAIT: &str = "storage_lock_wait_seconds";
+
+/// A tokio `RwLock` that records how long each `read()` and `write()` waited,
+/// labelled by `name` and `mode`. Uncontended acquisitions are recorded too,
+/// so the histogram count is the number of acquisitions.
+pub struct InstrumentedRwLock<T> {
+    inner: RwLock<T>,
+    read_wait: Arc<Histogram>,
+    write_wait: Arc<Histogram>,
+}
+
+impl<T> InstrumentedRwLock<T> {
+    pub fn new(name: &str, value: T) -> Self {
+        let labels = |mode: &str| vec![("lock", name.to_owned()), ("mode", mode.to_owned())];
+
+        InstrumentedRwLock {
+            inner: RwLock::new(value),
+            read_wait: super::histogram(LOCK_WAIT, labels("read")),
+            write_wait: super::histogram(LOCK_WAIT, labels("write")),
+        }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let started = Instant::now();
+        let guard = self.inner.read().await;
+        self.read_wait.observe(started.elapsed());
+        guard
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let started = Instant::now();
+        let guard = self.inner.write().await;
+        self.write_wait.observe(started.elapsed());
+        guard
+    }
+}
@@ -0,0 +1,284 @@
+pub mod http;
+pub mod lock;
+pub mod statsd;
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, RwLock,
+    },
+    time::Duration,
+};
+
+/// Bucket upper bounds, in seconds, shared by every latency histogram.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+pub type Labels = Vec<(&'static str, String)>;
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    name: &'static str,
+    labels: Labels,
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// An `f64` stored as bits so it can be shared without a lock.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let index = self
+            .bounds
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(self.bounds.len());
+
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    }
+
+    /// Estimates the `q` quantile by interpolating inside the bucket that holds
+    /// it. Values past the last bound are reported as that bound.
+    pub fn quantile(&self, q: f64) -> f64 {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0.0;
+        }
+
+        let rank = q * total as f64;
+        let mut seen = 0u64;
+
+        for (index, count) in counts.iter().enumerate() {
+            if *count > 0 && (seen + count) as f64 >= rank {
+                let Some(upper) = self.bounds.get(index) else {
+                    break;
+                };
+                let lower = if index == 0 {
+                    0.0
+                } else {
+                    self.bounds[index - 1]
+                };
+                return lower + (upper - lower) * ((rank - seen as f64) / *count as f64);
+            }
+            seen += count;
+        }
+
+        self.bounds.last().copied().unwrap_or(0.0)
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: RwLock<BTreeMap<Key, Arc<Counter>>>,
+    gauges: RwLock<BTreeMap<Key, Arc<Gauge>>>,
+    histograms: RwLock<BTreeMap<Key, Arc<Histogram>>>,
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+fn get_or_insert<T>(
+    map: &RwLock<BTreeMap<Key, Arc<T>>>,
+    key: Key,
+    create: impl FnOnce() -> T,
+) -> Arc<T> {
+    if let Some(found) = map.read().unwrap().get(&key) {
+        return found.clone();
+    }
+
+    map.write()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| Arc::new(create()))
+        .clone()
+}
+
+pub fn counter(name: &'static str, labels: Labels) -> Arc<Counter> {
+    get_or_insert(&REGISTRY.counters, Key { name, labels }, Counter::default)
+}
+
+pub fn gauge(name: &'static str, labels: Labels) -> Arc<Gauge> {
+    get_or_insert(&REGISTRY.gauges, Key { name, labels }, Gauge::default)
+}
+
+pub fn histogram(name: &'static str, labels: Labels) -> Arc<Histogram> {
+    get_or_insert(&REGISTRY.histograms, Key { name, labels }, || {
+        Histogram::new(LATENCY_BUCKETS)
+    })
+}
+
+/// Every histogram registered under `name`, with its labels.
+pub fn histograms(name: &'static str) -> Vec<(Labels, Arc<Histogram>)> {
+    REGISTRY
+        .histograms
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(key, _)| key.name == name)
+        .map(|(key, histogram)| (key.labels.clone(), histogram.clone()))
+        .collect()
+}
+
+pub enum Value {
+    Counter(u64),
+    Gauge(f64),
+    Histogram(Arc<Histogram>),
+}
+
+/// Everything currently registered, for exporters other than Prometheus.
+pub fn samples() -> Vec<(&'static str, Labels, Value)> {
+    let counters = REGISTRY.counters.read().unwrap();
+    let gauges = REGISTRY.gauges.read().unwrap();
+    let histograms = REGISTRY.histograms.read().unwrap();
+
+    let counters = counters
+        .iter()
+        .map(|(key, counter)| (key, Value::Counter(counter.get())));
+    let gauges = gauges
+        .iter()
+        .map(|(key, gauge)| (key, Value::Gauge(gauge.get())));
+    let histograms = histograms
+        .iter()
+        .map(|(key, histogram)| (key, Value::Histogram(histogram.clone())));
+
+    counters
+        .chain(gauges)
+        .chain(histograms)
+        .map(|(key, value)| (key.name, key.labels.clone(), value))
+        .collect()
+}
+
+fn write_labels(out: &mut String, labels: &Labels, extra: Option<(&str, &str)>) {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(extra)
+        .map(|(name, value)| {
+            let escaped = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{escaped}\"")
+        })
+        .collect();
+
+    if !pairs.is_empty() {
+        let _ = write!(out, "{{{}}}", pairs.join(","));
+    }
+}
+
+fn write_type(out: &mut String, last: &mut Option<&'static str>, name: &'static str, kind: &str) {
+    if *last != Some(name) {
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        *last = Some(name);
+    }
+}
+
+/// Renders the whole registry in the Prometheus text exposition format.
+pub fn render_prometheus() -> String {
+    let mut out = String::new();
+    let mut last = None;
+
+    for (key, counter) in REGISTRY.counters.read().unwrap().iter() {
+        write_type(&mut out, &mut last, key.name, "counter");
+        out.push_str(key.name);
+        write_labels(&mut out, &key.labels, None);
+        let _ = writeln!(out, " {}", counter.get());
+    }
+
+    for (key, gauge) in REGISTRY.gauges.read().unwrap().iter() {
+        write_type(&mut out, &mut last, key.name, "gauge");
+        out.push_str(key.name);
+        write_labels(&mut out, &key.labels, None);
+        let _ = writeln!(out, " {}", gauge.get());
+    }
+
+    for (key, histogram) in REGISTRY.histograms.read().unwrap().iter() {
+        write_type(&mut out, &mut last, key.name, "histogram");
+
+        let mut cumulative = 0;
+        for (index, bucket) in histogram.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = histogram
+                .bounds
+                .get(index)
+                .map_or_else(|| "+Inf".to_owned(), |bound| bound.to_string());
+
+            let _ = write!(out, "{}_bucket", key.name);
+            write_labels(&mut out, &key.labels, Some(("le", &le)));
+            let _ = writeln!(out, " {cumulative}");
+        }
+
+        let _ = write!(out, "{}_sum", key.name);
+        write_labels(&mut out, &key.labels, None);
+        let _ = writeln!(out, " {}", histogram.sum());
+
+        let _ = write!(out, "{}_count", key.name);
+        write_labels(&mut out, &key.labels, None);
+        let _ = writeln!(out, " {}", histogram.count());
+    }
+
+    out
+}
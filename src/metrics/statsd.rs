@@ -0,0 +1,211 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use clap::ValueEnum;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::{cli::Config, stats::Stats};
+
+use super::{http::HttpMetrics, Labels, Value};
+
+/// Keeps each datagram inside a typical MTU so nothing gets fragmented.
+const MAX_DATAGRAM: usize = 1432;
+
+const QUANTILES: &[(f64, &str)] = &[(0.5, "0.5"), (0.95, "0.95"), (0.99, "0.99")];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Flavor {
+    /// Plain statsd; labels are appended to the metric name
+    Statsd,
+    /// Datadog's dialect; labels and --statsd-tags are sent as tags
+    Dogstatsd,
+}
+
+/// Pushes the same registry `/metrics` exposes to a statsd agent over UDP.
+/// Counters are sent as the increase since the previous flush, gauges as
+/// they are, and histograms as their count plus a gauge per quantile.
+pub struct Statsd {
+    addr: String,
+    flavor: Flavor,
+    prefix: String,
+    tags: Vec<String>,
+    interval: Duration,
+    sent: HashMap<String, u64>,
+}
+
+fn sanitize(value: &str, keep: &[char]) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || keep.contains(&c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+impl Statsd {
+    /// `None` unless `--statsd-addr` is set.
+    pub fn new(config: &Config) -> Option<Self> {
+        let addr = config.statsd_addr.clone()?;
+        let prefix = config.statsd_prefix.trim_end_matches('.');
+
+        Some(Statsd {
+            addr,
+            flavor: config.statsd_flavor,
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{prefix}.")
+            },
+            tags: config.statsd_tags.clone(),
+            interval: Duration::from_secs(config.statsd_interval_secs.max(1)),
+            sent: HashMap::new(),
+        })
+    }
+
+    /// Metric name and the trailing tag section, e.g. `|#route:/clientes`.
+    fn identify(&self, name: &str, labels: &Labels) -> (String, String) {
+        match self.flavor {
+            Flavor::Statsd => {
+                let mut metric = format!("{}{name}", self.prefix);
+                for (_, value) in labels {
+                    metric.push('.');
+                    metric.push_str(&sanitize(value, &['-']));
+                }
+                (metric, String::new())
+            }
+            Flavor::Dogstatsd => {
+                let tags: Vec<String> = self
+                    .tags
+                    .iter()
+                    .cloned()
+                    .chain(
+                        labels
+                            .iter()
+                            .map(|(k, v)| format!("{k}:{}", sanitize(v, &['-', '/', '.', ':']))),
+                    )
+                    .collect();
+                let tags = if tags.is_empty() {
+                    String::new()
+                } else {
+                    format!("|#{}", tags.join(","))
+                };
+                (format!("{}{name}", self.prefix), tags)
+            }
+        }
+    }
+
+    fn gauge(&self, lines: &mut Vec<String>, name: &str, labels: &Labels, value: f64) {
+        let (metric, tags) = self.identify(name, labels);
+        lines.push(format!("{metric}:{value}|g{tags}"));
+    }
+
+    fn counter(&mut self, lines: &mut Vec<String>, name: &str, labels: &Labels, value: u64) {
+        let (metric, tags) = self.identify(name, labels);
+        let previous = self
+            .sent
+            .insert(format!("{metric}{tags}"), value)
+            .unwrap_or(0);
+        // A counter that went backwards was reset; count it from zero.
+        let delta = value.checked_sub(previous).unwrap_or(value);
+        if delta > 0 {
+            lines.push(format!("{metric}:{delta}|c{tags}"));
+        }
+    }
+
+    fn collect(&mut self, stats: &Stats) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for (name, labels, value) in super::samples() {
+            match value {
+                Value::Counter(value) => self.counter(&mut lines, name, &labels, value),
+                Value::Gauge(value) => self.gauge(&mut lines, name, &labels, value),
+                Value::Histogram(histogram) => {
+                    self.counter(
+                        &mut lines,
+                        &format!("{name}_count"),
+                        &labels,
+                        histogram.count(),
+                    );
+                    for (q, quantile) in QUANTILES {
+                        let mut quantile_labels = labels.clone();
+                        quantile_labels.push(("quantile", (*quantile).to_owned()));
+                        self.gauge(&mut lines, name, &quantile_labels, histogram.quantile(*q));
+                    }
+                }
+            }
+        }
+
+        for (name, kind, value) in stats.snapshot().metrics() {
+            if kind == "counter" {
+                self.counter(&mut lines, name, &Vec::new(), value as u64);
+            } else {
+                self.gauge(&mut lines, name, &Vec::new(), value);
+            }
+        }
+
+        lines
+    }
+}
+
+/// Packs lines into as few datagrams as fit.
+fn datagrams(lines: Vec<String>) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(current) if current.len() + 1 + line.len() <= MAX_DATAGRAM => {
+                current.push('\n');
+                current.push_str(&line);
+            }
+            _ => datagrams.push(line),
+        }
+    }
+
+    datagrams
+}
+
+async fn connect(addr: &str) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+pub async fn export(mut statsd: Statsd, http: Arc<HttpMetrics>, stats: Arc<Stats>) {
+    info!(
+        "exporting metrics to {} every {}s",
+        statsd.addr,
+        statsd.interval.as_secs()
+    );
+
+    let mut interval = tokio::time::interval(statsd.interval);
+    let mut socket = None;
+
+    loop {
+        interval.tick().await;
+
+        // The agent may not resolve yet at startup, so keep trying.
+        if socket.is_none() {
+            match connect(&statsd.addr).await {
+                Ok(connected) => socket = Some(connected),
+                Err(err) => {
+                    warn!("failed to reach statsd at {}: {err}", statsd.addr);
+                    continue;
+                }
+            }
+        }
+        let Some(udp) = &socket else { continue };
+
+        http.refresh();
+        for datagram in datagrams(statsd.collect(&stats)) {
+            if let Err(err) = udp.send(datagram.as_bytes()).await {
+                // Nobody listening right now; the next flush tries again.
+                debug!("failed to send metrics to statsd: {err}");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use axum::{
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{MatchedPath, Request, State},
+    http::{header, request::Parts, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::{BodyExt, Full};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde_json::Value;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::metrics;
+
+const MIRRORED: &str = "mirror_requests_total";
+const MISMATCHES: &str = "mirror_mismatches_total";
+const ERRORS: &str = "mirror_errors_total";
+
+/// Same cap axum's `Json` extractor applies, so nothing it would have
+/// accepted gets refused here.
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+/// Fields that legitimately differ between two instances and are left out
+/// when bodies are compared.
+const VOLATILE: &[&str] = &["data_extrato", "realizado_em", "id"];
+
+type CandidateResponse = Result<(StatusCode, Bytes), String>;
+
+/// Sends a share of the client API requests to a candidate build as well and
+/// reports where its answers differ. Callers always get this instance's
+/// response; the candidate's is only compared, after the fact.
+pub struct Mirror {
+    client: Client<HttpConnector, Full<Bytes>>,
+    base: String,
+    share: f64,
+    seen: AtomicU64,
+}
+
+impl Mirror {
+    /// Mirrors `percent` of the requests to `base`, e.g. `http://10.0.0.2:3000`.
+    pub fn new(base: &str, percent: u8) -> Self {
+        Mirror {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            base: base.trim_end_matches('/').to_owned(),
+            share: f64::from(percent.min(100)) / 100.0,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    /// Picks requests evenly rather than at random: the n-th one is mirrored
+    /// when it pushes `n * share` past a whole number.
+    fn sampled(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.share).floor() > (n * self.share).floor()
+    }
+
+    fn forward(&self, parts: &Parts, body: Bytes) -> JoinHandle<CandidateResponse> {
+        let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+        let uri = format!("{}{path}", self.base).parse::<Uri>();
+
+        let mut request = hyper::Request::new(Full::new(body));
+        *request.method_mut() = parts.method.clone();
+        *request.headers_mut() = parts.headers.clone();
+        request.headers_mut().remove(header::HOST);
+
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            *request.uri_mut() = uri.map_err(|err| err.to_string())?;
+
+            let response = client
+                .request(request)
+                .await
+                .map_err(|err| err.to_string())?;
+            let status = response.status();
+            let body = response
+                .into_body()
+                .collect()
+                .await
+                .map_err(|err| err.to_string())?
+                .to_bytes();
+
+            Ok((status, body))
+        })
+    }
+}
+
+fn strip_volatile(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            fields.retain(|key, _| !VOLATILE.contains(&key.as_str()));
+            fields.values_mut().for_each(strip_volatile);
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_volatile),
+        _ => {}
+    }
+}
+
+fn same_body(ours: &[u8], theirs: &[u8]) -> bool {
+    match (
+        serde_json::from_slice::<Value>(ours),
+        serde_json::from_slice::<Value>(theirs),
+    ) {
+        (Ok(mut ours), Ok(mut theirs)) => {
+            strip_volatile(&mut ours);
+            strip_volatile(&mut theirs);
+            ours == theirs
+        }
+        _ => ours == theirs,
+    }
+}
+
+async fn compare(
+    route: String,
+    status: StatusCode,
+    body: Bytes,
+    candidate: JoinHandle<CandidateResponse>,
+) {
+    let labels = vec![("route", route.clone())];
+    metrics::counter(MIRRORED, labels.clone()).inc();
+
+    let (their_status, their_body) = match candidate.await {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => {
+            metrics::counter(ERRORS, labels).inc();
+            debug!(route, "mirrored request failed: {err}");
+            return;
+        }
+        Err(_) => return,
+    };
+
+    let kind = if their_status != status {
+        "status"
+    } else if !same_body(&body, &their_body) {
+        "body"
+    } else {
+        return;
+    };
+
+    let mut mismatch_labels = labels;
+    mismatch_labels.push(("kind", kind.to_owned()));
+    metrics::counter(MISMATCHES, mismatch_labels).inc();
+    warn!(
+        route,
+        status = status.as_u16(),
+        candidate_status = their_status.as_u16(),
+        "candidate answered differently ({kind})"
+    );
+}
+
+pub async fn mirror_requests(
+    State(mirror): State<Arc<Mirror>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned());
+
+    let route = match route {
+        Some(route) if route.starts_with("/clientes") && mirror.sampled() => route,
+        _ => return next.run(request).await,
+    };
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_BODY).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+
+    let candidate = mirror.forward(&parts, body.clone());
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    // Streamed bodies, such as the event feeds and the complete extrato, are
+    // not held whole to compare them.
+    if response.body().size_hint().exact().is_none() {
+        candidate.abort();
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => {
+            warn!(route, "failed to buffer response for mirroring: {err}");
+            candidate.abort();
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    tokio::spawn(compare(route, parts.status, body.clone(), candidate));
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirrored(percent: u8, requests: usize) -> usize {
+        let mirror = Mirror::new("http://127.0.0.1:9", percent);
+        (0..requests).filter(|_| mirror.sampled()).count()
+    }
+
+    #[test]
+    fn the_share_mirrored_is_spread_evenly() {
+        assert_eq!(mirrored(0, 1000), 0);
+        assert_eq!(mirrored(10, 1000), 100);
+        assert_eq!(mirrored(10, 9), 0);
+        assert_eq!(mirrored(33, 100), 33);
+        assert_eq!(mirrored(100, 1000), 1000);
+    }
+}
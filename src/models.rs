@@ -0,0 +1,360 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Statement {
+    pub id: i32,
+    /// Public identifier; `id` is internal and only orders a client's history.
+    /// Statements stored before it existed read back as the nil UUID.
+    #[serde(default)]
+    pub uuid: Uuid,
+    /// Position in the client's history, starting at 1 with no gaps.
+    #[serde(default)]
+    pub sequencia: i64,
+    pub valor: i32,
+    pub tipo: String,
+    pub descricao: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub categoria: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub realizado_em: DateTime<Utc>,
+    pub user_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LastTransaction {
+    pub valor: i32,
+    pub tipo: String,
+    pub descricao: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub categoria: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub realizado_em: DateTime<Utc>,
+    pub id: Uuid,
+    pub sequencia: i64,
+}
+
+impl From<Statement> for LastTransaction {
+    fn from(statement: Statement) -> Self {
+        LastTransaction {
+            valor: statement.valor,
+            tipo: statement.tipo,
+            descricao: statement.descricao,
+            categoria: statement.categoria,
+            tags: statement.tags,
+            realizado_em: statement.realizado_em,
+            id: statement.uuid,
+            sequencia: statement.sequencia,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Balance {
+    pub total: i32,
+    pub data_extrato: DateTime<Utc>,
+    pub limite: i32,
+    /// Whether the client has used at least its `alerta_percentual` of limite.
+    pub alerta: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatementResponse {
+    pub saldo: Balance,
+    pub ultimas_transacoes: Vec<LastTransaction>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionResponse {
+    pub limite: i32,
+    pub saldo: i32,
+    pub id: Uuid,
+    pub sequencia: i64,
+}
+
+/// What a transaction would leave the client with; nothing was recorded.
+#[derive(Serialize)]
+pub struct SimulationResponse {
+    pub limite: i32,
+    pub saldo: i32,
+    pub simulada: bool,
+}
+
+#[derive(Deserialize)]
+pub struct TransactionQuery {
+    /// Validate and report the resulting balance without committing.
+    #[serde(default)]
+    pub simular: bool,
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct NewTransaction {
+    pub valor: i32,
+    pub tipo: String,
+    pub descricao: String,
+    /// What the money was for, e.g. `mercado`, to budget by.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub categoria: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct User {
+    pub id: i32,
+    pub limite: i32,
+    pub saldo: i32,
+    #[serde(default = "active")]
+    pub ativo: bool,
+    /// `sequencia` of the client's latest transaction. Seeding never lowers
+    /// it, so a re-seed can't hand out a number twice.
+    #[serde(default)]
+    pub ultima_sequencia: i64,
+    /// Share of limite, in percent, past which an alert goes out. Seeding
+    /// without one keeps whatever was configured.
+    #[serde(default)]
+    pub alerta_percentual: Option<i32>,
+}
+
+fn active() -> bool {
+    true
+}
+
+/// Changes to a client; fields left out stay as they are.
+#[derive(Serialize, Deserialize)]
+pub struct ClientPatch {
+    /// `null` turns alerts off.
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub alerta_percentual: Option<Option<i32>>,
+}
+
+/// Tells an explicit `null` apart from a missing field.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct StatementQuery {
+    pub quantidade: Option<i64>,
+    pub tipo: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub descricao: String,
+    /// Only transactions with a lower `sequencia`; pass the previous page's
+    /// `proxima` here.
+    pub antes: Option<i64>,
+    pub quantidade: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResponse {
+    pub transacoes: Vec<LastTransaction>,
+    pub proxima: Option<i64>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Periodo {
+    Dia,
+    Mes,
+}
+
+/// Narrows a client's history down to the statements with this `categoria`
+/// and this one among their `tags`, whichever are given.
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub categoria: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl HistoryQuery {
+    pub fn matches(&self, statement: &Statement) -> bool {
+        self.categoria
+            .as_ref()
+            .is_none_or(|categoria| statement.categoria.as_ref() == Some(categoria))
+            && self
+                .tag
+                .as_ref()
+                .is_none_or(|tag| statement.tags.contains(tag))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GroupedStatementQuery {
+    pub periodo: Periodo,
+    #[serde(flatten)]
+    pub filtro: HistoryQuery,
+}
+
+#[derive(Default, Serialize)]
+pub struct Totals {
+    pub creditos: i64,
+    pub debitos: i64,
+    pub liquido: i64,
+    pub quantidade: u64,
+}
+
+impl Totals {
+    pub fn add(&mut self, statement: &Statement) {
+        let valor = i64::from(statement.valor);
+        if statement.tipo == "d" {
+            self.debitos += valor;
+            self.liquido -= valor;
+        } else {
+            self.creditos += valor;
+            self.liquido += valor;
+        }
+        self.quantidade += 1;
+    }
+}
+
+#[derive(Serialize)]
+pub struct CategoryTotals {
+    pub categoria: String,
+    #[serde(flatten)]
+    pub totais: Totals,
+}
+
+/// Totals of one calendar period (UTC), which starts on `inicio`.
+#[derive(Serialize)]
+pub struct PeriodTotals {
+    pub inicio: NaiveDate,
+    #[serde(flatten)]
+    pub totais: Totals,
+    /// The same totals for each `categoria` seen in the period, by name;
+    /// statements without one are only in the totals above.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub categorias: Vec<CategoryTotals>,
+}
+
+#[derive(Serialize)]
+pub struct GroupedStatementResponse {
+    pub periodo: Periodo,
+    pub grupos: Vec<PeriodTotals>,
+}
+
+#[derive(Deserialize)]
+pub struct FeedQuery {
+    /// ULID of the last event already seen.
+    pub desde: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListClientsQuery {
+    #[serde(default)]
+    pub incluir_inativos: bool,
+}
+
+#[derive(Serialize)]
+pub struct ClientReconciliation {
+    pub id: i32,
+    pub saldo: i32,
+    pub saldo_calculado: i64,
+    pub transacoes: usize,
+    pub divergencia: i64,
+}
+
+/// A transaction acknowledged to the client while the backend failed, which
+/// the backend then refused when it was written there.
+#[derive(Clone, Serialize)]
+pub struct RejectedReplay {
+    pub cliente: i32,
+    pub uuid: Uuid,
+    pub sequencia: i64,
+    pub valor: i32,
+    pub tipo: String,
+    pub descricao: String,
+    pub realizado_em: DateTime<Utc>,
+    pub motivo: String,
+}
+
+#[derive(Serialize)]
+pub struct ReconciliationReport {
+    pub divergentes: usize,
+    pub clientes: Vec<ClientReconciliation>,
+    /// Acknowledged transactions the backend never got, oldest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub transacoes_rejeitadas: Vec<RejectedReplay>,
+}
+
+/// A transaction from before the client came here, recorded at its original
+/// time.
+#[derive(Deserialize)]
+pub struct ImportedTransaction {
+    #[serde(flatten)]
+    pub transaction: NewTransaction,
+    pub realizado_em: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub importadas: usize,
+    pub limite: i32,
+    pub saldo: i32,
+    pub ultima_sequencia: i64,
+}
+
+/// `manifesto.json` of an export bundle.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BundleManifest {
+    /// SHA-256 of everything below but `exportado_em`, so exporting an
+    /// unchanged client again yields the same address.
+    pub endereco: String,
+    pub cliente: i32,
+    pub exportado_em: DateTime<Utc>,
+    pub limite: i32,
+    /// The balance before the first statement, as the client was seeded.
+    pub saldo_inicial: i64,
+    pub saldo: i32,
+    pub ultima_sequencia: i64,
+    pub arquivos: Vec<BundleFile>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BundleFile {
+    pub nome: String,
+    pub sha256: String,
+    pub bytes: u64,
+}
+
+/// What `verify` found in a bundle.
+#[derive(Serialize)]
+pub struct BundleCheck {
+    pub endereco: String,
+    pub integro: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problemas: Vec<String>,
+}
+
+pub fn default_users() -> Vec<User> {
+    [
+        (1, 100000),
+        (2, 80000),
+        (3, 1000000),
+        (4, 10000000),
+        (5, 500000),
+    ]
+    .into_iter()
+    .map(|(id, limite)| User {
+        id,
+        limite,
+        saldo: 0,
+        ativo: true,
+        ultima_sequencia: 0,
+        alerta_percentual: None,
+    })
+    .collect()
+}
@@ -0,0 +1,365 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+
+use crate::{cli::Config, metrics};
+
+const EXCEEDED: &str = "quota_exceeded_total";
+
+/// Quotas count what a client did over the last day, not since midnight.
+const WINDOW_SECS: i64 = 24 * 60 * 60;
+
+/// How much one client may do within the window; `None` is no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quota {
+    pub transacoes: Option<u32>,
+    /// Sum of `valor`, credits and debits alike.
+    pub volume: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientQuota {
+    cliente: i32,
+    quota: Quota,
+}
+
+/// Parses `ID=TRANSACOES[/VOLUME]`, e.g. `1=100/500000`; either limit may be
+/// left empty, as in `2=/100000`.
+pub fn parse_client_quota(value: &str) -> Result<ClientQuota, String> {
+    let (cliente, limits) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected ID=TRANSACOES[/VOLUME], got `{value}`"))?;
+    let cliente = cliente
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid client id `{cliente}`"))?;
+
+    let (transacoes, volume) = limits.split_once('/').unwrap_or((limits, ""));
+    let transacoes = match transacoes.trim() {
+        "" => None,
+        n => Some(n.parse().map_err(|_| format!("invalid count `{n}`"))?),
+    };
+    let volume = match volume.trim() {
+        "" => None,
+        n => Some(n.parse().map_err(|_| format!("invalid volume `{n}`"))?),
+    };
+
+    Ok(ClientQuota {
+        cliente,
+        quota: Quota { transacoes, volume },
+    })
+}
+
+/// The quotas from `--daily-*-quota`, with `--client-quota` overriding them
+/// limit by limit for the clients it names.
+#[derive(Clone, Default)]
+pub struct QuotaLimits {
+    default: Quota,
+    clients: HashMap<i32, Quota>,
+}
+
+impl QuotaLimits {
+    pub fn new(config: &Config) -> Self {
+        let default = Quota {
+            transacoes: config.daily_transaction_quota,
+            volume: config.daily_volume_quota,
+        };
+
+        QuotaLimits {
+            default,
+            clients: config
+                .client_quota
+                .iter()
+                .map(|c| {
+                    let quota = Quota {
+                        transacoes: c.quota.transacoes.or(default.transacoes),
+                        volume: c.quota.volume.or(default.volume),
+                    };
+                    (c.cliente, quota)
+                })
+                .collect(),
+        }
+    }
+
+    fn of(&self, cliente: i32) -> Quota {
+        self.clients.get(&cliente).copied().unwrap_or(self.default)
+    }
+}
+
+struct Entry {
+    id: u64,
+    at: DateTime<Utc>,
+    valor: i64,
+}
+
+#[derive(Default)]
+struct Usage {
+    entries: VecDeque<Entry>,
+    volume: i64,
+}
+
+impl Usage {
+    fn expire(&mut self, since: DateTime<Utc>) {
+        while self.entries.front().is_some_and(|entry| entry.at <= since) {
+            let entry = self.entries.pop_front().unwrap();
+            self.volume -= entry.valor;
+        }
+    }
+
+    /// When enough of the window has passed for `valor` to fit under `limit`;
+    /// never for a `valor` above the limit itself.
+    fn volume_freed_at(&self, valor: i64, limit: i64) -> Option<DateTime<Utc>> {
+        if valor > limit {
+            return None;
+        }
+        let mut volume = self.volume;
+        self.entries.iter().find_map(|entry| {
+            volume -= entry.valor;
+            (volume + valor <= limit).then(|| entry.at + TimeDelta::seconds(WINDOW_SECS))
+        })
+    }
+}
+
+/// Sent with a 429 when a transaction would go over a quota.
+#[derive(Debug, Serialize)]
+pub struct QuotaExceeded {
+    /// `transacoes` or `volume`.
+    cota: &'static str,
+    limite: i64,
+    usado: i64,
+    janela_segundos: i64,
+    /// When the transaction would fit again, if it ever can.
+    liberada_em: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    now: DateTime<Utc>,
+}
+
+impl IntoResponse for QuotaExceeded {
+    fn into_response(self) -> Response {
+        let retry_after = self
+            .liberada_em
+            .map(|at| (at - self.now).num_seconds().max(0) + 1);
+        let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(&self)).into_response();
+        if let Some(seconds) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.into());
+        }
+        response
+    }
+}
+
+/// A transaction's place in its client's quota; give it back with
+/// [`QuotaGuard::release`] if the transaction does not go through.
+pub struct Reservation {
+    cliente: i32,
+    id: u64,
+}
+
+/// Keeps each client's transactions of the last day, for the clients a quota
+/// applies to.
+pub struct QuotaGuard {
+    limits: QuotaLimits,
+    usage: Mutex<(u64, HashMap<i32, Usage>)>,
+}
+
+impl QuotaGuard {
+    pub fn new(limits: QuotaLimits) -> Self {
+        QuotaGuard {
+            limits,
+            usage: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Counts the transaction against the client's quota right away, so
+    /// concurrent requests cannot both take the last place. `None` when no
+    /// quota applies.
+    pub fn reserve(
+        &self,
+        cliente: i32,
+        valor: i32,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Reservation>, QuotaExceeded> {
+        let quota = self.limits.of(cliente);
+        if quota.transacoes.is_none() && quota.volume.is_none() {
+            return Ok(None);
+        }
+        let valor = i64::from(valor);
+
+        let mut usage = self.usage.lock().unwrap();
+        let (next_id, clients) = &mut *usage;
+        let client = clients.entry(cliente).or_default();
+        client.expire(now - TimeDelta::seconds(WINDOW_SECS));
+
+        let exceeded = |cota: &'static str, limite, usado, liberada_em| {
+            metrics::counter(EXCEEDED, vec![("cota", cota.to_owned())]).inc();
+            QuotaExceeded {
+                cota,
+                limite,
+                usado,
+                janela_segundos: WINDOW_SECS,
+                liberada_em,
+                now,
+            }
+        };
+
+        if let Some(limit) = quota.transacoes {
+            let used = client.entries.len();
+            if used >= limit as usize {
+                let freed_at = client
+                    .entries
+                    .get(used - limit as usize)
+                    .map(|entry| entry.at + TimeDelta::seconds(WINDOW_SECS));
+                return Err(exceeded(
+                    "transacoes",
+                    i64::from(limit),
+                    used as i64,
+                    freed_at,
+                ));
+            }
+        }
+        if let Some(limit) = quota.volume {
+            if client.volume + valor > limit {
+                let freed_at = client.volume_freed_at(valor, limit);
+                return Err(exceeded("volume", limit, client.volume, freed_at));
+            }
+        }
+
+        *next_id += 1;
+        client.entries.push_back(Entry {
+            id: *next_id,
+            at: now,
+            valor,
+        });
+        client.volume += valor;
+
+        Ok(Some(Reservation {
+            cliente,
+            id: *next_id,
+        }))
+    }
+
+    pub fn release(&self, reservation: Reservation) {
+        let mut usage = self.usage.lock().unwrap();
+        let clients = &mut usage.1;
+        let Some(client) = clients.get_mut(&reservation.cliente) else {
+            return;
+        };
+
+        if let Some(index) = client
+            .entries
+            .iter()
+            .position(|entry| entry.id == reservation.id)
+        {
+            let entry = client.entries.remove(index).unwrap();
+            client.volume -= entry.valor;
+        }
+        // Unknown client ids must not pile up.
+        if client.entries.is_empty() {
+            clients.remove(&reservation.cliente);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        cli::Cli,
+        clock::{Clock, ManualClock},
+    };
+
+    const DAY: TimeDelta = TimeDelta::seconds(WINDOW_SECS);
+
+    fn guard(transacoes: Option<u32>, volume: Option<i64>) -> QuotaGuard {
+        QuotaGuard::new(QuotaLimits {
+            default: Quota { transacoes, volume },
+            clients: HashMap::new(),
+        })
+    }
+
+    fn clock() -> ManualClock {
+        ManualClock::new("2024-03-01T12:00:00Z".parse().unwrap())
+    }
+
+    #[test]
+    fn transactions_over_the_count_wait_for_the_oldest_to_leave_the_window() {
+        let (guard, clock) = (guard(Some(2), None), clock());
+        let first = clock.now();
+        assert!(guard.reserve(1, 10, first).unwrap().is_some());
+        clock.set(first + TimeDelta::hours(1));
+        assert!(guard.reserve(1, 10, clock.now()).unwrap().is_some());
+
+        let exceeded = guard.reserve(1, 10, clock.now()).err().unwrap();
+        assert_eq!((exceeded.cota, exceeded.usado), ("transacoes", 2));
+        assert_eq!(exceeded.liberada_em, Some(first + DAY));
+
+        clock.set(first + DAY);
+        assert!(guard.reserve(1, 10, clock.now()).is_ok());
+    }
+
+    #[test]
+    fn volume_is_freed_once_enough_of_it_leaves_the_window() {
+        let (guard, clock) = (guard(None, Some(100)), clock());
+        let first = clock.now();
+        guard.reserve(1, 30, first).unwrap();
+        clock.set(first + TimeDelta::hours(2));
+        guard.reserve(1, 60, clock.now()).unwrap();
+
+        let exceeded = guard.reserve(1, 50, clock.now()).err().unwrap();
+        assert_eq!((exceeded.cota, exceeded.usado), ("volume", 90));
+        assert_eq!(
+            exceeded.liberada_em,
+            Some(first + TimeDelta::hours(2) + DAY)
+        );
+        assert!(guard.reserve(1, 10, clock.now()).is_ok());
+    }
+
+    #[test]
+    fn a_transaction_above_the_volume_itself_never_fits() {
+        let exceeded = guard(None, Some(100))
+            .reserve(1, 101, clock().now())
+            .err()
+            .unwrap();
+        assert_eq!(exceeded.liberada_em, None);
+    }
+
+    #[test]
+    fn released_reservations_give_their_place_back() {
+        let (guard, now) = (guard(Some(1), Some(100)), clock().now());
+        let reservation = guard.reserve(1, 100, now).unwrap().unwrap();
+        assert!(guard.reserve(1, 1, now).is_err());
+
+        guard.release(reservation);
+        assert!(guard.reserve(1, 100, now).unwrap().is_some());
+    }
+
+    #[test]
+    fn client_quotas_override_the_default_limit_by_limit() {
+        let cli = Cli::try_parse_from([
+            "rust-lang",
+            "--daily-transaction-quota=1",
+            "--daily-volume-quota=100",
+            "--client-quota=2=/500",
+        ])
+        .unwrap();
+        let limits = QuotaLimits::new(&cli.config);
+
+        assert_eq!(limits.of(2).transacoes, Some(1));
+        assert_eq!(limits.of(2).volume, Some(500));
+        assert_eq!(limits.of(3).volume, Some(100));
+        assert!(parse_client_quota("2=x").is_err());
+        assert!(parse_client_quota("2").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::{error::Error, fs, io, path::Path, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Registry};
+
+use crate::{
+    audit::Audit,
+    cache::StatementCache,
+    cli::Config,
+    models::{default_users, User},
+    storage::Storage,
+};
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Settings that can change without a restart. Anything missing falls back to
+/// the value given on the command line or environment.
+#[derive(Deserialize, Default)]
+pub struct Settings {
+    pub log_level: Option<String>,
+}
+
+impl Settings {
+    fn load(path: Option<&Path>) -> Result<Self, BoxError> {
+        match path {
+            Some(path) => Ok(serde_json::from_slice(&fs::read(path)?)?),
+            None => Ok(Settings::default()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReloadSummary {
+    pub log_level: String,
+    pub clientes_atualizados: usize,
+}
+
+impl ReloadSummary {
+    pub fn audit_details(&self, origem: &str) -> Value {
+        json!({
+            "origem": origem,
+            "log_level": self.log_level,
+            "clientes_atualizados": self.clientes_atualizados,
+        })
+    }
+}
+
+pub struct Reloader {
+    config: Config,
+    log_filter: reload::Handle<EnvFilter, Registry>,
+}
+
+pub fn load_users(path: Option<&Path>) -> Result<Vec<User>, BoxError> {
+    match path {
+        Some(path) => Ok(serde_json::from_slice(&fs::read(path)?)?),
+        None => Ok(default_users()),
+    }
+}
+
+pub fn init_logging(config: Config) -> Result<Reloader, BoxError> {
+    let settings = Settings::load(config.settings_file.as_deref())?;
+    let log_level = settings.log_level.as_deref().unwrap_or(&config.log_level);
+
+    let (filter, log_filter) = reload::Layer::new(EnvFilter::try_new(log_level)?);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(io::stderr))
+        .init();
+
+    Ok(Reloader { config, log_filter })
+}
+
+impl Reloader {
+    /// Reads every file before applying anything, so a typo in one of them
+    /// leaves the running configuration untouched.
+    pub async fn reload(&self, storage: &dyn Storage) -> Result<ReloadSummary, BoxError> {
+        let settings = Settings::load(self.config.settings_file.as_deref())?;
+        let log_level = settings
+            .log_level
+            .unwrap_or_else(|| self.config.log_level.clone());
+        let filter = EnvFilter::try_new(&log_level)?;
+
+        let users = match &self.config.clients_file {
+            Some(path) => Some(load_users(Some(path))?),
+            None => None,
+        };
+
+        self.log_filter.reload(filter)?;
+
+        let clientes_atualizados = match users {
+            Some(users) => storage.update_limits(&users).await?,
+            None => 0,
+        };
+
+        info!(%log_level, clientes_atualizados, "configuration reloaded");
+
+        Ok(ReloadSummary {
+            log_level,
+            clientes_atualizados,
+        })
+    }
+}
+
+pub async fn reload_on_sighup(
+    reloader: Arc<Reloader>,
+    storage: Arc<dyn Storage>,
+    extratos: Arc<StatementCache>,
+    audit: Arc<Audit>,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!("cannot listen for SIGHUP: {err}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        match reloader.reload(storage.as_ref()).await {
+            Ok(summary) => {
+                extratos.clear();
+                audit.record(
+                    "configuracao_recarregada",
+                    None,
+                    summary.audit_details("sighup"),
+                );
+            }
+            Err(err) => error!("reload failed, keeping previous configuration: {err}"),
+        }
+    }
+}
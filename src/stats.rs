@@ -0,0 +1,139 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+
+/// Process-wide counters. Each instance keeps its own, so behind a load
+/// balancer the totals cover only the traffic this process saw.
+pub struct Stats {
+    started_at: Instant,
+    requests: AtomicU64,
+    requests_last_second: AtomicU64,
+    transactions: AtomicU64,
+    credits: AtomicI64,
+    debits: AtomicI64,
+    rejected_debits: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+#[derive(Serialize)]
+pub struct StatsSnapshot {
+    pub transacoes: u64,
+    pub total_creditos: i64,
+    pub total_debitos: i64,
+    pub debitos_recusados: u64,
+    pub requisicoes: u64,
+    pub rps: u64,
+    pub uptime_segundos: u64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            started_at: Instant::now(),
+            requests: AtomicU64::new(0),
+            requests_last_second: AtomicU64::new(0),
+            transactions: AtomicU64::new(0),
+            credits: AtomicI64::new(0),
+            debits: AtomicI64::new(0),
+            rejected_debits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_transaction(&self, tipo: &str, valor: i32) {
+        self.transactions.fetch_add(1, Ordering::Relaxed);
+
+        let sum = if tipo == "d" {
+            &self.debits
+        } else {
+            &self.credits
+        };
+        sum.fetch_add(i64::from(valor), Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_debit(&self) {
+        self.rejected_debits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            transacoes: self.transactions.load(Ordering::Relaxed),
+            total_creditos: self.credits.load(Ordering::Relaxed),
+            total_debitos: self.debits.load(Ordering::Relaxed),
+            debitos_recusados: self.rejected_debits.load(Ordering::Relaxed),
+            requisicoes: self.requests.load(Ordering::Relaxed),
+            rps: self.requests_last_second.load(Ordering::Relaxed),
+            uptime_segundos: self.started_at.elapsed().as_secs(),
+        }
+    }
+}
+
+impl StatsSnapshot {
+    /// Name, metric type and value of each figure, as exported.
+    pub fn metrics(&self) -> [(&'static str, &'static str, f64); 7] {
+        [
+            (
+                "rinha_transactions_total",
+                "counter",
+                self.transacoes as f64,
+            ),
+            ("rinha_credits_total", "counter", self.total_creditos as f64),
+            ("rinha_debits_total", "counter", self.total_debitos as f64),
+            (
+                "rinha_rejected_debits_total",
+                "counter",
+                self.debitos_recusados as f64,
+            ),
+            ("rinha_requests_total", "counter", self.requisicoes as f64),
+            ("rinha_requests_per_second", "gauge", self.rps as f64),
+            ("rinha_uptime_seconds", "gauge", self.uptime_segundos as f64),
+        ]
+    }
+
+    pub fn write_prometheus(&self, out: &mut String) {
+        for (name, kind, value) in self.metrics() {
+            let _ = writeln!(out, "# TYPE {name} {kind}\n{name} {value}");
+        }
+    }
+}
+
+pub async fn count_requests(
+    State(stats): State<Arc<Stats>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    stats.requests.fetch_add(1, Ordering::Relaxed);
+    next.run(request).await
+}
+
+/// Samples the request counter once a second so `rps` reflects the last
+/// full second rather than the average since startup.
+pub async fn track_rps(stats: Arc<Stats>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut previous = stats.requests.load(Ordering::Relaxed);
+
+    loop {
+        interval.tick().await;
+        let current = stats.requests.load(Ordering::Relaxed);
+        stats
+            .requests_last_second
+            .store(current - previous, Ordering::Relaxed);
+        previous = current;
+    }
+}
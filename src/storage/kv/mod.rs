@@ -0,0 +1,587 @@
+#[cfg(feature = "backend-rocksdb")]
+mod rocksdb;
+#[cfg(feature = "backend-sled")]
+mod sled;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+#[cfg(feature = "backend-rocksdb")]
+pub use self::rocksdb::RocksDbEngine;
+#[cfg(feature = "backend-sled")]
+pub use self::sled::SledEngine;
+
+use crate::{
+    domain::{self, Account},
+    ids,
+    metrics::lock::InstrumentedRwLock,
+    models::{NewTransaction, Statement, User},
+};
+
+use super::{trigram, Dump, IdempotencyRecord, Imported, Storage, StorageError, TransactionError};
+
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// The handful of operations the embedded stores have to provide. Values are
+/// JSON, keys are laid out so a client's statements sort by id.
+pub trait KvEngine: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Every entry under `prefix`, in key order.
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Entry>, StorageError>;
+
+    /// Up to `limit` values under `prefix` whose key is at least `start`, in
+    /// key order.
+    fn scan_from(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>, StorageError>;
+
+    /// Up to `limit` values under `prefix` for which `keep` holds, greatest key
+    /// first.
+    fn scan_rev(
+        &self,
+        prefix: &[u8],
+        limit: usize,
+        keep: &dyn Fn(&[u8]) -> bool,
+    ) -> Result<Vec<Vec<u8>>, StorageError>;
+
+    /// Writes all pairs or none of them.
+    fn write(&self, batch: Vec<Entry>) -> Result<(), StorageError>;
+
+    fn remove(&self, keys: Vec<Vec<u8>>) -> Result<(), StorageError>;
+}
+
+const CLIENT_PREFIX: &[u8] = b"c/";
+const STATEMENT_PREFIX: &[u8] = b"t/";
+const SEARCH_PREFIX: &[u8] = b"x/";
+const IDEMPOTENCY_PREFIX: &[u8] = b"i/";
+const NEXT_ID_KEY: &[u8] = b"n";
+/// Only raised by writes once `highest_integer_id` has set it from every
+/// statement.
+const HIGHEST_INTEGER_ID_KEY: &[u8] = b"h";
+
+/// Idempotency records saved between two sweeps of the expired ones.
+const IDEMPOTENCY_SWEEP_EVERY: usize = 1024;
+
+fn client_key(id: i32) -> Vec<u8> {
+    [CLIENT_PREFIX, &id.to_be_bytes()].concat()
+}
+
+fn statements_prefix(user_id: i32) -> Vec<u8> {
+    [STATEMENT_PREFIX, &user_id.to_be_bytes(), b"/"].concat()
+}
+
+fn statement_key(user_id: i32, id: i32) -> Vec<u8> {
+    [statements_prefix(user_id).as_slice(), &id.to_be_bytes()].concat()
+}
+
+fn idempotency_key(key: &str) -> Vec<u8> {
+    [IDEMPOTENCY_PREFIX, key.as_bytes()].concat()
+}
+
+/// Index entries are `x/{client}/{trigram length}{trigram}{id}` with no value,
+/// so scanning one trigram yields a client's matching ids in order.
+fn search_prefix(user_id: i32, trigram: &str) -> Vec<u8> {
+    [
+        SEARCH_PREFIX,
+        &user_id.to_be_bytes(),
+        &[trigram.len() as u8],
+        trigram.as_bytes(),
+    ]
+    .concat()
+}
+
+fn search_entries(statement: &Statement) -> Vec<Entry> {
+    trigram::trigrams(&statement.descricao)
+        .iter()
+        .map(|trigram| {
+            let key = [
+                search_prefix(statement.user_id, trigram).as_slice(),
+                &statement.id.to_be_bytes(),
+            ]
+            .concat();
+            (key, Vec::new())
+        })
+        .collect()
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, StorageError> {
+    Ok(serde_json::to_vec(value)?)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, StorageError> {
+    Ok(serde_json::from_slice(bytes)?)
+}
+
+/// Storage over any [`KvEngine`]. Writes are serialized by `write_lock`, which
+/// is what makes the read-check-write in `apply_transaction` atomic; reads of
+/// a client together with its statements share it, so none lands in between.
+pub struct KvStorage<E> {
+    engine: E,
+    write_lock: InstrumentedRwLock<()>,
+    idempotency_saves: AtomicUsize,
+}
+
+impl<E: KvEngine> KvStorage<E> {
+    pub fn new(engine: E) -> Self {
+        KvStorage {
+            engine,
+            write_lock: InstrumentedRwLock::new("kv_write", ()),
+            idempotency_saves: AtomicUsize::new(0),
+        }
+    }
+
+    fn get_user(&self, id: i32) -> Result<Option<User>, StorageError> {
+        self.engine
+            .get(&client_key(id))?
+            .map(|bytes| decode(&bytes))
+            .transpose()
+    }
+
+    /// The entry raising the highest integer id to one of `uuids`, when it
+    /// is set and one of them is higher.
+    fn raise_highest_integer_id(
+        &self,
+        uuids: impl IntoIterator<Item = Uuid>,
+    ) -> Result<Option<Entry>, StorageError> {
+        let Some(id) = uuids.into_iter().filter_map(ids::integer).max() else {
+            return Ok(None);
+        };
+        let Some(bytes) = self.engine.get(HIGHEST_INTEGER_ID_KEY)? else {
+            return Ok(None);
+        };
+        if decode::<u64>(&bytes)? >= id {
+            return Ok(None);
+        }
+        Ok(Some((HIGHEST_INTEGER_ID_KEY.to_vec(), encode(&id)?)))
+    }
+
+    fn sweep_idempotency_records(&self, now: DateTime<Utc>) -> Result<(), StorageError> {
+        let mut expired = Vec::new();
+        for (key, bytes) in self.engine.scan(IDEMPOTENCY_PREFIX)? {
+            let record: IdempotencyRecord = decode(&bytes)?;
+            if record.expira_em <= now {
+                expired.push(key);
+            }
+        }
+
+        self.engine.remove(expired)
+    }
+}
+
+#[async_trait]
+impl<E: KvEngine> Storage for KvStorage<E> {
+    /// Rebuilds the descricao search index, covering statements written
+    /// before it existed. Safe to run repeatedly.
+    async fn migrate(&self) -> Result<(), StorageError> {
+        let _guard = self.write_lock.write().await;
+
+        let mut batch = Vec::new();
+        for (_, bytes) in self.engine.scan(STATEMENT_PREFIX)? {
+            batch.extend(search_entries(&decode(&bytes)?));
+        }
+
+        self.engine.write(batch)
+    }
+
+    async fn seed(&self, users: &[User]) -> Result<(), StorageError> {
+        let _guard = self.write_lock.write().await;
+
+        let mut batch = Vec::with_capacity(users.len());
+
+        for user in users {
+            let mut user = user.clone();
+            if let Some(existing) = self.get_user(user.id)? {
+                user.ultima_sequencia = user.ultima_sequencia.max(existing.ultima_sequencia);
+                user.alerta_percentual = user.alerta_percentual.or(existing.alerta_percentual);
+            }
+            batch.push((client_key(user.id), encode(&user)?));
+        }
+
+        self.engine.write(batch)
+    }
+
+    async fn seed_missing(&self, users: &[User]) -> Result<Vec<i32>, StorageError> {
+        let _guard = self.write_lock.write().await;
+
+        let mut batch = Vec::new();
+        let mut seeded = Vec::new();
+
+        for user in users {
+            if self.get_user(user.id)?.is_none() {
+                batch.push((client_key(user.id), encode(user)?));
+                seeded.push(user.id);
+            }
+        }
+
+        self.engine.write(batch)?;
+        Ok(seeded)
+    }
+
+    async fn update_limits(&self, users: &[User]) -> Result<usize, StorageError> {
+        let _guard = self.write_lock.write().await;
+        let mut batch = Vec::new();
+
+        for user in users {
+            if let Some(mut existing) = self.get_user(user.id)? {
+                existing.limite = user.limite;
+                batch.push((client_key(user.id), encode(&existing)?));
+            }
+        }
+
+        let updated = batch.len();
+        self.engine.write(batch)?;
+        Ok(updated)
+    }
+
+    async fn list_clients(&self, include_inactive: bool) -> Result<Vec<User>, StorageError> {
+        self.engine
+            .scan(CLIENT_PREFIX)?
+            .iter()
+            .map(|(_, bytes)| decode::<User>(bytes))
+            .filter(|user| user.as_ref().map_or(true, |u| include_inactive || u.ativo))
+            .collect()
+    }
+
+    async fn deactivate(&self, user_id: i32) -> Result<bool, StorageError> {
+        let _guard = self.write_lock.write().await;
+
+        let Some(mut user) = self.get_user(user_id)? else {
+            return Ok(false);
+        };
+
+        user.ativo = false;
+        self.engine
+            .write(vec![(client_key(user_id), encode(&user)?)])?;
+        Ok(true)
+    }
+
+    async fn set_alert(
+        &self,
+        user_id: i32,
+        alerta_percentual: Option<i32>,
+    ) -> Result<Option<User>, StorageError> {
+        let _guard = self.write_lock.write().await;
+
+        let Some(mut user) = self.get_user(user_id)? else {
+            return Ok(None);
+        };
+
+        user.alerta_percentual = alerta_percentual;
+        self.engine
+            .write(vec![(client_key(user_id), encode(&user)?)])?;
+        Ok(Some(user))
+    }
+
+    async fn apply_transaction(
+        &self,
+        user_id: i32,
+        transaction: NewTransaction,
+        realizado_em: DateTime<Utc>,
+        uuid: Uuid,
+    ) -> Result<(User, Statement), TransactionError> {
+        let _guard = self.write_lock.write().await;
+
+        let mut user = self.get_user(user_id)?.ok_or(TransactionError::NotFound)?;
+
+        user.saldo = domain::apply_transaction(Account::from(&user), &transaction)?.saldo;
+        user.ultima_sequencia += 1;
+
+        let id = match self.engine.get(NEXT_ID_KEY)? {
+            Some(bytes) => decode::<i32>(&bytes)?,
+            None => 1,
+        };
+
+        let statement = Statement {
+            id,
+            uuid,
+            sequencia: user.ultima_sequencia,
+            valor: transaction.valor,
+            tipo: transaction.tipo,
+            descricao: transaction.descricao,
+            categoria: transaction.categoria,
+            tags: transaction.tags,
+            realizado_em,
+            user_id,
+        };
+
+        let mut batch = vec![
+            (client_key(user_id), encode(&user)?),
+            (statement_key(user_id, id), encode(&statement)?),
+            (NEXT_ID_KEY.to_vec(), encode(&(id + 1))?),
+        ];
+        batch.extend(self.raise_highest_integer_id([uuid])?);
+        batch.extend(search_entries(&statement));
+        self.engine.write(batch)?;
+
+        Ok((user, statement))
+    }
+
+    async fn import_history(
+        &self,
+        user_id: i32,
+        transactions: Vec<Imported>,
+    ) -> Result<(User, Vec<Statement>), TransactionError> {
+        let _guard = self.write_lock.write().await;
+
+        let mut user = self.get_user(user_id)?.ok_or(TransactionError::NotFound)?;
+
+        let mut account = Account::from(&user);
+        for imported in &transactions {
+            account = domain::apply_transaction(account, &imported.transaction)?;
+        }
+        user.saldo = account.saldo;
+
+        let mut id = match self.engine.get(NEXT_ID_KEY)? {
+            Some(bytes) => decode::<i32>(&bytes)?,
+            None => 1,
+        };
+
+        let mut batch = Vec::new();
+        let mut recorded = Vec::with_capacity(transactions.len());
+        for Imported {
+            transaction,
+            realizado_em,
+            uuid,
+        } in transactions
+        {
+            user.ultima_sequencia += 1;
+            let statement = Statement {
+                id,
+                uuid,
+                sequencia: user.ultima_sequencia,
+                valor: transaction.valor,
+                tipo: transaction.tipo,
+                descricao: transaction.descricao,
+                categoria: transaction.categoria,
+                tags: transaction.tags,
+                realizado_em,
+                user_id,
+            };
+            batch.push((statement_key(user_id, id), encode(&statement)?));
+            batch.extend(search_entries(&statement));
+            recorded.push(statement);
+            id += 1;
+        }
+        batch.push((client_key(user_id), encode(&user)?));
+        batch.push((NEXT_ID_KEY.to_vec(), encode(&id)?));
+        batch.extend(self.raise_highest_integer_id(recorded.iter().map(|s| s.uuid))?);
+        // One batch, so the engine applies all of it or none.
+        self.engine.write(batch)?;
+
+        Ok((user, recorded))
+    }
+
+    async fn statement(
+        &self,
+        user_id: i32,
+        limit: usize,
+        tipo: Option<&str>,
+    ) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        let _guard = self.write_lock.read().await;
+        let Some(user) = self.get_user(user_id)? else {
+            return Ok(None);
+        };
+
+        // Entries that fail to decode are kept so the error surfaces below.
+        let keep = |bytes: &[u8]| match tipo {
+            None => true,
+            Some(tipo) => decode::<Statement>(bytes).map_or(true, |s| s.tipo == tipo),
+        };
+
+        let statements = self
+            .engine
+            .scan_rev(&statements_prefix(user_id), limit, &keep)?
+            .iter()
+            .map(|bytes| decode(bytes))
+            .collect::<Result<Vec<Statement>, _>>()?;
+
+        Ok(Some((user, statements)))
+    }
+
+    async fn history(&self, user_id: i32) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        let _guard = self.write_lock.read().await;
+        let Some(user) = self.get_user(user_id)? else {
+            return Ok(None);
+        };
+
+        let statements = self
+            .engine
+            .scan(&statements_prefix(user_id))?
+            .iter()
+            .map(|(_, bytes)| decode(bytes))
+            .collect::<Result<Vec<Statement>, _>>()?;
+
+        Ok(Some((user, statements)))
+    }
+
+    async fn history_page(
+        &self,
+        user_id: i32,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<(Vec<Statement>, Option<u64>), StorageError> {
+        let start = statement_key(user_id, cursor as i32 + 1);
+
+        let page = self
+            .engine
+            .scan_from(&statements_prefix(user_id), &start, limit)?
+            .iter()
+            .map(|bytes| decode(bytes))
+            .collect::<Result<Vec<Statement>, _>>()?;
+
+        let next = match page.last() {
+            Some(last) if page.len() == limit => Some(last.id as u64),
+            _ => None,
+        };
+
+        Ok((page, next))
+    }
+
+    async fn search(
+        &self,
+        user_id: i32,
+        needle: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Option<Vec<Statement>>, StorageError> {
+        if self.get_user(user_id)?.is_none() {
+            return Ok(None);
+        }
+
+        let needle = needle.to_lowercase();
+        let wanted = |s: &Statement| {
+            before.is_none_or(|before| s.sequencia < before)
+                && trigram::matches(&s.descricao, &needle)
+        };
+
+        let needed = trigram::trigrams(&needle);
+        if needed.is_empty() {
+            let keep = |bytes: &[u8]| decode::<Statement>(bytes).map_or(true, |s| wanted(&s));
+
+            return self
+                .engine
+                .scan_rev(&statements_prefix(user_id), limit, &keep)?
+                .iter()
+                .map(|bytes| decode(bytes))
+                .collect::<Result<Vec<Statement>, _>>()
+                .map(Some);
+        }
+
+        let mut lists = Vec::with_capacity(needed.len());
+        for trigram in &needed {
+            let ids: Vec<i32> = self
+                .engine
+                .scan(&search_prefix(user_id, trigram))?
+                .iter()
+                .filter_map(|(key, _)| {
+                    Some(i32::from_be_bytes(
+                        key.get(key.len() - 4..)?.try_into().ok()?,
+                    ))
+                })
+                .collect();
+            lists.push(ids);
+        }
+
+        let mut found = Vec::new();
+        for id in trigram::intersect(lists).into_iter().rev() {
+            if found.len() >= limit {
+                break;
+            }
+            let Some(bytes) = self.engine.get(&statement_key(user_id, id))? else {
+                continue;
+            };
+            let statement: Statement = decode(&bytes)?;
+            if wanted(&statement) {
+                found.push(statement);
+            }
+        }
+
+        Ok(Some(found))
+    }
+
+    async fn dump(&self) -> Result<Dump, StorageError> {
+        let clientes = self.list_clients(true).await?;
+
+        let mut transacoes = self
+            .engine
+            .scan(STATEMENT_PREFIX)?
+            .iter()
+            .map(|(_, bytes)| decode(bytes))
+            .collect::<Result<Vec<Statement>, _>>()?;
+        transacoes.sort_by_key(|s| s.id);
+
+        Ok(Dump {
+            clientes,
+            transacoes,
+        })
+    }
+
+    /// The first call reads every statement to set it; writes keep it up from
+    /// then on.
+    async fn highest_integer_id(&self) -> Result<u64, StorageError> {
+        let _guard = self.write_lock.write().await;
+
+        if let Some(bytes) = self.engine.get(HIGHEST_INTEGER_ID_KEY)? {
+            return decode(&bytes);
+        }
+        let statements = self
+            .engine
+            .scan(STATEMENT_PREFIX)?
+            .iter()
+            .map(|(_, bytes)| decode(bytes))
+            .collect::<Result<Vec<Statement>, _>>()?;
+        let highest = super::highest_integer_id(&statements);
+        self.engine
+            .write(vec![(HIGHEST_INTEGER_ID_KEY.to_vec(), encode(&highest)?)])?;
+        Ok(highest)
+    }
+
+    fn keeps_idempotency_records(&self) -> bool {
+        true
+    }
+
+    /// An expired record is removed when it is next looked up.
+    async fn idempotency_record(
+        &self,
+        key: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        let key = idempotency_key(key);
+        let Some(bytes) = self.engine.get(&key)? else {
+            return Ok(None);
+        };
+
+        let record: IdempotencyRecord = decode(&bytes)?;
+        if record.expira_em <= now {
+            self.engine.remove(vec![key])?;
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+
+    /// Every so often also drops the records that expired without being
+    /// looked up again.
+    async fn save_idempotency_record(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        now: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.engine
+            .write(vec![(idempotency_key(key), encode(record)?)])?;
+
+        let saves = self.idempotency_saves.fetch_add(1, Ordering::Relaxed) + 1;
+        if saves.is_multiple_of(IDEMPOTENCY_SWEEP_EVERY) {
+            self.sweep_idempotency_records(now)?;
+        }
+        Ok(())
+    }
+}
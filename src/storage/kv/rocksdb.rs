@@ -0,0 +1,117 @@
+use std::path::Path;
+
+use rocksdb::{Options, WriteBatch, DB};
+
+use crate::storage::StorageError;
+
+use super::{Entry, KvEngine};
+
+impl From<rocksdb::Error> for StorageError {
+    fn from(err: rocksdb::Error) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+pub struct RocksDbEngine {
+    db: DB,
+}
+
+impl RocksDbEngine {
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+
+        Ok(RocksDbEngine {
+            db: DB::open(&options, path)?,
+        })
+    }
+}
+
+impl KvEngine for RocksDbEngine {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.db.get(key)?)
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Entry>, StorageError> {
+        let mut entries = Vec::new();
+        let mut iter = self.db.raw_iterator();
+        iter.seek(prefix);
+
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key.to_vec(), value.to_vec()));
+            iter.next();
+        }
+
+        iter.status()?;
+        Ok(entries)
+    }
+
+    fn scan_from(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut values = Vec::new();
+        let mut iter = self.db.raw_iterator();
+        iter.seek(start);
+
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            if values.len() >= limit || !key.starts_with(prefix) {
+                break;
+            }
+            values.push(value.to_vec());
+            iter.next();
+        }
+
+        iter.status()?;
+        Ok(values)
+    }
+
+    fn scan_rev(
+        &self,
+        prefix: &[u8],
+        limit: usize,
+        keep: &dyn Fn(&[u8]) -> bool,
+    ) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut values = Vec::new();
+        let mut iter = self.db.raw_iterator();
+        iter.seek_for_prev([prefix, &[0xff; 8]].concat());
+
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            if values.len() >= limit || !key.starts_with(prefix) {
+                break;
+            }
+            if keep(value) {
+                values.push(value.to_vec());
+            }
+            iter.prev();
+        }
+
+        iter.status()?;
+        Ok(values)
+    }
+
+    fn write(&self, batch: Vec<Entry>) -> Result<(), StorageError> {
+        let mut rocks_batch = WriteBatch::default();
+
+        for (key, value) in batch {
+            rocks_batch.put(key, value);
+        }
+
+        Ok(self.db.write(rocks_batch)?)
+    }
+
+    fn remove(&self, keys: Vec<Vec<u8>>) -> Result<(), StorageError> {
+        let mut rocks_batch = WriteBatch::default();
+
+        for key in keys {
+            rocks_batch.delete(key);
+        }
+
+        Ok(self.db.write(rocks_batch)?)
+    }
+}
@@ -0,0 +1,99 @@
+use std::path::Path;
+
+use crate::storage::StorageError;
+
+use super::{Entry, KvEngine};
+
+impl From<sled::Error> for StorageError {
+    fn from(err: sled::Error) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+pub struct SledEngine {
+    db: sled::Db,
+}
+
+impl SledEngine {
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        Ok(SledEngine {
+            db: sled::open(path)?,
+        })
+    }
+}
+
+impl KvEngine for SledEngine {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Entry>, StorageError> {
+        self.db
+            .scan_prefix(prefix)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn scan_from(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut values = Vec::new();
+
+        for entry in self.db.range(start..).take(limit) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            values.push(value.to_vec());
+        }
+
+        Ok(values)
+    }
+
+    fn scan_rev(
+        &self,
+        prefix: &[u8],
+        limit: usize,
+        keep: &dyn Fn(&[u8]) -> bool,
+    ) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut values = Vec::new();
+
+        for entry in self.db.scan_prefix(prefix).rev() {
+            if values.len() >= limit {
+                break;
+            }
+            let (_, value) = entry?;
+            if keep(&value) {
+                values.push(value.to_vec());
+            }
+        }
+
+        Ok(values)
+    }
+
+    fn write(&self, batch: Vec<Entry>) -> Result<(), StorageError> {
+        let mut sled_batch = sled::Batch::default();
+
+        for (key, value) in batch {
+            sled_batch.insert(key, value);
+        }
+
+        Ok(self.db.apply_batch(sled_batch)?)
+    }
+
+    fn remove(&self, keys: Vec<Vec<u8>>) -> Result<(), StorageError> {
+        let mut sled_batch = sled::Batch::default();
+
+        for key in keys {
+            sled_batch.remove(key);
+        }
+
+        Ok(self.db.apply_batch(sled_batch)?)
+    }
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{
+    domain::{self, Account},
+    metrics::lock::InstrumentedRwLock,
+    models::{NewTransaction, Statement, User},
+};
+
+use super::{trigram, Dump, Imported, Storage, StorageError, TransactionError};
+
+type ArcState = Arc<InstrumentedRwLock<HashMap<i32, User>>>;
+type StatementState = Arc<InstrumentedRwLock<Statements>>;
+
+/// Statements grouped by client, each list in id order, so reads only touch
+/// the client they are about. `search_index` maps each client's descricao
+/// trigrams to positions in its list.
+#[derive(Default)]
+struct Statements {
+    by_client: HashMap<i32, Vec<Statement>>,
+    search_index: HashMap<i32, HashMap<String, Vec<usize>>>,
+    last_id: i32,
+}
+
+impl Statements {
+    fn of(&self, user_id: i32) -> &[Statement] {
+        self.by_client.get(&user_id).map_or(&[], Vec::as_slice)
+    }
+
+    fn push(&mut self, statement: Statement) {
+        let history = self.by_client.entry(statement.user_id).or_default();
+        let index = self.search_index.entry(statement.user_id).or_default();
+
+        for trigram in trigram::trigrams(&statement.descricao) {
+            index.entry(trigram).or_default().push(history.len());
+        }
+        history.push(statement);
+    }
+
+    /// Positions that may match `needle`, ascending. Needles too short to
+    /// have trigrams get every position.
+    fn candidates(&self, user_id: i32, needle: &str) -> Vec<usize> {
+        let needed = trigram::trigrams(needle);
+        if needed.is_empty() {
+            return (0..self.of(user_id).len()).collect();
+        }
+
+        let Some(index) = self.search_index.get(&user_id) else {
+            return Vec::new();
+        };
+
+        let mut lists = Vec::with_capacity(needed.len());
+        for trigram in &needed {
+            match index.get(trigram) {
+                Some(positions) => lists.push(positions.clone()),
+                None => return Vec::new(),
+            }
+        }
+
+        trigram::intersect(lists)
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoryStorage {
+    user_state: ArcState,
+    statement_state: StatementState,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage {
+            user_state: Arc::new(InstrumentedRwLock::new("users", HashMap::new())),
+            statement_state: Arc::new(InstrumentedRwLock::new("statements", Statements::default())),
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+/// Statements each client gets room for on warmup, so the first writes
+/// don't reallocate.
+const RESERVED_STATEMENTS: usize = 4096;
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        let users = self.user_state.read().await;
+        let mut statements = self.statement_state.write().await;
+
+        for id in users.keys() {
+            statements
+                .by_client
+                .entry(*id)
+                .or_default()
+                .reserve(RESERVED_STATEMENTS);
+            statements.search_index.entry(*id).or_default();
+        }
+
+        Ok(())
+    }
+
+    async fn seed(&self, users: &[User]) -> Result<(), StorageError> {
+        let mut hash_user = self.user_state.write().await;
+
+        for user in users {
+            let mut user = user.clone();
+            if let Some(existing) = hash_user.get(&user.id) {
+                user.ultima_sequencia = user.ultima_sequencia.max(existing.ultima_sequencia);
+                user.alerta_percentual = user.alerta_percentual.or(existing.alerta_percentual);
+            }
+            hash_user.insert(user.id, user);
+        }
+
+        Ok(())
+    }
+
+    async fn seed_missing(&self, users: &[User]) -> Result<Vec<i32>, StorageError> {
+        let mut hash_user = self.user_state.write().await;
+        let mut seeded = Vec::new();
+
+        for user in users {
+            if let Entry::Vacant(entry) = hash_user.entry(user.id) {
+                entry.insert(user.clone());
+                seeded.push(user.id);
+            }
+        }
+
+        Ok(seeded)
+    }
+
+    async fn update_limits(&self, users: &[User]) -> Result<usize, StorageError> {
+        let mut hash_user = self.user_state.write().await;
+        let mut updated = 0;
+
+        for user in users {
+            if let Some(existing) = hash_user.get_mut(&user.id) {
+                existing.limite = user.limite;
+                updated += 1;
+            }
+        }
+
+        Ok(updated)
+    }
+
+    async fn list_clients(&self, include_inactive: bool) -> Result<Vec<User>, StorageError> {
+        let users = self.user_state.read().await;
+
+        let mut clientes: Vec<User> = users
+            .values()
+            .filter(|u| include_inactive || u.ativo)
+            .cloned()
+            .collect();
+        clientes.sort_by_key(|u| u.id);
+
+        Ok(clientes)
+    }
+
+    async fn deactivate(&self, user_id: i32) -> Result<bool, StorageError> {
+        let mut users = self.user_state.write().await;
+
+        match users.get_mut(&user_id) {
+            Some(user) => {
+                user.ativo = false;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn set_alert(
+        &self,
+        user_id: i32,
+        alerta_percentual: Option<i32>,
+    ) -> Result<Option<User>, StorageError> {
+        let mut users = self.user_state.write().await;
+
+        Ok(users.get_mut(&user_id).map(|user| {
+            user.alerta_percentual = alerta_percentual;
+            user.clone()
+        }))
+    }
+
+    async fn apply_transaction(
+        &self,
+        user_id: i32,
+        transaction: NewTransaction,
+        realizado_em: DateTime<Utc>,
+        uuid: Uuid,
+    ) -> Result<(User, Statement), TransactionError> {
+        let mut users = self.user_state.write().await;
+        let mut statements = self.statement_state.write().await;
+
+        let user = users.get_mut(&user_id).ok_or(TransactionError::NotFound)?;
+
+        user.saldo = domain::apply_transaction(Account::from(&*user), &transaction)?.saldo;
+        user.ultima_sequencia += 1;
+
+        statements.last_id += 1;
+
+        let statement = Statement {
+            id: statements.last_id,
+            uuid,
+            sequencia: user.ultima_sequencia,
+            valor: transaction.valor,
+            tipo: transaction.tipo,
+            descricao: transaction.descricao,
+            categoria: transaction.categoria,
+            tags: transaction.tags,
+            realizado_em,
+            user_id,
+        };
+        statements.push(statement.clone());
+
+        Ok((user.clone(), statement))
+    }
+
+    async fn import_history(
+        &self,
+        user_id: i32,
+        transactions: Vec<Imported>,
+    ) -> Result<(User, Vec<Statement>), TransactionError> {
+        let mut users = self.user_state.write().await;
+        let mut statements = self.statement_state.write().await;
+
+        let user = users.get_mut(&user_id).ok_or(TransactionError::NotFound)?;
+
+        let mut account = Account::from(&*user);
+        for imported in &transactions {
+            account = domain::apply_transaction(account, &imported.transaction)?;
+        }
+        user.saldo = account.saldo;
+
+        let mut recorded = Vec::with_capacity(transactions.len());
+        for Imported {
+            transaction,
+            realizado_em,
+            uuid,
+        } in transactions
+        {
+            user.ultima_sequencia += 1;
+            statements.last_id += 1;
+            let statement = Statement {
+                id: statements.last_id,
+                uuid,
+                sequencia: user.ultima_sequencia,
+                valor: transaction.valor,
+                tipo: transaction.tipo,
+                descricao: transaction.descricao,
+                categoria: transaction.categoria,
+                tags: transaction.tags,
+                realizado_em,
+                user_id,
+            };
+            statements.push(statement.clone());
+            recorded.push(statement);
+        }
+
+        Ok((user.clone(), recorded))
+    }
+
+    async fn statement(
+        &self,
+        user_id: i32,
+        limit: usize,
+        tipo: Option<&str>,
+    ) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        let users = self.user_state.read().await;
+        let statements = self.statement_state.read().await;
+
+        let Some(user) = users.get(&user_id).cloned() else {
+            return Ok(None);
+        };
+
+        let last_transactions: Vec<Statement> = statements
+            .of(user_id)
+            .iter()
+            .rev()
+            .filter(|s| tipo.is_none_or(|tipo| s.tipo == tipo))
+            .take(limit)
+            .cloned()
+            .collect();
+
+        Ok(Some((user, last_transactions)))
+    }
+
+    async fn history(&self, user_id: i32) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        let users = self.user_state.read().await;
+        let statements = self.statement_state.read().await;
+
+        let Some(user) = users.get(&user_id).cloned() else {
+            return Ok(None);
+        };
+
+        Ok(Some((user, statements.of(user_id).to_vec())))
+    }
+
+    async fn history_page(
+        &self,
+        user_id: i32,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<(Vec<Statement>, Option<u64>), StorageError> {
+        let statements = self.statement_state.read().await;
+
+        let history = statements.of(user_id);
+        let start = history.partition_point(|s| i64::from(s.id) <= cursor as i64);
+        let page: Vec<Statement> = history[start..].iter().take(limit).cloned().collect();
+
+        let next = match page.last() {
+            Some(last) if page.len() == limit => Some(last.id as u64),
+            _ => None,
+        };
+
+        Ok((page, next))
+    }
+
+    async fn search(
+        &self,
+        user_id: i32,
+        needle: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Option<Vec<Statement>>, StorageError> {
+        if !self.user_state.read().await.contains_key(&user_id) {
+            return Ok(None);
+        }
+        let statements = self.statement_state.read().await;
+
+        let needle = needle.to_lowercase();
+        let history = statements.of(user_id);
+
+        let found = statements
+            .candidates(user_id, &needle)
+            .into_iter()
+            .rev()
+            .map(|position| &history[position])
+            .filter(|s| before.is_none_or(|before| s.sequencia < before))
+            .filter(|s| trigram::matches(&s.descricao, &needle))
+            .take(limit)
+            .cloned()
+            .collect();
+
+        Ok(Some(found))
+    }
+
+    async fn dump(&self) -> Result<Dump, StorageError> {
+        let users = self.user_state.read().await;
+        let statements = self.statement_state.read().await;
+
+        let mut clientes: Vec<User> = users.values().cloned().collect();
+        clientes.sort_by_key(|u| u.id);
+
+        let mut transacoes: Vec<Statement> =
+            statements.by_client.values().flatten().cloned().collect();
+        transacoes.sort_by_key(|s| s.id);
+
+        Ok(Dump {
+            clientes,
+            transacoes,
+        })
+    }
+
+    async fn highest_integer_id(&self) -> Result<u64, StorageError> {
+        let statements = self.statement_state.read().await;
+        Ok(super::highest_integer_id(
+            statements.by_client.values().flatten(),
+        ))
+    }
+
+    async fn restore(&self, dump: Dump) -> Result<(), StorageError> {
+        let mut users = self.user_state.write().await;
+        let mut statements = self.statement_state.write().await;
+
+        let mut restored = Statements {
+            last_id: dump.transacoes.iter().map(|s| s.id).max().unwrap_or(0),
+            ..Statements::default()
+        };
+        let mut transacoes = dump.transacoes;
+        transacoes.sort_by_key(|s| s.id);
+        for statement in transacoes {
+            restored.push(statement);
+        }
+
+        *users = dump.clientes.into_iter().map(|u| (u.id, u)).collect();
+        *statements = restored;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,395 @@
+#[cfg(any(feature = "backend-rocksdb", feature = "backend-sled"))]
+mod kv;
+mod memory;
+#[cfg(feature = "backend-postgres")]
+mod postgres;
+#[cfg(feature = "backend-redis")]
+mod redis;
+mod shadow;
+mod tiered;
+mod trigram;
+
+use std::{fmt, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[cfg(any(feature = "backend-rocksdb", feature = "backend-sled"))]
+pub use kv::KvStorage;
+#[cfg(feature = "backend-rocksdb")]
+pub use kv::RocksDbEngine;
+#[cfg(feature = "backend-sled")]
+pub use kv::SledEngine;
+pub use memory::MemoryStorage;
+#[cfg(feature = "backend-postgres")]
+pub use postgres::PostgresStorage;
+#[cfg(feature = "backend-redis")]
+pub use redis::RedisStorage;
+pub use shadow::ShadowStorage;
+pub use tiered::TieredStorage;
+
+use crate::{
+    cli::Config,
+    domain::Rejection,
+    ids,
+    models::{NewTransaction, RejectedReplay, Statement, User},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    Memory,
+    #[cfg(feature = "backend-postgres")]
+    Postgres,
+    #[cfg(feature = "backend-redis")]
+    Redis,
+    #[cfg(feature = "backend-rocksdb")]
+    Rocksdb,
+    #[cfg(feature = "backend-sled")]
+    Sled,
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Backend(String),
+    SchemaBehind { current: i64, expected: i64 },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Backend(message) => write!(f, "storage backend error: {message}"),
+            StorageError::SchemaBehind { current, expected } => write!(
+                f,
+                "schema is at version {current} but this build expects {expected}; run `migrate` first"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(err: serde_json::Error) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+pub enum TransactionError {
+    NotFound,
+    Inactive,
+    LimitExceeded,
+    /// The balance would no longer fit the stored integer.
+    Overflow,
+    Storage(StorageError),
+}
+
+impl From<Rejection> for TransactionError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::Inactive => TransactionError::Inactive,
+            Rejection::LimitExceeded => TransactionError::LimitExceeded,
+            Rejection::Overflow => TransactionError::Overflow,
+        }
+    }
+}
+
+impl From<StorageError> for TransactionError {
+    fn from(err: StorageError) -> Self {
+        TransactionError::Storage(err)
+    }
+}
+
+/// A transaction recorded at the time and under the id it is given.
+#[derive(Clone)]
+pub struct Imported {
+    pub transaction: NewTransaction,
+    pub realizado_em: DateTime<Utc>,
+    pub uuid: Uuid,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Dump {
+    pub clientes: Vec<User>,
+    pub transacoes: Vec<Statement>,
+}
+
+/// The answer a request got, kept under its `Idempotency-Key` until
+/// `expira_em` so a retry is answered the same way instead of applied again.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    /// What the key was first used for; the key cannot be reused for another.
+    pub requisicao: NewTransaction,
+    pub status: u16,
+    /// The JSON sent back, if the answer had one.
+    pub corpo: Option<String>,
+    pub expira_em: DateTime<Utc>,
+}
+
+pub struct SchemaVersion {
+    pub current: i64,
+    pub expected: i64,
+}
+
+/// Everything the handlers and the CLI need from a backend. `apply_transaction`
+/// must check the limit and record the statement atomically.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn migrate(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Backends without a schema always report themselves as up to date.
+    async fn schema_version(&self) -> Result<SchemaVersion, StorageError> {
+        Ok(SchemaVersion {
+            current: 0,
+            expected: 0,
+        })
+    }
+
+    /// Gets ready to serve the clients already stored. Backends with nothing
+    /// to prepare do nothing.
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn seed(&self, users: &[User]) -> Result<(), StorageError>;
+
+    /// Like [`Storage::seed`], but only creates the clients that don't exist
+    /// yet and leaves the others untouched. Returns the ids it created.
+    async fn seed_missing(&self, users: &[User]) -> Result<Vec<i32>, StorageError>;
+
+    /// Changes `limite` of the clients that already exist, leaving balances
+    /// alone. Returns how many were updated.
+    async fn update_limits(&self, users: &[User]) -> Result<usize, StorageError>;
+
+    async fn list_clients(&self, include_inactive: bool) -> Result<Vec<User>, StorageError>;
+
+    /// Marks a client inactive, keeping its history. Returns false when the
+    /// client does not exist.
+    async fn deactivate(&self, user_id: i32) -> Result<bool, StorageError>;
+
+    /// Sets or clears a client's `alerta_percentual`. Returns the updated
+    /// client, or `None` when it does not exist.
+    async fn set_alert(
+        &self,
+        user_id: i32,
+        alerta_percentual: Option<i32>,
+    ) -> Result<Option<User>, StorageError>;
+
+    /// Returns the updated client and the recorded statement, stamped with
+    /// `realizado_em` and known by `uuid`. Inactive clients are rejected with
+    /// [`TransactionError::Inactive`].
+    async fn apply_transaction(
+        &self,
+        user_id: i32,
+        transaction: NewTransaction,
+        realizado_em: DateTime<Utc>,
+        uuid: Uuid,
+    ) -> Result<(User, Statement), TransactionError>;
+
+    /// Applies `transactions` in order like [`Storage::apply_transaction`],
+    /// or none of them when one would be rejected, so a failed import leaves
+    /// the client as it was. Returns the client after the last one and the
+    /// recorded statements, oldest first.
+    async fn import_history(
+        &self,
+        user_id: i32,
+        transactions: Vec<Imported>,
+    ) -> Result<(User, Vec<Statement>), TransactionError>;
+
+    /// The client and up to `limit` of its newest statements, newest first,
+    /// only of the given `tipo` when one is passed. Both are read as of one
+    /// point in time, so the balance is the one the statements lead to.
+    async fn statement(
+        &self,
+        user_id: i32,
+        limit: usize,
+        tipo: Option<&str>,
+    ) -> Result<Option<(User, Vec<Statement>)>, StorageError>;
+
+    /// The client alone, as `statement` with no statements gives it.
+    async fn client(&self, user_id: i32) -> Result<Option<User>, StorageError> {
+        Ok(self
+            .statement(user_id, 0, None)
+            .await?
+            .map(|(user, _)| user))
+    }
+
+    /// Every statement of a client, oldest first, as of the same point in
+    /// time as the client.
+    async fn history(&self, user_id: i32) -> Result<Option<(User, Vec<Statement>)>, StorageError>;
+
+    /// Up to `limit` statements of a client, oldest first, starting at
+    /// `cursor`. The cursor is opaque to callers: start from 0 and pass back
+    /// the one returned until it is `None`.
+    async fn history_page(
+        &self,
+        user_id: i32,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<(Vec<Statement>, Option<u64>), StorageError>;
+
+    /// Statements of a client whose descricao contains `needle`, ignoring
+    /// case, newest first and only below `before` in `sequencia` when given.
+    /// `None` when the client does not exist.
+    async fn search(
+        &self,
+        user_id: i32,
+        needle: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Option<Vec<Statement>>, StorageError>;
+
+    async fn dump(&self) -> Result<Dump, StorageError>;
+
+    /// The highest transaction id stored that is an integer (see
+    /// [`ids::integer`]), 0 without any. Backends that can, answer without
+    /// reading every transaction.
+    async fn highest_integer_id(&self) -> Result<u64, StorageError> {
+        let dump = self.dump().await?;
+        Ok(highest_integer_id(&dump.transacoes))
+    }
+
+    /// Replaces everything stored with `dump`. Only backends keeping their
+    /// data inside the process support it.
+    async fn restore(&self, _dump: Dump) -> Result<(), StorageError> {
+        Err(StorageError::Backend(
+            "this backend cannot restore a dump".into(),
+        ))
+    }
+
+    /// Whether the backend keeps idempotency records itself, so they survive
+    /// restarts. The others are never asked for them and the records are held
+    /// in memory instead.
+    fn keeps_idempotency_records(&self) -> bool {
+        false
+    }
+
+    /// Whether some of the client's transactions were only committed in
+    /// memory and have not reached the backend yet, so reads served from it
+    /// may miss them.
+    fn replaying(&self, _user_id: i32) -> bool {
+        false
+    }
+
+    /// Transactions committed in memory that the backend refused once it
+    /// came back, so their clients' balances diverge from what was answered.
+    fn rejected_replays(&self) -> Vec<RejectedReplay> {
+        Vec::new()
+    }
+
+    /// The record saved under `key`, unless it expired by `now`.
+    async fn idempotency_record(
+        &self,
+        _key: &str,
+        _now: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        Ok(None)
+    }
+
+    /// Saves `record` under `key`, replacing any earlier one. Records are
+    /// expired by `now`, the application's clock, never the backend's.
+    async fn save_idempotency_record(
+        &self,
+        _key: &str,
+        _record: &IdempotencyRecord,
+        _now: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+/// The configured backend, shadowed by `--shadow-backend` when one is given
+/// and behind the hot tier with `--hot-statements`.
+pub async fn open(config: &Config) -> Result<Arc<dyn Storage>, StorageError> {
+    let storage = open_shadowed(config).await?;
+
+    match config.hot_statements {
+        None if config.statement_replay_capacity.is_some() => Err(StorageError::Backend(
+            "--statement-replay-capacity needs --hot-statements to hold the balances".into(),
+        )),
+        None => Ok(storage),
+        Some(_) if config.backend == Backend::Memory => Err(StorageError::Backend(
+            "the memory backend already holds everything in memory; drop --hot-statements".into(),
+        )),
+        Some(keep) => Ok(Arc::new(TieredStorage::new(
+            storage,
+            keep,
+            config.statement_replay_capacity,
+        ))),
+    }
+}
+
+async fn open_shadowed(config: &Config) -> Result<Arc<dyn Storage>, StorageError> {
+    let primary = open_backend(config, config.backend).await?;
+
+    match config.shadow_backend {
+        None => Ok(primary),
+        Some(backend) if backend == config.backend => Err(StorageError::Backend(
+            "the shadow backend must differ from the primary one".into(),
+        )),
+        Some(backend) => {
+            let secondary = open_backend(config, backend).await?;
+            Ok(Arc::new(ShadowStorage::new(primary, secondary)))
+        }
+    }
+}
+
+// Only the optional backends read anything from `config`.
+#[cfg_attr(
+    not(any(
+        feature = "backend-postgres",
+        feature = "backend-redis",
+        feature = "backend-rocksdb",
+        feature = "backend-sled"
+    )),
+    allow(unused_variables)
+)]
+async fn open_backend(config: &Config, backend: Backend) -> Result<Arc<dyn Storage>, StorageError> {
+    match backend {
+        Backend::Memory => Ok(Arc::new(MemoryStorage::new())),
+        #[cfg(feature = "backend-postgres")]
+        Backend::Postgres => {
+            let url = config.database_url.as_deref().ok_or_else(|| {
+                StorageError::Backend("DATABASE_URL is required for the postgres backend".into())
+            })?;
+            Ok(Arc::new(PostgresStorage::connect(url).await?))
+        }
+        #[cfg(feature = "backend-redis")]
+        Backend::Redis => Ok(Arc::new(RedisStorage::connect(&config.redis_url).await?)),
+        #[cfg(feature = "backend-rocksdb")]
+        Backend::Rocksdb => Ok(Arc::new(KvStorage::new(RocksDbEngine::open(
+            &config.data_dir,
+        )?))),
+        #[cfg(feature = "backend-sled")]
+        Backend::Sled => Ok(Arc::new(KvStorage::new(SledEngine::open(
+            &config.data_dir,
+        )?))),
+    }
+}
+
+fn highest_integer_id<'a>(statements: impl IntoIterator<Item = &'a Statement>) -> u64 {
+    statements
+        .into_iter()
+        .filter_map(|statement| ids::integer(statement.uuid))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Refuses to go on when the backend has migrations this build knows about but
+/// that were never applied.
+pub async fn ensure_schema(storage: &dyn Storage) -> Result<(), StorageError> {
+    let version = storage.schema_version().await?;
+
+    if version.current < version.expected {
+        return Err(StorageError::SchemaBehind {
+            current: version.current,
+            expected: version.expected,
+        });
+    }
+
+    Ok(())
+}
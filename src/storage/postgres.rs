@@ -0,0 +1,581 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{migrate::Migrator, postgres::PgPoolOptions, PgPool, Row};
+use uuid::Uuid;
+
+use crate::{
+    domain::{self, Account},
+    ids,
+    models::{NewTransaction, Statement, User},
+};
+
+use super::{
+    Dump, IdempotencyRecord, Imported, SchemaVersion, Storage, StorageError, TransactionError,
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Reads of a client and its statements see one snapshot, so a write
+/// committed between the two queries shows in neither.
+const SNAPSHOT: &str = "BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY";
+
+impl From<sqlx::Error> for StorageError {
+    fn from(err: sqlx::Error) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for StorageError {
+    fn from(err: sqlx::migrate::MigrateError) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    pub async fn connect(url: &str) -> Result<Self, StorageError> {
+        let pool = PgPoolOptions::new().connect(url).await?;
+        Ok(PostgresStorage { pool })
+    }
+}
+
+fn statement_from_row(row: &sqlx::postgres::PgRow) -> Result<Statement, sqlx::Error> {
+    Ok(Statement {
+        id: row.try_get("id")?,
+        uuid: row.try_get("uuid")?,
+        sequencia: row.try_get("sequencia")?,
+        valor: row.try_get("valor")?,
+        tipo: row.try_get("tipo")?,
+        descricao: row.try_get("descricao")?,
+        categoria: row.try_get("categoria")?,
+        tags: row.try_get("tags")?,
+        realizado_em: row.try_get("realizado_em")?,
+        user_id: row.try_get("cliente_id")?,
+    })
+}
+
+fn user_from_row(row: &sqlx::postgres::PgRow) -> Result<User, sqlx::Error> {
+    Ok(User {
+        id: row.try_get("id")?,
+        limite: row.try_get("limite")?,
+        saldo: row.try_get("saldo")?,
+        ativo: row.try_get("ativo")?,
+        ultima_sequencia: row.try_get("ultima_sequencia")?,
+        alerta_percentual: row.try_get("alerta_percentual")?,
+    })
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn migrate(&self) -> Result<(), StorageError> {
+        MIGRATOR.run(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn schema_version(&self) -> Result<SchemaVersion, StorageError> {
+        let expected = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+
+        let has_table: bool =
+            sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+                .fetch_one(&self.pool)
+                .await?;
+
+        let current: i64 = if has_table {
+            sqlx::query_scalar(
+                "SELECT COALESCE(MAX(version), 0) FROM _sqlx_migrations WHERE success",
+            )
+            .fetch_one(&self.pool)
+            .await?
+        } else {
+            0
+        };
+
+        Ok(SchemaVersion { current, expected })
+    }
+
+    async fn seed(&self, users: &[User]) -> Result<(), StorageError> {
+        let mut tx = self.pool.begin().await?;
+
+        for user in users {
+            sqlx::query(
+                "INSERT INTO clientes (id, limite, saldo, ativo, ultima_sequencia, alerta_percentual)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (id) DO UPDATE
+                 SET limite = EXCLUDED.limite, saldo = EXCLUDED.saldo, ativo = EXCLUDED.ativo,
+                     ultima_sequencia = GREATEST(clientes.ultima_sequencia, EXCLUDED.ultima_sequencia),
+                     alerta_percentual = COALESCE(EXCLUDED.alerta_percentual, clientes.alerta_percentual)",
+            )
+            .bind(user.id)
+            .bind(user.limite)
+            .bind(user.saldo)
+            .bind(user.ativo)
+            .bind(user.ultima_sequencia)
+            .bind(user.alerta_percentual)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn seed_missing(&self, users: &[User]) -> Result<Vec<i32>, StorageError> {
+        let mut tx = self.pool.begin().await?;
+        let mut seeded = Vec::new();
+
+        for user in users {
+            let created: Option<i32> = sqlx::query_scalar(
+                "INSERT INTO clientes (id, limite, saldo, ativo, ultima_sequencia, alerta_percentual)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (id) DO NOTHING
+                 RETURNING id",
+            )
+            .bind(user.id)
+            .bind(user.limite)
+            .bind(user.saldo)
+            .bind(user.ativo)
+            .bind(user.ultima_sequencia)
+            .bind(user.alerta_percentual)
+            .fetch_optional(&mut *tx)
+            .await?;
+            seeded.extend(created);
+        }
+
+        tx.commit().await?;
+        Ok(seeded)
+    }
+
+    async fn update_limits(&self, users: &[User]) -> Result<usize, StorageError> {
+        let mut tx = self.pool.begin().await?;
+        let mut updated = 0;
+
+        for user in users {
+            updated += sqlx::query("UPDATE clientes SET limite = $2 WHERE id = $1")
+                .bind(user.id)
+                .bind(user.limite)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected() as usize;
+        }
+
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    async fn list_clients(&self, include_inactive: bool) -> Result<Vec<User>, StorageError> {
+        Ok(sqlx::query(
+            "SELECT id, limite, saldo, ativo, ultima_sequencia, alerta_percentual FROM clientes WHERE ativo OR $1 ORDER BY id",
+        )
+        .bind(include_inactive)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(user_from_row)
+        .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    async fn deactivate(&self, user_id: i32) -> Result<bool, StorageError> {
+        let result = sqlx::query("UPDATE clientes SET ativo = FALSE WHERE id = $1")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_alert(
+        &self,
+        user_id: i32,
+        alerta_percentual: Option<i32>,
+    ) -> Result<Option<User>, StorageError> {
+        let row = sqlx::query(
+            "UPDATE clientes SET alerta_percentual = $2 WHERE id = $1
+             RETURNING id, limite, saldo, ativo, ultima_sequencia, alerta_percentual",
+        )
+        .bind(user_id)
+        .bind(alerta_percentual)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(user_from_row).transpose()?)
+    }
+
+    async fn apply_transaction(
+        &self,
+        user_id: i32,
+        transaction: NewTransaction,
+        realizado_em: DateTime<Utc>,
+        uuid: Uuid,
+    ) -> Result<(User, Statement), TransactionError> {
+        let delta = if transaction.tipo == "d" {
+            -transaction.valor
+        } else {
+            transaction.valor
+        };
+
+        let row = sqlx::query(
+            "WITH updated AS (
+                UPDATE clientes SET saldo = saldo + $2, ultima_sequencia = ultima_sequencia + 1
+                WHERE id = $1 AND ativo AND saldo::int8 + $2 BETWEEN -limite AND 2147483647
+                RETURNING id, limite, saldo, ativo, ultima_sequencia, alerta_percentual
+            ), inserted AS (
+                INSERT INTO transacoes
+                    (cliente_id, valor, tipo, descricao, categoria, tags, uuid, sequencia, realizado_em)
+                SELECT id, $3, $4, $5, $8, $9, $6, ultima_sequencia, $7 FROM updated
+                RETURNING id
+            )
+            SELECT u.id, u.limite, u.saldo, u.ativo, u.ultima_sequencia, u.alerta_percentual,
+                   i.id AS transacao_id
+            FROM updated u, inserted i",
+        )
+        .bind(user_id)
+        .bind(delta)
+        .bind(transaction.valor)
+        .bind(&transaction.tipo)
+        .bind(&transaction.descricao)
+        .bind(uuid)
+        .bind(realizado_em)
+        .bind(&transaction.categoria)
+        .bind(&transaction.tags)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(StorageError::from)?;
+
+        if let Some(row) = row {
+            let user = user_from_row(&row).map_err(StorageError::from)?;
+            let statement = Statement {
+                id: row.try_get("transacao_id").map_err(StorageError::from)?,
+                uuid,
+                sequencia: user.ultima_sequencia,
+                valor: transaction.valor,
+                tipo: transaction.tipo,
+                descricao: transaction.descricao,
+                categoria: transaction.categoria,
+                tags: transaction.tags,
+                realizado_em,
+                user_id,
+            };
+            return Ok((user, statement));
+        }
+
+        // Refused: the domain says why, from the row as it is now.
+        let user = self
+            .client(user_id)
+            .await?
+            .ok_or(TransactionError::NotFound)?;
+        match domain::apply_transaction(Account::from(&user), &transaction) {
+            Err(rejection) => Err(rejection.into()),
+            Ok(_) => Err(TransactionError::LimitExceeded),
+        }
+    }
+
+    /// Checked against the client's row, locked until the commit.
+    async fn import_history(
+        &self,
+        user_id: i32,
+        transactions: Vec<Imported>,
+    ) -> Result<(User, Vec<Statement>), TransactionError> {
+        let mut tx = self.pool.begin().await.map_err(StorageError::from)?;
+
+        let row = sqlx::query(
+            "SELECT id, limite, saldo, ativo, ultima_sequencia, alerta_percentual
+             FROM clientes WHERE id = $1 FOR UPDATE",
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(StorageError::from)?;
+        let mut user = match row {
+            Some(row) => user_from_row(&row).map_err(StorageError::from)?,
+            None => return Err(TransactionError::NotFound),
+        };
+
+        let mut account = Account::from(&user);
+        for imported in &transactions {
+            account = domain::apply_transaction(account, &imported.transaction)?;
+        }
+        user.saldo = account.saldo;
+
+        let mut recorded = Vec::with_capacity(transactions.len());
+        for Imported {
+            transaction,
+            realizado_em,
+            uuid,
+        } in transactions
+        {
+            user.ultima_sequencia += 1;
+            let id: i32 = sqlx::query_scalar(
+                "INSERT INTO transacoes
+                    (cliente_id, valor, tipo, descricao, categoria, tags, uuid, sequencia, realizado_em)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                RETURNING id",
+            )
+            .bind(user_id)
+            .bind(transaction.valor)
+            .bind(&transaction.tipo)
+            .bind(&transaction.descricao)
+            .bind(&transaction.categoria)
+            .bind(&transaction.tags)
+            .bind(uuid)
+            .bind(user.ultima_sequencia)
+            .bind(realizado_em)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(StorageError::from)?;
+
+            recorded.push(Statement {
+                id,
+                uuid,
+                sequencia: user.ultima_sequencia,
+                valor: transaction.valor,
+                tipo: transaction.tipo,
+                descricao: transaction.descricao,
+                categoria: transaction.categoria,
+                tags: transaction.tags,
+                realizado_em,
+                user_id,
+            });
+        }
+
+        sqlx::query("UPDATE clientes SET saldo = $2, ultima_sequencia = $3 WHERE id = $1")
+            .bind(user_id)
+            .bind(user.saldo)
+            .bind(user.ultima_sequencia)
+            .execute(&mut *tx)
+            .await
+            .map_err(StorageError::from)?;
+        tx.commit().await.map_err(StorageError::from)?;
+
+        Ok((user, recorded))
+    }
+
+    async fn client(&self, user_id: i32) -> Result<Option<User>, StorageError> {
+        let row = sqlx::query(
+            "SELECT id, limite, saldo, ativo, ultima_sequencia, alerta_percentual FROM clientes WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.as_ref().map(user_from_row).transpose()?)
+    }
+
+    async fn statement(
+        &self,
+        user_id: i32,
+        limit: usize,
+        tipo: Option<&str>,
+    ) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        let mut tx = self.pool.begin_with(SNAPSHOT).await?;
+        let Some(row) = sqlx::query(
+            "SELECT id, limite, saldo, ativo, ultima_sequencia, alerta_percentual FROM clientes WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let user = user_from_row(&row)?;
+
+        let statements = sqlx::query(
+            "SELECT id, uuid, sequencia, cliente_id, valor, tipo, descricao, categoria, tags, realizado_em FROM transacoes
+             WHERE cliente_id = $1 AND ($3::text IS NULL OR tipo = $3)
+             ORDER BY id DESC LIMIT $2",
+        )
+        .bind(user_id)
+        .bind(limit as i64)
+        .bind(tipo)
+        .fetch_all(&mut *tx)
+        .await?
+        .iter()
+        .map(statement_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+        tx.commit().await?;
+
+        Ok(Some((user, statements)))
+    }
+
+    async fn history(&self, user_id: i32) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        let mut tx = self.pool.begin_with(SNAPSHOT).await?;
+        let Some(row) = sqlx::query(
+            "SELECT id, limite, saldo, ativo, ultima_sequencia, alerta_percentual FROM clientes WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let user = user_from_row(&row)?;
+
+        let statements = sqlx::query(
+            "SELECT id, uuid, sequencia, cliente_id, valor, tipo, descricao, categoria, tags, realizado_em FROM transacoes
+             WHERE cliente_id = $1 ORDER BY id",
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?
+        .iter()
+        .map(statement_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+        tx.commit().await?;
+
+        Ok(Some((user, statements)))
+    }
+
+    async fn history_page(
+        &self,
+        user_id: i32,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<(Vec<Statement>, Option<u64>), StorageError> {
+        let page = sqlx::query(
+            "SELECT id, uuid, sequencia, cliente_id, valor, tipo, descricao, categoria, tags, realizado_em FROM transacoes
+             WHERE cliente_id = $1 AND id > $2 ORDER BY id LIMIT $3",
+        )
+        .bind(user_id)
+        .bind(cursor as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(statement_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        let next = match page.last() {
+            Some(last) if page.len() == limit => Some(last.id as u64),
+            _ => None,
+        };
+
+        Ok((page, next))
+    }
+
+    async fn search(
+        &self,
+        user_id: i32,
+        needle: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Option<Vec<Statement>>, StorageError> {
+        let exists: Option<i32> = sqlx::query_scalar("SELECT id FROM clientes WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        if exists.is_none() {
+            return Ok(None);
+        }
+
+        let pattern = format!(
+            "%{}%",
+            needle
+                .to_lowercase()
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+
+        // lower(descricao) LIKE is what the trigram index serves.
+        let found = sqlx::query(
+            "SELECT id, uuid, sequencia, cliente_id, valor, tipo, descricao, categoria, tags, realizado_em
+             FROM transacoes
+             WHERE cliente_id = $1 AND lower(descricao) LIKE $2
+               AND ($3::bigint IS NULL OR sequencia < $3)
+             ORDER BY id DESC LIMIT $4",
+        )
+        .bind(user_id)
+        .bind(pattern)
+        .bind(before)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(statement_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(found))
+    }
+
+    async fn dump(&self) -> Result<Dump, StorageError> {
+        let clientes = self.list_clients(true).await?;
+
+        let transacoes = sqlx::query(
+            "SELECT id, uuid, sequencia, cliente_id, valor, tipo, descricao, categoria, tags, realizado_em FROM transacoes ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(statement_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Dump {
+            clientes,
+            transacoes,
+        })
+    }
+
+    /// Integer ids are the uuids below 2^64, so the unique index on them
+    /// finds the highest.
+    async fn highest_integer_id(&self) -> Result<u64, StorageError> {
+        let highest: Option<Uuid> = sqlx::query_scalar(
+            "SELECT uuid FROM transacoes WHERE uuid < '00000000-0000-0001-0000-000000000000'
+             ORDER BY uuid DESC LIMIT 1",
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(highest.and_then(ids::integer).unwrap_or(0))
+    }
+
+    fn keeps_idempotency_records(&self) -> bool {
+        true
+    }
+
+    async fn idempotency_record(
+        &self,
+        key: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        let registro: Option<String> = sqlx::query_scalar(
+            "SELECT registro FROM idempotencia WHERE chave = $1 AND expira_em > $2",
+        )
+        .bind(key)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(registro
+            .map(|registro| serde_json::from_str(&registro))
+            .transpose()?)
+    }
+
+    /// Also drops the records that expired, so the table only holds live ones.
+    async fn save_idempotency_record(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        now: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM idempotencia WHERE expira_em <= $1")
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO idempotencia (chave, registro, expira_em) VALUES ($1, $2, $3)
+             ON CONFLICT (chave) DO UPDATE
+             SET registro = EXCLUDED.registro, expira_em = EXCLUDED.expira_em",
+        )
+        .bind(key)
+        .bind(serde_json::to_string(record)?)
+        .bind(record.expira_em)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,921 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use redis::{aio::ConnectionManager, Script};
+use uuid::Uuid;
+
+use crate::{
+    ids,
+    models::{NewTransaction, Statement, User},
+};
+
+use super::{trigram, Dump, IdempotencyRecord, Imported, Storage, StorageError, TransactionError};
+
+/// Newest statements kept per client for the extrato; the full history lives
+/// in a separate list, read when more than this is asked for.
+const RECENT_LEN: isize = 10;
+
+/// History entries fetched per round-trip when walking it back.
+const HISTORY_SCAN_CHUNK: isize = 100;
+
+const CLIENT_SET_KEY: &str = "clientes";
+const NEXT_ID_KEY: &str = "transacoes:seq";
+/// The highest integer transaction id, zero-padded to 20 digits so scripts
+/// compare them as strings; Lua numbers would round snowflakes. Scripts only
+/// raise it once it is set, from every history, by `highest_integer_id`.
+const HIGHEST_INTEGER_ID_KEY: &str = "transacoes:maior_inteiro";
+
+const APPLY_SCRIPT: &str = r#"
+local limite = tonumber(redis.call('HGET', KEYS[1], 'limite'))
+if not limite then
+    return {-1, 0, 0, 0, 0, 0}
+end
+if redis.call('HGET', KEYS[1], 'ativo') == '0' then
+    return {-3, 0, 0, 0, 0, 0}
+end
+
+local saldo = tonumber(redis.call('HGET', KEYS[1], 'saldo')) + tonumber(ARGV[1])
+if saldo < -limite then
+    return {-2, limite, 0, 0, 0, 0}
+end
+if saldo > 2147483647 then
+    return {-4, limite, 0, 0, 0, 0}
+end
+
+local statement = cjson.decode(ARGV[2])
+if ARGV[4] ~= '' then
+    local highest = redis.call('GET', KEYS[5])
+    if highest and ARGV[4] > highest then
+        redis.call('SET', KEYS[5], ARGV[4])
+    end
+end
+statement['id'] = redis.call('INCR', KEYS[4])
+statement['sequencia'] = redis.call('HINCRBY', KEYS[1], 'ultima_sequencia', 1)
+local encoded = cjson.encode(statement)
+
+redis.call('HSET', KEYS[1], 'saldo', saldo)
+redis.call('LPUSH', KEYS[2], encoded)
+redis.call('LTRIM', KEYS[2], 0, tonumber(ARGV[3]) - 1)
+local position = redis.call('RPUSH', KEYS[3], encoded) - 1
+for i = 6, #KEYS do
+    redis.call('SADD', KEYS[i], position)
+end
+
+local alerta = tonumber(redis.call('HGET', KEYS[1], 'alerta_percentual') or 0)
+return {0, limite, saldo, statement['id'], statement['sequencia'], alerta}
+"#;
+
+/// APPLY_SCRIPT for several statements at once: ARGV[2] of them follow as a
+/// delta, the encoded statement and how many of the remaining keys are its
+/// trigrams, then their highest integer id. Nothing is written unless the
+/// limit holds after every one.
+const IMPORT_SCRIPT: &str = r#"
+local limite = tonumber(redis.call('HGET', KEYS[1], 'limite'))
+if not limite then
+    return {-1, 0, 0, 0, 0, 0}
+end
+if redis.call('HGET', KEYS[1], 'ativo') == '0' then
+    return {-3, 0, 0, 0, 0, 0}
+end
+
+local count = tonumber(ARGV[2])
+local saldo = tonumber(redis.call('HGET', KEYS[1], 'saldo'))
+for i = 0, count - 1 do
+    saldo = saldo + tonumber(ARGV[3 + i * 3])
+    if saldo < -limite then
+        return {-2, limite, 0, 0, 0, 0}
+    end
+    if saldo > 2147483647 then
+        return {-4, limite, 0, 0, 0, 0}
+    end
+end
+
+local highest_id = ARGV[3 + count * 3]
+if highest_id ~= '' then
+    local highest = redis.call('GET', KEYS[5])
+    if highest and highest_id > highest then
+        redis.call('SET', KEYS[5], highest_id)
+    end
+end
+
+local first_id = 0
+local sequencia = tonumber(redis.call('HGET', KEYS[1], 'ultima_sequencia') or 0)
+local key = 6
+for i = 0, count - 1 do
+    local statement = cjson.decode(ARGV[4 + i * 3])
+    statement['id'] = redis.call('INCR', KEYS[4])
+    statement['sequencia'] = redis.call('HINCRBY', KEYS[1], 'ultima_sequencia', 1)
+    if i == 0 then
+        first_id = statement['id']
+    end
+    sequencia = statement['sequencia']
+    local encoded = cjson.encode(statement)
+
+    redis.call('LPUSH', KEYS[2], encoded)
+    local position = redis.call('RPUSH', KEYS[3], encoded) - 1
+    for _ = 1, tonumber(ARGV[5 + i * 3]) do
+        redis.call('SADD', KEYS[key], position)
+        key = key + 1
+    end
+end
+redis.call('LTRIM', KEYS[2], 0, tonumber(ARGV[1]) - 1)
+redis.call('HSET', KEYS[1], 'saldo', saldo)
+
+local alerta = tonumber(redis.call('HGET', KEYS[1], 'alerta_percentual') or 0)
+return {0, limite, saldo, first_id, sequencia, alerta}
+"#;
+
+const SET_IF_EXISTS_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return 0
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+return 1
+"#;
+
+const CREATE_IF_MISSING_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'limite', ARGV[1], 'saldo', ARGV[2], 'ativo', ARGV[3],
+    'ultima_sequencia', ARGV[4])
+if ARGV[5] ~= '' then
+    redis.call('HSET', KEYS[1], 'alerta_percentual', ARGV[5])
+end
+redis.call('SADD', KEYS[2], ARGV[6])
+return 1
+"#;
+
+const RAISE_IF_HIGHER_SCRIPT: &str = r#"
+local highest = redis.call('GET', KEYS[1])
+if not highest or ARGV[1] > highest then
+    redis.call('SET', KEYS[1], ARGV[1])
+end
+"#;
+
+const CLEAR_IF_EXISTS_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return 0
+end
+redis.call('HDEL', KEYS[1], ARGV[1])
+return 1
+"#;
+
+impl From<redis::RedisError> for StorageError {
+    fn from(err: redis::RedisError) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+/// Hash fields read back into a [`User`], in the order of [`UserFields`].
+const USER_FIELDS: [&str; 5] = [
+    "limite",
+    "saldo",
+    "ativo",
+    "ultima_sequencia",
+    "alerta_percentual",
+];
+
+type UserFields = (
+    Option<i32>,
+    Option<i32>,
+    Option<bool>,
+    Option<i64>,
+    Option<i32>,
+);
+
+fn client_key(id: i32) -> String {
+    format!("cliente:{id}")
+}
+
+fn recent_key(id: i32) -> String {
+    format!("ultimas:{id}")
+}
+
+fn history_key(id: i32) -> String {
+    format!("historico:{id}")
+}
+
+/// Set of positions in `historico:{id}` whose descricao has `trigram`.
+fn search_key(id: i32, trigram: &str) -> String {
+    format!("busca:{id}:{trigram}")
+}
+
+fn idempotency_key(key: &str) -> String {
+    format!("idempotencia:{key}")
+}
+
+/// The value HIGHEST_INTEGER_ID_KEY would take for `ids`, empty without an
+/// integer among them.
+fn padded_integer_id(ids: impl IntoIterator<Item = Uuid>) -> String {
+    ids.into_iter()
+        .filter_map(ids::integer)
+        .max()
+        .map(|id| format!("{id:020}"))
+        .unwrap_or_default()
+}
+
+fn user_from_fields(id: i32, fields: UserFields) -> Option<User> {
+    let (limite, saldo, ativo, ultima_sequencia, alerta_percentual) = fields;
+
+    limite.map(|limite| User {
+        id,
+        limite,
+        saldo: saldo.unwrap_or(0),
+        ativo: ativo.unwrap_or(true),
+        ultima_sequencia: ultima_sequencia.unwrap_or(0),
+        alerta_percentual,
+    })
+}
+
+fn decode_statements(raw: Vec<String>) -> Result<Vec<Statement>, StorageError> {
+    raw.iter()
+        .map(|json| Ok(serde_json::from_str(json)?))
+        .collect()
+}
+
+pub struct RedisStorage {
+    connection: ConnectionManager,
+    apply_script: Script,
+    import_script: Script,
+    set_if_exists_script: Script,
+    clear_if_exists_script: Script,
+    create_if_missing_script: Script,
+    raise_if_higher_script: Script,
+}
+
+impl RedisStorage {
+    pub async fn connect(url: &str) -> Result<Self, StorageError> {
+        let client = redis::Client::open(url)?;
+
+        Ok(RedisStorage {
+            connection: ConnectionManager::new(client).await?,
+            apply_script: Script::new(APPLY_SCRIPT),
+            import_script: Script::new(IMPORT_SCRIPT),
+            set_if_exists_script: Script::new(SET_IF_EXISTS_SCRIPT),
+            clear_if_exists_script: Script::new(CLEAR_IF_EXISTS_SCRIPT),
+            create_if_missing_script: Script::new(CREATE_IF_MISSING_SCRIPT),
+            raise_if_higher_script: Script::new(RAISE_IF_HIGHER_SCRIPT),
+        })
+    }
+
+    async fn get_user(&self, id: i32) -> Result<Option<User>, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let fields: UserFields = redis::cmd("HMGET")
+            .arg(client_key(id))
+            .arg(&USER_FIELDS)
+            .query_async(&mut connection)
+            .await?;
+
+        Ok(user_from_fields(id, fields))
+    }
+
+    /// The client and how long its history list was at that moment; the
+    /// entries before that position never change, so scanning them later
+    /// still matches the balance.
+    async fn user_and_history_len(
+        &self,
+        user_id: i32,
+    ) -> Result<Option<(User, isize)>, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let (fields, len): (UserFields, isize) = redis::pipe()
+            .atomic()
+            .cmd("HMGET")
+            .arg(client_key(user_id))
+            .arg(&USER_FIELDS)
+            .cmd("LLEN")
+            .arg(history_key(user_id))
+            .query_async(&mut connection)
+            .await?;
+
+        Ok(user_from_fields(user_id, fields).map(|user| (user, len)))
+    }
+
+    async fn statement_of_tipo(
+        &self,
+        user_id: i32,
+        limit: usize,
+        tipo: &str,
+    ) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        let Some((user, len)) = self.user_and_history_len(user_id).await? else {
+            return Ok(None);
+        };
+
+        let statements = self
+            .scan_history(user_id, len, limit, |s| s.tipo == tipo)
+            .await?;
+        Ok(Some((user, statements)))
+    }
+
+    /// Walks the first `len` entries of the history list back from the
+    /// newest, a chunk at a time, until `limit` statements satisfying `keep`
+    /// are found or it runs out.
+    async fn scan_history(
+        &self,
+        user_id: i32,
+        len: isize,
+        limit: usize,
+        keep: impl Fn(&Statement) -> bool,
+    ) -> Result<Vec<Statement>, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let mut statements = Vec::new();
+        let mut end = len - 1;
+
+        while statements.len() < limit && end >= 0 {
+            let start = (end - HISTORY_SCAN_CHUNK + 1).max(0);
+            let raw: Vec<String> = redis::cmd("LRANGE")
+                .arg(history_key(user_id))
+                .arg(start)
+                .arg(end)
+                .query_async(&mut connection)
+                .await?;
+
+            statements.extend(
+                decode_statements(raw)?
+                    .into_iter()
+                    .rev()
+                    .filter(|s| keep(s)),
+            );
+            end = start - 1;
+        }
+
+        statements.truncate(limit);
+        Ok(statements)
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    /// Rebuilds the descricao search index, covering statements written
+    /// before it existed. Safe to run repeatedly.
+    async fn migrate(&self) -> Result<(), StorageError> {
+        let mut connection = self.connection.clone();
+
+        for user in self.list_clients(true).await? {
+            let mut start: isize = 0;
+
+            loop {
+                let raw: Vec<String> = redis::cmd("LRANGE")
+                    .arg(history_key(user.id))
+                    .arg(start)
+                    .arg(start + HISTORY_SCAN_CHUNK - 1)
+                    .query_async(&mut connection)
+                    .await?;
+                let found = raw.len() as isize;
+
+                let mut pipe = redis::pipe();
+                for (offset, statement) in decode_statements(raw)?.iter().enumerate() {
+                    for trigram in trigram::trigrams(&statement.descricao) {
+                        pipe.cmd("SADD")
+                            .arg(search_key(user.id, &trigram))
+                            .arg(start + offset as isize)
+                            .ignore();
+                    }
+                }
+                pipe.query_async::<()>(&mut connection).await?;
+
+                if found < HISTORY_SCAN_CHUNK {
+                    break;
+                }
+                start += HISTORY_SCAN_CHUNK;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn seed(&self, users: &[User]) -> Result<(), StorageError> {
+        let mut connection = self.connection.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for user in users {
+            pipe.cmd("HSET")
+                .arg(client_key(user.id))
+                .arg("limite")
+                .arg(user.limite)
+                .arg("saldo")
+                .arg(user.saldo)
+                .arg("ativo")
+                .arg(user.ativo)
+                .ignore();
+            // Seeding without a threshold keeps the configured one.
+            if let Some(alerta) = user.alerta_percentual {
+                pipe.cmd("HSET")
+                    .arg(client_key(user.id))
+                    .arg("alerta_percentual")
+                    .arg(alerta)
+                    .ignore();
+            }
+            pipe.cmd("SADD").arg(CLIENT_SET_KEY).arg(user.id).ignore();
+        }
+
+        pipe.query_async::<()>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn seed_missing(&self, users: &[User]) -> Result<Vec<i32>, StorageError> {
+        let mut connection = self.connection.clone();
+        let mut seeded = Vec::new();
+
+        for user in users {
+            let created: i32 = self
+                .create_if_missing_script
+                .key(client_key(user.id))
+                .key(CLIENT_SET_KEY)
+                .arg(user.limite)
+                .arg(user.saldo)
+                .arg(user.ativo)
+                .arg(user.ultima_sequencia)
+                .arg(
+                    user.alerta_percentual
+                        .map(|a| a.to_string())
+                        .unwrap_or_default(),
+                )
+                .arg(user.id)
+                .invoke_async(&mut connection)
+                .await?;
+            if created > 0 {
+                seeded.push(user.id);
+            }
+        }
+
+        Ok(seeded)
+    }
+
+    async fn update_limits(&self, users: &[User]) -> Result<usize, StorageError> {
+        let mut connection = self.connection.clone();
+        let mut updated = 0;
+
+        for user in users {
+            let changed: i32 = self
+                .set_if_exists_script
+                .key(client_key(user.id))
+                .arg("limite")
+                .arg(user.limite)
+                .invoke_async(&mut connection)
+                .await?;
+            updated += changed as usize;
+        }
+
+        Ok(updated)
+    }
+
+    async fn list_clients(&self, include_inactive: bool) -> Result<Vec<User>, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let mut ids: Vec<i32> = redis::cmd("SMEMBERS")
+            .arg(CLIENT_SET_KEY)
+            .query_async(&mut connection)
+            .await?;
+        ids.sort_unstable();
+
+        let mut clientes = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(user) = self.get_user(id).await? {
+                if include_inactive || user.ativo {
+                    clientes.push(user);
+                }
+            }
+        }
+
+        Ok(clientes)
+    }
+
+    async fn deactivate(&self, user_id: i32) -> Result<bool, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let changed: i32 = self
+            .set_if_exists_script
+            .key(client_key(user_id))
+            .arg("ativo")
+            .arg(false)
+            .invoke_async(&mut connection)
+            .await?;
+
+        Ok(changed > 0)
+    }
+
+    async fn set_alert(
+        &self,
+        user_id: i32,
+        alerta_percentual: Option<i32>,
+    ) -> Result<Option<User>, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let changed: i32 = match alerta_percentual {
+            Some(alerta) => {
+                self.set_if_exists_script
+                    .key(client_key(user_id))
+                    .arg("alerta_percentual")
+                    .arg(alerta)
+                    .invoke_async(&mut connection)
+                    .await?
+            }
+            None => {
+                self.clear_if_exists_script
+                    .key(client_key(user_id))
+                    .arg("alerta_percentual")
+                    .invoke_async(&mut connection)
+                    .await?
+            }
+        };
+
+        if changed == 0 {
+            return Ok(None);
+        }
+        self.get_user(user_id).await
+    }
+
+    async fn apply_transaction(
+        &self,
+        user_id: i32,
+        transaction: NewTransaction,
+        realizado_em: DateTime<Utc>,
+        uuid: Uuid,
+    ) -> Result<(User, Statement), TransactionError> {
+        let mut connection = self.connection.clone();
+
+        let delta = if transaction.tipo == "d" {
+            -transaction.valor
+        } else {
+            transaction.valor
+        };
+
+        let mut statement = Statement {
+            id: 0,
+            uuid,
+            sequencia: 0,
+            valor: transaction.valor,
+            tipo: transaction.tipo,
+            descricao: transaction.descricao,
+            categoria: transaction.categoria,
+            tags: transaction.tags,
+            realizado_em,
+            user_id,
+        };
+        let encoded = serde_json::to_string(&statement).map_err(StorageError::from)?;
+
+        let mut invocation = self.apply_script.key(client_key(user_id));
+        invocation
+            .key(recent_key(user_id))
+            .key(history_key(user_id))
+            .key(NEXT_ID_KEY)
+            .key(HIGHEST_INTEGER_ID_KEY);
+        for trigram in trigram::trigrams(&statement.descricao) {
+            invocation.key(search_key(user_id, &trigram));
+        }
+
+        let (status, limite, saldo, id, sequencia, alerta): (i32, i32, i32, i32, i64, i32) =
+            invocation
+                .arg(delta)
+                .arg(encoded)
+                .arg(RECENT_LEN)
+                .arg(padded_integer_id([uuid]))
+                .invoke_async(&mut connection)
+                .await
+                .map_err(StorageError::from)?;
+
+        match status {
+            -1 => Err(TransactionError::NotFound),
+            -2 => Err(TransactionError::LimitExceeded),
+            -3 => Err(TransactionError::Inactive),
+            -4 => Err(TransactionError::Overflow),
+            _ => {
+                statement.id = id;
+                statement.sequencia = sequencia;
+                let user = User {
+                    id: user_id,
+                    limite,
+                    saldo,
+                    ativo: true,
+                    ultima_sequencia: sequencia,
+                    // The script answers 0 when no threshold is set.
+                    alerta_percentual: (alerta > 0).then_some(alerta),
+                };
+                Ok((user, statement))
+            }
+        }
+    }
+
+    async fn import_history(
+        &self,
+        user_id: i32,
+        transactions: Vec<Imported>,
+    ) -> Result<(User, Vec<Statement>), TransactionError> {
+        let mut connection = self.connection.clone();
+
+        let mut invocation = self.import_script.key(client_key(user_id));
+        invocation
+            .key(recent_key(user_id))
+            .key(history_key(user_id))
+            .key(NEXT_ID_KEY)
+            .key(HIGHEST_INTEGER_ID_KEY);
+        invocation.arg(RECENT_LEN).arg(transactions.len());
+
+        let mut recorded = Vec::with_capacity(transactions.len());
+        for Imported {
+            transaction,
+            realizado_em,
+            uuid,
+        } in transactions
+        {
+            let delta = if transaction.tipo == "d" {
+                -transaction.valor
+            } else {
+                transaction.valor
+            };
+            let statement = Statement {
+                id: 0,
+                uuid,
+                sequencia: 0,
+                valor: transaction.valor,
+                tipo: transaction.tipo,
+                descricao: transaction.descricao,
+                categoria: transaction.categoria,
+                tags: transaction.tags,
+                realizado_em,
+                user_id,
+            };
+            let encoded = serde_json::to_string(&statement).map_err(StorageError::from)?;
+            let trigrams = trigram::trigrams(&statement.descricao);
+            for trigram in &trigrams {
+                invocation.key(search_key(user_id, trigram));
+            }
+            invocation.arg(delta).arg(encoded).arg(trigrams.len());
+            recorded.push(statement);
+        }
+
+        invocation.arg(padded_integer_id(recorded.iter().map(|s| s.uuid)));
+
+        let (status, limite, saldo, first_id, sequencia, alerta): (i32, i32, i32, i32, i64, i32) =
+            invocation
+                .invoke_async(&mut connection)
+                .await
+                .map_err(StorageError::from)?;
+
+        match status {
+            -1 => Err(TransactionError::NotFound),
+            -2 => Err(TransactionError::LimitExceeded),
+            -3 => Err(TransactionError::Inactive),
+            -4 => Err(TransactionError::Overflow),
+            _ => {
+                let first_sequencia = sequencia - recorded.len() as i64 + 1;
+                for (i, statement) in recorded.iter_mut().enumerate() {
+                    statement.id = first_id + i as i32;
+                    statement.sequencia = first_sequencia + i as i64;
+                }
+                let user = User {
+                    id: user_id,
+                    limite,
+                    saldo,
+                    ativo: true,
+                    ultima_sequencia: sequencia,
+                    alerta_percentual: (alerta > 0).then_some(alerta),
+                };
+                Ok((user, recorded))
+            }
+        }
+    }
+
+    async fn client(&self, user_id: i32) -> Result<Option<User>, StorageError> {
+        self.get_user(user_id).await
+    }
+
+    async fn statement(
+        &self,
+        user_id: i32,
+        limit: usize,
+        tipo: Option<&str>,
+    ) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        if let Some(tipo) = tipo {
+            return self.statement_of_tipo(user_id, limit, tipo).await;
+        }
+
+        let mut connection = self.connection.clone();
+        let count = limit as isize;
+
+        // The history list is oldest first, so its tail has to be reversed.
+        let (list, start, stop, reverse) = if count <= RECENT_LEN {
+            (recent_key(user_id), 0, count - 1, false)
+        } else {
+            (history_key(user_id), -count, -1, true)
+        };
+
+        let (fields, raw): (UserFields, Vec<String>) = redis::pipe()
+            .atomic()
+            .cmd("HMGET")
+            .arg(client_key(user_id))
+            .arg(&USER_FIELDS)
+            .cmd("LRANGE")
+            .arg(list)
+            .arg(start)
+            .arg(stop)
+            .query_async(&mut connection)
+            .await?;
+
+        let Some(user) = user_from_fields(user_id, fields) else {
+            return Ok(None);
+        };
+
+        let mut statements = decode_statements(raw)?;
+        if reverse {
+            statements.reverse();
+        }
+        // LRANGE 0 -1 is the whole list, so a zero limit still needs this.
+        statements.truncate(limit);
+
+        Ok(Some((user, statements)))
+    }
+
+    async fn history(&self, user_id: i32) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let (fields, raw): (UserFields, Vec<String>) = redis::pipe()
+            .atomic()
+            .cmd("HMGET")
+            .arg(client_key(user_id))
+            .arg(&USER_FIELDS)
+            .cmd("LRANGE")
+            .arg(history_key(user_id))
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut connection)
+            .await?;
+
+        let Some(user) = user_from_fields(user_id, fields) else {
+            return Ok(None);
+        };
+
+        Ok(Some((user, decode_statements(raw)?)))
+    }
+
+    /// The cursor is an index into the client's history list.
+    async fn history_page(
+        &self,
+        user_id: i32,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<(Vec<Statement>, Option<u64>), StorageError> {
+        if limit == 0 {
+            return Ok((Vec::new(), None));
+        }
+        let mut connection = self.connection.clone();
+
+        let raw: Vec<String> = redis::cmd("LRANGE")
+            .arg(history_key(user_id))
+            .arg(cursor)
+            .arg(cursor + limit as u64 - 1)
+            .query_async(&mut connection)
+            .await?;
+
+        let page = decode_statements(raw)?;
+        let next = (page.len() == limit).then(|| cursor + limit as u64);
+
+        Ok((page, next))
+    }
+
+    async fn search(
+        &self,
+        user_id: i32,
+        needle: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Option<Vec<Statement>>, StorageError> {
+        let Some((_, len)) = self.user_and_history_len(user_id).await? else {
+            return Ok(None);
+        };
+
+        let needle = needle.to_lowercase();
+        let wanted = |s: &Statement| {
+            before.is_none_or(|before| s.sequencia < before)
+                && trigram::matches(&s.descricao, &needle)
+        };
+
+        let needed = trigram::trigrams(&needle);
+        if needed.is_empty() {
+            return Ok(Some(self.scan_history(user_id, len, limit, wanted).await?));
+        }
+
+        let mut connection = self.connection.clone();
+        let mut sinter = redis::cmd("SINTER");
+        for trigram in &needed {
+            sinter.arg(search_key(user_id, trigram));
+        }
+        let mut positions: Vec<isize> = sinter.query_async(&mut connection).await?;
+        positions.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut found = Vec::new();
+        for chunk in positions.chunks(HISTORY_SCAN_CHUNK as usize) {
+            let mut pipe = redis::pipe();
+            for position in chunk {
+                pipe.cmd("LINDEX").arg(history_key(user_id)).arg(*position);
+            }
+            let raw: Vec<Option<String>> = pipe.query_async(&mut connection).await?;
+
+            found.extend(
+                decode_statements(raw.into_iter().flatten().collect())?
+                    .into_iter()
+                    .filter(|s| wanted(s)),
+            );
+            if found.len() >= limit {
+                break;
+            }
+        }
+
+        found.truncate(limit);
+        Ok(Some(found))
+    }
+
+    async fn dump(&self) -> Result<Dump, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let clientes = self.list_clients(true).await?;
+        let mut transacoes = Vec::new();
+
+        for user in &clientes {
+            let raw: Vec<String> = redis::cmd("LRANGE")
+                .arg(history_key(user.id))
+                .arg(0)
+                .arg(-1)
+                .query_async(&mut connection)
+                .await?;
+            transacoes.extend(decode_statements(raw)?);
+        }
+
+        transacoes.sort_by_key(|s| s.id);
+
+        Ok(Dump {
+            clientes,
+            transacoes,
+        })
+    }
+
+    /// Kept up by the scripts that write transactions once set; the first
+    /// call reads every history to set it.
+    async fn highest_integer_id(&self) -> Result<u64, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let stored: Option<u64> = redis::cmd("GET")
+            .arg(HIGHEST_INTEGER_ID_KEY)
+            .query_async(&mut connection)
+            .await?;
+        if let Some(stored) = stored {
+            return Ok(stored);
+        }
+
+        let highest = super::highest_integer_id(&self.dump().await?.transacoes);
+        self.raise_if_higher_script
+            .key(HIGHEST_INTEGER_ID_KEY)
+            .arg(format!("{highest:020}"))
+            .invoke_async::<()>(&mut connection)
+            .await?;
+        Ok(highest)
+    }
+
+    fn keeps_idempotency_records(&self) -> bool {
+        true
+    }
+
+    async fn idempotency_record(
+        &self,
+        key: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        let mut connection = self.connection.clone();
+
+        let raw: Option<String> = redis::cmd("GET")
+            .arg(idempotency_key(key))
+            .query_async(&mut connection)
+            .await?;
+        let record = raw
+            .map(|json| serde_json::from_str::<IdempotencyRecord>(&json))
+            .transpose()?;
+
+        Ok(record.filter(|record| record.expira_em > now))
+    }
+
+    /// Expires the key along with the record, so redis drops it by itself. The
+    /// time left is given rather than the instant, which redis would read on
+    /// its own clock.
+    async fn save_idempotency_record(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        now: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        let mut connection = self.connection.clone();
+
+        redis::cmd("SET")
+            .arg(idempotency_key(key))
+            .arg(serde_json::to_string(record)?)
+            .arg("PX")
+            .arg((record.expira_em - now).num_milliseconds().max(1))
+            .query_async::<()>(&mut connection)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    metrics,
+    models::{NewTransaction, Statement, User},
+};
+
+use super::{
+    Dump, IdempotencyRecord, Imported, SchemaVersion, Storage, StorageError, TransactionError,
+};
+
+const DIVERGENCES: &str = "shadow_divergences_total";
+const ERRORS: &str = "shadow_errors_total";
+
+/// Writes to `primary` and then `secondary`, reads from `primary` only. Each
+/// write's outcome on the two is compared and differences are counted per
+/// operation, so a new backend can be checked under real traffic before it
+/// takes over. Both get the same statement uuid.
+pub struct ShadowStorage {
+    primary: Arc<dyn Storage>,
+    secondary: Arc<dyn Storage>,
+}
+
+impl ShadowStorage {
+    pub fn new(primary: Arc<dyn Storage>, secondary: Arc<dyn Storage>) -> Self {
+        ShadowStorage { primary, secondary }
+    }
+}
+
+fn same_client(a: &User, b: &User) -> bool {
+    a.id == b.id
+        && a.limite == b.limite
+        && a.saldo == b.saldo
+        && a.ativo == b.ativo
+        && a.alerta_percentual == b.alerta_percentual
+}
+
+fn diverged(operation: &'static str, detail: &str) {
+    metrics::counter(DIVERGENCES, vec![("operation", operation.to_owned())]).inc();
+    warn!(operation, "shadow backend diverged: {detail}");
+}
+
+/// Compares a write's result on both backends. Failures of the secondary are
+/// counted on their own and never reach the caller.
+fn compare<T>(
+    operation: &'static str,
+    primary: &Result<T, StorageError>,
+    secondary: Result<T, StorageError>,
+    same: impl Fn(&T, &T) -> bool,
+) {
+    match (primary, secondary) {
+        (_, Err(err)) => {
+            metrics::counter(ERRORS, vec![("operation", operation.to_owned())]).inc();
+            warn!(operation, "shadow backend failed: {err}");
+        }
+        (Ok(a), Ok(b)) if !same(a, &b) => diverged(operation, "different results"),
+        (Err(_), Ok(_)) => diverged(operation, "only the primary failed"),
+        _ => {}
+    }
+}
+
+fn outcome<T>(result: &Result<T, TransactionError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(TransactionError::NotFound) => "not found",
+        Err(TransactionError::Inactive) => "inactive",
+        Err(TransactionError::LimitExceeded) => "limit exceeded",
+        Err(TransactionError::Overflow) => "overflow",
+        Err(TransactionError::Storage(_)) => "storage error",
+    }
+}
+
+#[async_trait]
+impl Storage for ShadowStorage {
+    async fn migrate(&self) -> Result<(), StorageError> {
+        self.primary.migrate().await?;
+        self.secondary.migrate().await
+    }
+
+    /// Whichever backend is further behind, so neither is served unmigrated.
+    async fn schema_version(&self) -> Result<SchemaVersion, StorageError> {
+        let primary = self.primary.schema_version().await?;
+        let secondary = self.secondary.schema_version().await?;
+
+        if secondary.current < secondary.expected {
+            Ok(secondary)
+        } else {
+            Ok(primary)
+        }
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.primary.warm_up().await?;
+        self.secondary.warm_up().await
+    }
+
+    async fn seed(&self, users: &[User]) -> Result<(), StorageError> {
+        let result = self.primary.seed(users).await;
+        compare("seed", &result, self.secondary.seed(users).await, |_, _| {
+            true
+        });
+        result
+    }
+
+    async fn seed_missing(&self, users: &[User]) -> Result<Vec<i32>, StorageError> {
+        let result = self.primary.seed_missing(users).await;
+        compare(
+            "seed_missing",
+            &result,
+            self.secondary.seed_missing(users).await,
+            |a, b| a == b,
+        );
+        result
+    }
+
+    async fn update_limits(&self, users: &[User]) -> Result<usize, StorageError> {
+        let result = self.primary.update_limits(users).await;
+        compare(
+            "update_limits",
+            &result,
+            self.secondary.update_limits(users).await,
+            |a, b| a == b,
+        );
+        result
+    }
+
+    async fn list_clients(&self, include_inactive: bool) -> Result<Vec<User>, StorageError> {
+        self.primary.list_clients(include_inactive).await
+    }
+
+    async fn deactivate(&self, user_id: i32) -> Result<bool, StorageError> {
+        let result = self.primary.deactivate(user_id).await;
+        compare(
+            "deactivate",
+            &result,
+            self.secondary.deactivate(user_id).await,
+            |a, b| a == b,
+        );
+        result
+    }
+
+    async fn set_alert(
+        &self,
+        user_id: i32,
+        alerta_percentual: Option<i32>,
+    ) -> Result<Option<User>, StorageError> {
+        let result = self.primary.set_alert(user_id, alerta_percentual).await;
+        compare(
+            "set_alert",
+            &result,
+            self.secondary.set_alert(user_id, alerta_percentual).await,
+            |a, b| match (a, b) {
+                (Some(a), Some(b)) => same_client(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            },
+        );
+        result
+    }
+
+    async fn apply_transaction(
+        &self,
+        user_id: i32,
+        transaction: NewTransaction,
+        realizado_em: DateTime<Utc>,
+        uuid: Uuid,
+    ) -> Result<(User, Statement), TransactionError> {
+        let copy = transaction.clone();
+
+        let result = self
+            .primary
+            .apply_transaction(user_id, transaction, realizado_em, uuid)
+            .await;
+        let shadow = self
+            .secondary
+            .apply_transaction(user_id, copy, realizado_em, uuid)
+            .await;
+
+        match (&result, shadow) {
+            (_, Err(TransactionError::Storage(err))) => {
+                metrics::counter(ERRORS, vec![("operation", "apply_transaction".to_owned())]).inc();
+                warn!(
+                    operation = "apply_transaction",
+                    "shadow backend failed: {err}"
+                );
+            }
+            (Ok((a, _)), Ok((b, _))) if !same_client(a, &b) => diverged(
+                "apply_transaction",
+                &format!(
+                    "client {user_id} has saldo {} on the primary and {} on the secondary",
+                    a.saldo, b.saldo
+                ),
+            ),
+            (result, shadow) if outcome(result) != outcome(&shadow) => diverged(
+                "apply_transaction",
+                &format!(
+                    "client {user_id}: {} on the primary, {} on the secondary",
+                    outcome(result),
+                    outcome(&shadow)
+                ),
+            ),
+            _ => {}
+        }
+
+        result
+    }
+
+    async fn import_history(
+        &self,
+        user_id: i32,
+        transactions: Vec<Imported>,
+    ) -> Result<(User, Vec<Statement>), TransactionError> {
+        let copy = transactions.clone();
+
+        let result = self.primary.import_history(user_id, transactions).await;
+        let shadow = self.secondary.import_history(user_id, copy).await;
+
+        match (&result, shadow) {
+            (_, Err(TransactionError::Storage(err))) => {
+                metrics::counter(ERRORS, vec![("operation", "import_history".to_owned())]).inc();
+                warn!(operation = "import_history", "shadow backend failed: {err}");
+            }
+            (Ok((a, _)), Ok((b, _))) if !same_client(a, &b) => diverged(
+                "import_history",
+                &format!(
+                    "client {user_id} has saldo {} on the primary and {} on the secondary",
+                    a.saldo, b.saldo
+                ),
+            ),
+            (result, shadow) if outcome(result) != outcome(&shadow) => diverged(
+                "import_history",
+                &format!(
+                    "client {user_id}: {} on the primary, {} on the secondary",
+                    outcome(result),
+                    outcome(&shadow)
+                ),
+            ),
+            _ => {}
+        }
+
+        result
+    }
+
+    async fn client(&self, user_id: i32) -> Result<Option<User>, StorageError> {
+        self.primary.client(user_id).await
+    }
+
+    async fn statement(
+        &self,
+        user_id: i32,
+        limit: usize,
+        tipo: Option<&str>,
+    ) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        self.primary.statement(user_id, limit, tipo).await
+    }
+
+    async fn history(&self, user_id: i32) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        self.primary.history(user_id).await
+    }
+
+    async fn history_page(
+        &self,
+        user_id: i32,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<(Vec<Statement>, Option<u64>), StorageError> {
+        self.primary.history_page(user_id, cursor, limit).await
+    }
+
+    async fn search(
+        &self,
+        user_id: i32,
+        needle: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Option<Vec<Statement>>, StorageError> {
+        self.primary.search(user_id, needle, before, limit).await
+    }
+
+    async fn dump(&self) -> Result<Dump, StorageError> {
+        self.primary.dump().await
+    }
+
+    async fn highest_integer_id(&self) -> Result<u64, StorageError> {
+        self.primary.highest_integer_id().await
+    }
+
+    /// Idempotency records are not compared; only the primary keeps them.
+    fn keeps_idempotency_records(&self) -> bool {
+        self.primary.keeps_idempotency_records()
+    }
+
+    async fn idempotency_record(
+        &self,
+        key: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        self.primary.idempotency_record(key, now).await
+    }
+
+    async fn save_idempotency_record(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        now: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.primary.save_idempotency_record(key, record, now).await
+    }
+}
@@ -0,0 +1,739 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    domain::{self, Account},
+    metrics,
+    models::{NewTransaction, RejectedReplay, Statement, User},
+};
+
+use super::{
+    Dump, IdempotencyRecord, Imported, SchemaVersion, Storage, StorageError, TransactionError,
+};
+
+const READS: &str = "hot_tier_reads_total";
+const HELD: &str = "hot_tier_statements";
+const COMMITTED_HERE: &str = "hot_tier_committed_in_memory_total";
+const PENDING: &str = "statement_replay_pending";
+const REPLAYED: &str = "statement_replay_total";
+const REJECTED: &str = "statement_replay_rejected";
+
+/// How often the replay looks for work, and waits before retrying the
+/// backend.
+const REPLAY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A client and its newest statements, newest first.
+struct Hot {
+    user: User,
+    recent: VecDeque<Statement>,
+}
+
+impl Hot {
+    /// Whether `recent` is the client's whole history.
+    fn complete(&self) -> bool {
+        self.recent.len() as i64 >= self.user.ultima_sequencia
+    }
+
+    /// Up to `limit` statements of `tipo`, if the hot ones are enough to be
+    /// sure they are the newest.
+    fn statement(&self, limit: usize, tipo: Option<&str>) -> Option<Vec<Statement>> {
+        let found: Vec<Statement> = self
+            .recent
+            .iter()
+            .filter(|s| tipo.is_none_or(|tipo| s.tipo == tipo))
+            .take(limit)
+            .cloned()
+            .collect();
+        (found.len() >= limit || self.complete()).then_some(found)
+    }
+}
+
+/// A transaction committed in memory only, to be written to the backend.
+#[derive(Clone)]
+struct Pending {
+    user_id: i32,
+    transaction: NewTransaction,
+    realizado_em: DateTime<Utc>,
+    uuid: Uuid,
+    sequencia: i64,
+}
+
+#[derive(Default)]
+struct Clients {
+    /// Bumped on every write; a load is dropped when it changed since the
+    /// cold read, so a write landing in between is not lost.
+    generation: u64,
+    hot: HashMap<i32, Hot>,
+    held: usize,
+    /// Oldest first.
+    pending: VecDeque<Pending>,
+    pending_per_client: HashMap<i32, usize>,
+    /// Kept until the restart, for reconciliation to report.
+    rejected: Vec<RejectedReplay>,
+}
+
+impl Clients {
+    /// Clients with transactions waiting for the backend stay, since it does
+    /// not have their balance yet.
+    fn remove(&mut self, user_id: i32) {
+        if self.pending_per_client.contains_key(&user_id) {
+            return;
+        }
+        if let Some(hot) = self.hot.remove(&user_id) {
+            self.held -= hot.recent.len();
+        }
+    }
+
+    /// Makes a change the backend took to the client as held, since one with
+    /// pending transactions stays held and would go on with its old state;
+    /// the others are dropped.
+    fn changed(&mut self, user_id: i32, change: impl FnOnce(&mut User)) {
+        self.generation += 1;
+        if let Some(hot) = self.hot.get_mut(&user_id) {
+            change(&mut hot.user);
+        }
+        self.remove(user_id);
+    }
+
+    fn clear(&mut self) {
+        if !self.pending.is_empty() {
+            warn!(
+                pendentes = self.pending.len(),
+                "dropping transactions not yet written to the backend"
+            );
+        }
+        self.hot.clear();
+        self.held = 0;
+        self.pending.clear();
+        self.pending_per_client.clear();
+        metrics::gauge(PENDING, Vec::new()).set(0.0);
+    }
+
+    /// Applies the transaction to the held balance and queues it for the
+    /// backend. `None` when the client is not held or the queue is full.
+    fn commit_here(
+        &mut self,
+        keep: usize,
+        capacity: usize,
+        user_id: i32,
+        transaction: NewTransaction,
+        realizado_em: DateTime<Utc>,
+        uuid: Uuid,
+    ) -> Option<Result<(User, Statement), TransactionError>> {
+        if self.pending.len() >= capacity {
+            return None;
+        }
+        let hot = self.hot.get_mut(&user_id)?;
+        let account = match domain::apply_transaction(Account::from(&hot.user), &transaction) {
+            Ok(account) => account,
+            Err(rejection) => return Some(Err(rejection.into())),
+        };
+        hot.user.saldo = account.saldo;
+        hot.user.ultima_sequencia += 1;
+
+        let statement = Statement {
+            // Only the backend hands these out; the client is loaded again
+            // once replayed.
+            id: 0,
+            uuid,
+            sequencia: hot.user.ultima_sequencia,
+            valor: transaction.valor,
+            tipo: transaction.tipo.clone(),
+            descricao: transaction.descricao.clone(),
+            categoria: transaction.categoria.clone(),
+            tags: transaction.tags.clone(),
+            realizado_em,
+            user_id,
+        };
+        hot.recent.push_front(statement.clone());
+        let evicted = hot.recent.len().saturating_sub(keep);
+        hot.recent.truncate(keep);
+        let user = hot.user.clone();
+        self.held = self.held + 1 - evicted;
+
+        self.pending.push_back(Pending {
+            user_id,
+            transaction,
+            realizado_em,
+            uuid,
+            sequencia: statement.sequencia,
+        });
+        *self.pending_per_client.entry(user_id).or_default() += 1;
+        metrics::counter(COMMITTED_HERE, Vec::new()).inc();
+        metrics::gauge(PENDING, Vec::new()).set(self.pending.len() as f64);
+        Some(Ok((user, statement)))
+    }
+
+    /// Sets aside the oldest pending transaction, which the backend refused,
+    /// and then drops it like a replayed one.
+    fn rejected(&mut self, motivo: &str) {
+        let Some(pending) = self.pending.front() else {
+            return;
+        };
+        error!(
+            cliente = pending.user_id,
+            sequencia = pending.sequencia,
+            "an acknowledged transaction never reached the backend: {motivo}"
+        );
+        self.rejected.push(RejectedReplay {
+            cliente: pending.user_id,
+            uuid: pending.uuid,
+            sequencia: pending.sequencia,
+            valor: pending.transaction.valor,
+            tipo: pending.transaction.tipo.clone(),
+            descricao: pending.transaction.descricao.clone(),
+            realizado_em: pending.realizado_em,
+            motivo: motivo.to_owned(),
+        });
+        metrics::gauge(REJECTED, Vec::new()).set(self.rejected.len() as f64);
+        self.replayed();
+    }
+
+    /// Drops the oldest pending transaction once the backend has it, and the
+    /// client with it when it was its last, so it is read again from there:
+    /// after a rejection, that puts the held balance back in line with the
+    /// backend's.
+    fn replayed(&mut self) {
+        let Some(done) = self.pending.pop_front() else {
+            return;
+        };
+        let user_id = done.user_id;
+        if let Some(left) = self.pending_per_client.get_mut(&user_id) {
+            *left -= 1;
+            if *left == 0 {
+                self.pending_per_client.remove(&user_id);
+                self.generation += 1;
+                self.remove(user_id);
+            }
+        }
+        metrics::gauge(PENDING, Vec::new()).set(self.pending.len() as f64);
+        metrics::gauge(HELD, Vec::new()).set(self.held as f64);
+    }
+}
+
+fn read(result: &'static str) {
+    metrics::counter(READS, vec![("result", result.to_owned())]).inc();
+}
+
+/// Writes the pending transactions to the backend, in order, as soon as it
+/// takes them again.
+async fn replay(cold: Arc<dyn Storage>, clients: Arc<RwLock<Clients>>) {
+    let mut failing = false;
+    loop {
+        let next = clients.read().unwrap().pending.front().cloned();
+        let Some(pending) = next else {
+            tokio::time::sleep(REPLAY_INTERVAL).await;
+            continue;
+        };
+
+        match replay_one(cold.as_ref(), pending).await {
+            Ok(outcome) => {
+                if failing {
+                    info!("the backend takes transactions again, replaying");
+                    failing = false;
+                }
+                let result = match outcome {
+                    Replay::Stored(result) => {
+                        clients.write().unwrap().replayed();
+                        result
+                    }
+                    Replay::Rejected(motivo) => {
+                        clients.write().unwrap().rejected(motivo);
+                        "rejected"
+                    }
+                };
+                metrics::counter(REPLAYED, vec![("result", result.to_owned())]).inc();
+            }
+            Err(err) => {
+                if !failing {
+                    warn!("replaying transactions to the backend failed, retrying: {err}");
+                    failing = true;
+                }
+                tokio::time::sleep(REPLAY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+enum Replay {
+    /// The backend has it, as the result label says.
+    Stored(&'static str),
+    /// The backend will not take it, for the reason given.
+    Rejected(&'static str),
+}
+
+async fn replay_one(cold: &dyn Storage, pending: Pending) -> Result<Replay, StorageError> {
+    let Pending {
+        user_id,
+        transaction,
+        realizado_em,
+        uuid,
+        sequencia,
+    } = pending;
+
+    let Some(user) = cold.client(user_id).await? else {
+        return Ok(Replay::Rejected("the client is gone from the backend"));
+    };
+    // The failed write may have landed after all.
+    if user.ultima_sequencia >= sequencia {
+        return Ok(Replay::Stored("already_stored"));
+    }
+
+    match cold
+        .apply_transaction(user_id, transaction, realizado_em, uuid)
+        .await
+    {
+        Ok(_) => Ok(Replay::Stored("replayed")),
+        Err(TransactionError::Storage(err)) => Err(err),
+        Err(_) => Ok(Replay::Rejected("the backend refused it")),
+    }
+}
+
+/// Keeps each client's newest `keep` statements in memory in front of a
+/// persistent backend, so extratos are served without touching it while the
+/// full history stays on `cold` for the pages, exports and searches that
+/// need it. Like the extrato cache, it only sees writes made through this
+/// process.
+///
+/// With a `replay_capacity`, a transaction the backend fails for a held
+/// client is committed against the balance held here instead, and written to
+/// the backend later; until then, the client's reads that go to the backend
+/// miss it, as [`Storage::replaying`] tells.
+pub struct TieredStorage {
+    cold: Arc<dyn Storage>,
+    keep: usize,
+    replay_capacity: Option<usize>,
+    clients: Arc<RwLock<Clients>>,
+}
+
+impl TieredStorage {
+    pub fn new(cold: Arc<dyn Storage>, keep: usize, replay_capacity: Option<usize>) -> Self {
+        metrics::gauge(HELD, Vec::new()).set(0.0);
+        let clients = Arc::new(RwLock::new(Clients::default()));
+        if replay_capacity.is_some() {
+            metrics::gauge(PENDING, Vec::new()).set(0.0);
+            metrics::gauge(REJECTED, Vec::new()).set(0.0);
+            tokio::spawn(replay(cold.clone(), clients.clone()));
+        }
+        TieredStorage {
+            cold,
+            keep,
+            replay_capacity,
+            clients,
+        }
+    }
+
+    fn hot_statement(
+        &self,
+        user_id: i32,
+        limit: usize,
+        tipo: Option<&str>,
+    ) -> Option<Option<(User, Vec<Statement>)>> {
+        let clients = self.clients.read().unwrap();
+        let hot = clients.hot.get(&user_id)?;
+        hot.statement(limit, tipo)
+            .map(|statements| Some((hot.user.clone(), statements)))
+    }
+
+    /// Reads the client's newest statements from `cold` into memory. `None`
+    /// when it does not exist.
+    async fn load(&self, user_id: i32) -> Result<Option<()>, StorageError> {
+        let generation = self.clients.read().unwrap().generation;
+        let Some((user, statements)) = self.cold.statement(user_id, self.keep, None).await? else {
+            return Ok(None);
+        };
+
+        let mut clients = self.clients.write().unwrap();
+        if clients.generation == generation && !clients.pending_per_client.contains_key(&user_id) {
+            clients.remove(user_id);
+            clients.held += statements.len();
+            clients.hot.insert(
+                user_id,
+                Hot {
+                    user,
+                    recent: statements.into(),
+                },
+            );
+            metrics::gauge(HELD, Vec::new()).set(clients.held as f64);
+        }
+        Ok(Some(()))
+    }
+
+    fn forget(&self, user_ids: impl IntoIterator<Item = i32>) {
+        let mut clients = self.clients.write().unwrap();
+        clients.generation += 1;
+        for user_id in user_ids {
+            clients.remove(user_id);
+        }
+        metrics::gauge(HELD, Vec::new()).set(clients.held as f64);
+    }
+
+    fn changed(&self, user_id: i32, change: impl FnOnce(&mut User)) {
+        let mut clients = self.clients.write().unwrap();
+        clients.changed(user_id, change);
+        metrics::gauge(HELD, Vec::new()).set(clients.held as f64);
+    }
+
+    fn forget_all(&self) {
+        let mut clients = self.clients.write().unwrap();
+        clients.generation += 1;
+        clients.clear();
+        metrics::gauge(HELD, Vec::new()).set(0.0);
+    }
+}
+
+#[async_trait]
+impl Storage for TieredStorage {
+    async fn migrate(&self) -> Result<(), StorageError> {
+        self.cold.migrate().await
+    }
+
+    async fn schema_version(&self) -> Result<SchemaVersion, StorageError> {
+        self.cold.schema_version().await
+    }
+
+    /// Also loads every active client's newest statements.
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.cold.warm_up().await?;
+        for user in self.cold.list_clients(false).await? {
+            self.load(user.id).await?;
+        }
+        Ok(())
+    }
+
+    async fn seed(&self, users: &[User]) -> Result<(), StorageError> {
+        let result = self.cold.seed(users).await;
+        self.forget(users.iter().map(|user| user.id));
+        result
+    }
+
+    async fn seed_missing(&self, users: &[User]) -> Result<Vec<i32>, StorageError> {
+        self.cold.seed_missing(users).await
+    }
+
+    async fn update_limits(&self, users: &[User]) -> Result<usize, StorageError> {
+        let result = self.cold.update_limits(users).await;
+        match result {
+            Ok(_) => {
+                for user in users {
+                    self.changed(user.id, |held| held.limite = user.limite);
+                }
+            }
+            Err(_) => self.forget(users.iter().map(|user| user.id)),
+        }
+        result
+    }
+
+    async fn list_clients(&self, include_inactive: bool) -> Result<Vec<User>, StorageError> {
+        self.cold.list_clients(include_inactive).await
+    }
+
+    async fn deactivate(&self, user_id: i32) -> Result<bool, StorageError> {
+        let result = self.cold.deactivate(user_id).await;
+        match result {
+            Ok(true) => self.changed(user_id, |held| held.ativo = false),
+            _ => self.forget([user_id]),
+        }
+        result
+    }
+
+    async fn set_alert(
+        &self,
+        user_id: i32,
+        alerta_percentual: Option<i32>,
+    ) -> Result<Option<User>, StorageError> {
+        let result = self.cold.set_alert(user_id, alerta_percentual).await;
+        match result {
+            Ok(Some(_)) => self.changed(user_id, |held| {
+                held.alerta_percentual = alerta_percentual;
+            }),
+            _ => self.forget([user_id]),
+        }
+        result
+    }
+
+    async fn apply_transaction(
+        &self,
+        user_id: i32,
+        transaction: NewTransaction,
+        realizado_em: DateTime<Utc>,
+        uuid: Uuid,
+    ) -> Result<(User, Statement), TransactionError> {
+        let mut fallback = None;
+        if let Some(capacity) = self.replay_capacity {
+            let mut clients = self.clients.write().unwrap();
+            if clients.pending_per_client.contains_key(&user_id) {
+                // Its earlier transactions have to reach the backend first.
+                clients.generation += 1;
+                return clients
+                    .commit_here(
+                        self.keep,
+                        capacity,
+                        user_id,
+                        transaction,
+                        realizado_em,
+                        uuid,
+                    )
+                    .unwrap_or_else(|| {
+                        Err(TransactionError::Storage(StorageError::Backend(
+                            "the replay queue is full".into(),
+                        )))
+                    });
+            }
+            fallback = Some((capacity, transaction.clone()));
+        }
+
+        let result = self
+            .cold
+            .apply_transaction(user_id, transaction, realizado_em, uuid)
+            .await;
+
+        let mut clients = self.clients.write().unwrap();
+        clients.generation += 1;
+        if let (Err(TransactionError::Storage(err)), Some((capacity, transaction))) =
+            (&result, fallback)
+        {
+            if let Some(committed) = clients.commit_here(
+                self.keep,
+                capacity,
+                user_id,
+                transaction,
+                realizado_em,
+                uuid,
+            ) {
+                warn!(
+                    cliente = user_id,
+                    "committed in memory, to be replayed, after the backend failed: {err}"
+                );
+                return committed;
+            }
+        }
+        match &result {
+            Ok((user, statement)) => {
+                if let Some(hot) = clients.hot.get_mut(&user_id) {
+                    hot.user = user.clone();
+                    hot.recent.push_front(statement.clone());
+                    let evicted = hot.recent.len().saturating_sub(self.keep);
+                    hot.recent.truncate(self.keep);
+                    clients.held = clients.held + 1 - evicted;
+                }
+            }
+            // A failed write may still have reached the backend.
+            Err(TransactionError::Storage(_)) => clients.remove(user_id),
+            Err(_) => {}
+        }
+        metrics::gauge(HELD, Vec::new()).set(clients.held as f64);
+        result
+    }
+
+    async fn import_history(
+        &self,
+        user_id: i32,
+        transactions: Vec<Imported>,
+    ) -> Result<(User, Vec<Statement>), TransactionError> {
+        if self.replaying(user_id) {
+            return Err(TransactionError::Storage(StorageError::Backend(
+                "transactions of the client are still being replayed".into(),
+            )));
+        }
+        let result = self.cold.import_history(user_id, transactions).await;
+        self.forget([user_id]);
+        result
+    }
+
+    async fn statement(
+        &self,
+        user_id: i32,
+        limit: usize,
+        tipo: Option<&str>,
+    ) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        if let Some(found) = self.hot_statement(user_id, limit, tipo) {
+            read("hit");
+            return Ok(found);
+        }
+
+        read("miss");
+        let held = self.clients.read().unwrap().hot.contains_key(&user_id);
+        if !held {
+            if self.load(user_id).await?.is_none() {
+                return Ok(None);
+            }
+            if let Some(found) = self.hot_statement(user_id, limit, tipo) {
+                return Ok(found);
+            }
+        }
+        self.cold.statement(user_id, limit, tipo).await
+    }
+
+    async fn history(&self, user_id: i32) -> Result<Option<(User, Vec<Statement>)>, StorageError> {
+        let held = {
+            let clients = self.clients.read().unwrap();
+            clients
+                .hot
+                .get(&user_id)
+                .filter(|hot| hot.complete())
+                .map(|hot| (hot.user.clone(), hot.recent.iter().rev().cloned().collect()))
+        };
+        if let Some(found) = held {
+            read("hit");
+            return Ok(Some(found));
+        }
+
+        read("miss");
+        self.cold.history(user_id).await
+    }
+
+    async fn history_page(
+        &self,
+        user_id: i32,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<(Vec<Statement>, Option<u64>), StorageError> {
+        read("miss");
+        self.cold.history_page(user_id, cursor, limit).await
+    }
+
+    async fn search(
+        &self,
+        user_id: i32,
+        needle: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Option<Vec<Statement>>, StorageError> {
+        read("miss");
+        self.cold.search(user_id, needle, before, limit).await
+    }
+
+    fn replaying(&self, user_id: i32) -> bool {
+        self.clients
+            .read()
+            .unwrap()
+            .pending_per_client
+            .contains_key(&user_id)
+    }
+
+    fn rejected_replays(&self) -> Vec<RejectedReplay> {
+        self.clients.read().unwrap().rejected.clone()
+    }
+
+    async fn dump(&self) -> Result<Dump, StorageError> {
+        self.cold.dump().await
+    }
+
+    /// Transactions only committed here got their ids after the writer
+    /// resumed from the backend, so it has all the others.
+    async fn highest_integer_id(&self) -> Result<u64, StorageError> {
+        self.cold.highest_integer_id().await
+    }
+
+    async fn restore(&self, dump: Dump) -> Result<(), StorageError> {
+        let result = self.cold.restore(dump).await;
+        self.forget_all();
+        result
+    }
+
+    fn keeps_idempotency_records(&self) -> bool {
+        self.cold.keeps_idempotency_records()
+    }
+
+    async fn idempotency_record(
+        &self,
+        key: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        self.cold.idempotency_record(key, now).await
+    }
+
+    async fn save_idempotency_record(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        now: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.cold.save_idempotency_record(key, record, now).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(valor: i32, tipo: &str) -> NewTransaction {
+        NewTransaction {
+            valor,
+            tipo: tipo.to_owned(),
+            descricao: "teste".to_owned(),
+            categoria: None,
+            tags: Vec::new(),
+        }
+    }
+
+    fn commit(
+        clients: &mut Clients,
+        valor: i32,
+    ) -> Option<Result<(User, Statement), TransactionError>> {
+        clients.commit_here(
+            10,
+            10,
+            1,
+            transaction(valor, "c"),
+            Utc::now(),
+            Uuid::now_v7(),
+        )
+    }
+
+    fn held() -> Clients {
+        let mut clients = Clients::default();
+        let user = User {
+            id: 1,
+            limite: 1000,
+            saldo: 0,
+            ativo: true,
+            ultima_sequencia: 0,
+            alerta_percentual: None,
+        };
+        clients.hot.insert(
+            1,
+            Hot {
+                user,
+                recent: VecDeque::new(),
+            },
+        );
+        clients
+    }
+
+    #[test]
+    fn a_client_waiting_for_replays_takes_admin_changes() {
+        let mut clients = held();
+        assert!(matches!(commit(&mut clients, 100), Some(Ok(_))));
+
+        clients.changed(1, |user| user.alerta_percentual = Some(80));
+        clients.changed(1, |user| user.ativo = false);
+
+        let hot = &clients.hot[&1];
+        assert_eq!(hot.user.alerta_percentual, Some(80));
+        assert!(!hot.user.ativo);
+        assert!(matches!(
+            commit(&mut clients, 100),
+            Some(Err(TransactionError::Inactive))
+        ));
+        assert_eq!(clients.pending.len(), 1);
+    }
+
+    #[test]
+    fn a_client_with_nothing_to_replay_is_dropped_when_changed() {
+        let mut clients = held();
+        clients.changed(1, |user| user.ativo = false);
+
+        assert!(clients.hot.is_empty());
+        assert!(commit(&mut clients, 100).is_none());
+    }
+}
@@ -0,0 +1,35 @@
+/// The distinct trigrams of `text`, lowercased and sorted, as kept in the
+/// per-client descricao index. A needle can only match statements holding all
+/// of its own trigrams; texts shorter than three characters have none.
+pub fn trigrams(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.to_lowercase().chars().collect();
+
+    let mut found: Vec<String> = chars
+        .windows(3)
+        .map(|window| window.iter().collect())
+        .collect();
+    found.sort_unstable();
+    found.dedup();
+    found
+}
+
+/// Whether `descricao` contains `needle`, ignoring case. `needle` must already
+/// be lowercased.
+pub fn matches(descricao: &str, needle: &str) -> bool {
+    descricao.to_lowercase().contains(needle)
+}
+
+/// Ids present in every list. Each list must be sorted ascending.
+pub fn intersect<T: Ord + Copy>(mut lists: Vec<Vec<T>>) -> Vec<T> {
+    lists.sort_by_key(Vec::len);
+
+    let Some((shortest, rest)) = lists.split_first() else {
+        return Vec::new();
+    };
+
+    shortest
+        .iter()
+        .copied()
+        .filter(|id| rest.iter().all(|list| list.binary_search(id).is_ok()))
+        .collect()
+}
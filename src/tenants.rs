@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use axum::{
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alerts::Alerts,
+    cache::StatementCache,
+    cli::Config,
+    clock::Clock,
+    duplicates::{DuplicateGuard, DuplicateMode},
+    feed::Feed,
+    i18n::{Lang, Localized, Message},
+    idempotency::IdempotencyStore,
+    ids::{self, IdStrategy},
+    models::User,
+    quotas::{QuotaGuard, QuotaLimits},
+    stats::Stats,
+    storage::{Dump, IdempotencyRecord, MemoryStorage, Storage, StorageError},
+    writer::WriteQueue,
+    AppState,
+};
+
+/// Requests carrying this header are served from the named tenant instead of
+/// the default one.
+pub const HEADER: &str = "x-tenant";
+
+/// One tenant as handed to another process.
+#[derive(Serialize, Deserialize)]
+pub struct TenantSnapshot {
+    pub nome: String,
+    pub dump: Dump,
+    pub idempotencia: Vec<(String, IdempotencyRecord)>,
+}
+
+/// Everything that belongs to one client universe.
+#[derive(Clone)]
+struct Universe {
+    storage: Arc<dyn Storage>,
+    writes: Arc<WriteQueue>,
+    extratos: Arc<StatementCache>,
+    stats: Arc<Stats>,
+    alerts: Arc<Alerts>,
+    feed: Arc<Feed>,
+    duplicates: Arc<DuplicateGuard>,
+    quotas: Arc<QuotaGuard>,
+    idempotency: Arc<IdempotencyStore>,
+}
+
+/// Named tenants besides the default one. They are always held in memory,
+/// whatever backend the default tenant uses, and are gone on restart.
+pub struct Tenants {
+    write_queue_capacity: usize,
+    write_queue_client_capacity: Option<usize>,
+    extrato_cache: bool,
+    duplicate_window_ms: Option<u64>,
+    duplicate_mode: DuplicateMode,
+    id_strategy: IdStrategy,
+    node_id: u16,
+    quota_limits: QuotaLimits,
+    idempotency_cache_capacity: usize,
+    idempotency_ttl_secs: u64,
+    clock: Arc<dyn Clock>,
+    clients_file: Option<PathBuf>,
+    universes: RwLock<HashMap<String, Universe>>,
+}
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Tenants {
+    pub fn new(config: &Config, clock: Arc<dyn Clock>) -> Self {
+        Tenants {
+            write_queue_capacity: config.write_queue_capacity,
+            write_queue_client_capacity: config.write_queue_client_capacity,
+            extrato_cache: config.extrato_cache,
+            duplicate_window_ms: config.duplicate_window_ms,
+            duplicate_mode: config.duplicate_mode,
+            id_strategy: config.id_strategy,
+            node_id: config.node_id,
+            quota_limits: QuotaLimits::new(config),
+            idempotency_cache_capacity: config.idempotency_cache_capacity,
+            idempotency_ttl_secs: config.idempotency_ttl_secs,
+            clock,
+            clients_file: config.clients_file.clone(),
+            universes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn clients_file(&self) -> Option<&Path> {
+        self.clients_file.as_deref()
+    }
+
+    fn universe(&self, storage: Arc<dyn Storage>) -> Universe {
+        let feed = Arc::new(Feed::new());
+        Universe {
+            writes: Arc::new(WriteQueue::spawn(
+                storage.clone(),
+                self.write_queue_capacity,
+                self.write_queue_client_capacity,
+                self.clock.clone(),
+                ids::generator(self.id_strategy, self.node_id),
+                feed.clone(),
+            )),
+            idempotency: Arc::new(IdempotencyStore::new(
+                &storage,
+                self.idempotency_cache_capacity,
+                self.idempotency_ttl_secs,
+            )),
+            storage,
+            extratos: Arc::new(StatementCache::new(self.extrato_cache)),
+            stats: Arc::new(Stats::new()),
+            alerts: Arc::new(Alerts::new()),
+            feed,
+            duplicates: Arc::new(DuplicateGuard::new(
+                self.duplicate_window_ms,
+                self.duplicate_mode,
+            )),
+            quotas: Arc::new(QuotaGuard::new(self.quota_limits.clone())),
+        }
+    }
+
+    fn insert(&self, name: &str, universe: Universe) -> bool {
+        self.universes
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), universe)
+            .is_none()
+    }
+
+    /// Creates the tenant seeded with `users`, replacing it and everything it
+    /// held if it already existed. Returns whether it is new.
+    pub async fn reset(&self, name: &str, users: &[User]) -> Result<bool, StorageError> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        storage.seed(users).await?;
+
+        Ok(self.insert(name, self.universe(storage)))
+    }
+
+    /// Every tenant's clients, transactions and idempotency records, to hand
+    /// them to another process.
+    pub async fn snapshot(&self) -> Result<Vec<TenantSnapshot>, StorageError> {
+        let universes: Vec<(String, Universe)> = self
+            .universes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, universe)| (name.clone(), universe.clone()))
+            .collect();
+
+        let now = self.clock.now();
+        let mut snapshots = Vec::with_capacity(universes.len());
+        for (nome, universe) in universes {
+            snapshots.push(TenantSnapshot {
+                nome,
+                dump: universe.storage.dump().await?,
+                idempotencia: universe.idempotency.held(now),
+            });
+        }
+        Ok(snapshots)
+    }
+
+    /// Creates or replaces the tenant with the contents of `snapshot`.
+    pub async fn restore(&self, snapshot: TenantSnapshot) -> Result<(), StorageError> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        storage.restore(snapshot.dump).await?;
+
+        let universe = self.universe(storage);
+        universe.idempotency.hold(snapshot.idempotencia);
+        self.insert(&snapshot.nome, universe);
+        Ok(())
+    }
+
+    /// Whether no tenant has writes waiting or being written.
+    pub fn writes_idle(&self) -> bool {
+        self.universes
+            .read()
+            .unwrap()
+            .values()
+            .all(|universe| universe.writes.idle())
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        self.universes.write().unwrap().remove(name).is_some()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.universes.read().unwrap().keys().cloned().collect();
+        names.sort_unstable();
+        names
+    }
+
+    fn get(&self, name: &str) -> Option<Universe> {
+        self.universes.read().unwrap().get(name).cloned()
+    }
+}
+
+/// The application state as seen by the tenant a request is addressed to.
+pub struct Tenant(pub AppState);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Tenant {
+    type Rejection = (StatusCode, Localized);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(name) = parts.headers.get(HEADER) else {
+            return Ok(Tenant(state.clone()));
+        };
+
+        let (name, universe) = name
+            .to_str()
+            .ok()
+            .and_then(|name| Some((name, state.tenants.get(name)?)))
+            .ok_or_else(|| {
+                let lang = Lang::from_headers(&parts.headers);
+                (
+                    StatusCode::NOT_FOUND,
+                    Message::new("tenant_desconhecido").localize(lang),
+                )
+            })?;
+
+        Ok(Tenant(AppState {
+            storage: universe.storage,
+            writes: universe.writes,
+            extratos: universe.extratos,
+            stats: universe.stats,
+            alerts: universe.alerts,
+            feed: universe.feed,
+            duplicates: universe.duplicates,
+            quotas: universe.quotas,
+            idempotency: universe.idempotency,
+            tenant: Some(name.to_owned()),
+            ..state.clone()
+        }))
+    }
+}
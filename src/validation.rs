@@ -0,0 +1,294 @@
+use std::fmt::Display;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    chaos::ChaosSettings,
+    i18n::{Lang, Message},
+    models::{ClientPatch, ImportedTransaction, NewTransaction},
+};
+
+/// Field name for problems with the body as a whole.
+const BODY: &str = "$";
+
+pub const TIPOS: [&str; 2] = ["c", "d"];
+
+const MAX_TAGS: usize = 5;
+
+/// What is wrong with one field, in a form clients can act on, plus the same
+/// in words.
+#[derive(Serialize)]
+pub struct FieldError {
+    field: String,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed: Option<Vec<&'static str>>,
+    message: String,
+}
+
+/// Sent instead of the request's usual answer, by default as a 422.
+pub struct ValidationErrors {
+    status: StatusCode,
+    lang: Lang,
+    errors: Vec<FieldError>,
+}
+
+#[derive(Serialize)]
+struct Body<'a> {
+    errors: &'a [FieldError],
+}
+
+impl IntoResponse for ValidationErrors {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            [(header::CONTENT_LANGUAGE, self.lang.tag())],
+            Json(Body {
+                errors: &self.errors,
+            }),
+        )
+            .into_response()
+    }
+}
+
+/// Collects every problem with a request instead of stopping at the first.
+pub struct Validator {
+    lang: Lang,
+    prefix: String,
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new(lang: Lang) -> Self {
+        Validator {
+            lang,
+            prefix: String::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, field: &str, code: &'static str, message: Message) -> &mut FieldError {
+        let field = format!("{}{field}", self.prefix);
+        let message = message.with("campo", &field).localize(self.lang);
+        self.errors.push(FieldError {
+            field,
+            code,
+            min: None,
+            max: None,
+            allowed: None,
+            message: message.into_text(),
+        });
+        self.errors.last_mut().unwrap()
+    }
+
+    /// Records an error no helper below covers; its message is catalog `key`.
+    pub fn error(&mut self, field: &str, code: &'static str, key: &'static str) {
+        self.push(field, code, Message::new(key));
+    }
+
+    pub fn length(&mut self, field: &str, value: &str, min: usize, max: usize) {
+        let len = value.chars().count();
+        if len < min {
+            self.push(
+                field,
+                "too_short",
+                Message::new("campo_curto").with("min", min),
+            )
+            .min = Some(min.into());
+        } else if len > max {
+            self.push(
+                field,
+                "too_long",
+                Message::new("campo_longo").with("max", max),
+            )
+            .max = Some(max.into());
+        }
+    }
+
+    pub fn range<N>(&mut self, field: &str, value: N, min: N, max: N)
+    where
+        N: PartialOrd + Display + Serialize,
+    {
+        if value < min || value > max {
+            let message = Message::new("campo_fora_do_intervalo")
+                .with("min", &min)
+                .with("max", &max)
+                .with("valor", &value);
+            let error = self.push(field, "out_of_range", message);
+            error.min = serde_json::to_value(min).ok();
+            error.max = serde_json::to_value(max).ok();
+        }
+    }
+
+    pub fn count(&mut self, field: &str, len: usize, max: usize) {
+        if len > max {
+            self.push(
+                field,
+                "too_many",
+                Message::new("campo_com_itens_demais").with("max", max),
+            )
+            .max = Some(max.into());
+        }
+    }
+
+    pub fn one_of(&mut self, field: &str, value: &str, allowed: &[&'static str]) {
+        if !allowed.contains(&value) {
+            let message = Message::new("campo_fora_das_opcoes")
+                .with("opcoes", allowed.join(", "))
+                .with("valor", value);
+            self.push(field, "not_one_of", message).allowed = Some(allowed.to_vec());
+        }
+    }
+
+    /// Validates `item` with its fields named after `prefix`, as in `[2].tipo`.
+    pub fn nested(&mut self, prefix: &str, item: &impl Validate) {
+        let inner = format!("{}{prefix}.", self.prefix);
+        let outer = std::mem::replace(&mut self.prefix, inner);
+        item.validate(self);
+        self.prefix = outer;
+    }
+
+    pub fn finish(self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        Err(ValidationErrors {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            lang: self.lang,
+            errors: self.errors,
+        })
+    }
+
+    /// A body that could not even be read into the DTO, keeping the status
+    /// axum would have answered with.
+    fn rejected(mut self, rejection: JsonRejection) -> ValidationErrors {
+        let status = rejection.status();
+        match &rejection {
+            JsonRejection::JsonDataError(err) => {
+                let text = err.body_text();
+                let (path, cause) = serde_error(&text);
+                match missing_field(cause) {
+                    Some(field) => {
+                        let field = path.map_or(field.to_owned(), |path| format!("{path}.{field}"));
+                        self.error(&field, "required", "campo_obrigatorio");
+                    }
+                    None => {
+                        let message = Message::new("campo_invalido").with("detalhe", cause);
+                        self.push(path.unwrap_or(BODY), "invalid", message);
+                    }
+                }
+            }
+            JsonRejection::MissingJsonContentType(_) => {
+                self.error(BODY, "unsupported_media_type", "content_type_invalido")
+            }
+            _ => self.error(BODY, "malformed", "json_malformado"),
+        }
+
+        ValidationErrors {
+            status,
+            lang: self.lang,
+            errors: self.errors,
+        }
+    }
+}
+
+/// Splits axum's "Failed to deserialize ...: [0].valor: invalid type: ..."
+/// into the path serde was at, if not the top, and what went wrong there.
+fn serde_error(text: &str) -> (Option<&str>, &str) {
+    let detail = text.split_once(": ").map_or(text, |(_, detail)| detail);
+    match detail.split_once(": ") {
+        Some((path, cause)) if !path.contains(char::is_whitespace) => (Some(path), cause),
+        _ => (None, detail),
+    }
+}
+
+/// The field serde complained about in "missing field `valor` at line 1".
+fn missing_field(cause: &str) -> Option<&str> {
+    let rest = cause.strip_prefix("missing field `")?;
+    rest.split_once('`').map(|(field, _)| field)
+}
+
+/// Rules a request DTO checks itself against.
+pub trait Validate {
+    fn validate(&self, v: &mut Validator);
+}
+
+impl<T: Validate> Validate for Vec<T> {
+    fn validate(&self, v: &mut Validator) {
+        for (index, item) in self.iter().enumerate() {
+            v.nested(&format!("[{index}]"), item);
+        }
+    }
+}
+
+/// `Json<T>` that also runs `T`'s rules, answering with every broken one.
+pub struct Valid<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Valid<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ValidationErrors;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let mut v = Validator::new(Lang::from_headers(request.headers()));
+        match Json::<T>::from_request(request, state).await {
+            Ok(Json(value)) => {
+                value.validate(&mut v);
+                v.finish().map(|()| Valid(value))
+            }
+            Err(rejection) => Err(v.rejected(rejection)),
+        }
+    }
+}
+
+impl Validate for NewTransaction {
+    fn validate(&self, v: &mut Validator) {
+        v.range("valor", self.valor, 1, i32::MAX);
+        v.one_of("tipo", &self.tipo, &TIPOS);
+        v.length("descricao", &self.descricao, 1, 10);
+        if let Some(categoria) = &self.categoria {
+            v.length("categoria", categoria, 1, 20);
+        }
+        v.count("tags", self.tags.len(), MAX_TAGS);
+        for (index, tag) in self.tags.iter().enumerate() {
+            v.length(&format!("tags[{index}]"), tag, 1, 20);
+        }
+    }
+}
+
+impl Validate for ImportedTransaction {
+    fn validate(&self, v: &mut Validator) {
+        self.transaction.validate(v);
+    }
+}
+
+impl Validate for ClientPatch {
+    fn validate(&self, v: &mut Validator) {
+        if let Some(Some(percentual)) = self.alerta_percentual {
+            v.range("alerta_percentual", percentual, 1, 100);
+        }
+    }
+}
+
+impl Validate for ChaosSettings {
+    fn validate(&self, v: &mut Validator) {
+        v.range("latencia_percentual", self.latencia_percentual, 0.0, 100.0);
+        v.range("erro_percentual", self.erro_percentual, 0.0, 100.0);
+        v.range("queda_percentual", self.queda_percentual, 0.0, 100.0);
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{Path, Query},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    handlers,
+    models::{Balance, LastTransaction, StatementQuery, StatementResponse, TransactionResponse},
+    storage::StorageError,
+    tenants::Tenant,
+    AppState,
+};
+
+/// Extrato reads issued per warmup, spread across the clients.
+const READS: usize = 200;
+
+/// Reads in flight at once; enough to open every pooled connection.
+const CONCURRENCY: usize = 16;
+
+#[derive(Serialize)]
+pub struct WarmupReport {
+    pub clientes: usize,
+    pub leituras: usize,
+    pub duracao_ms: u128,
+}
+
+/// Runs the read paths once so the first real requests don't pay for lazy
+/// initialization: connections get opened, storage grows its maps and the
+/// serializers run. Nothing is written.
+pub async fn warm_up(state: &AppState) -> Result<WarmupReport, StorageError> {
+    let started = Instant::now();
+
+    state.storage.warm_up().await?;
+    let clientes = state.storage.list_clients(false).await?;
+
+    serde_json::to_vec(&TransactionResponse {
+        limite: 0,
+        saldo: 0,
+        id: Uuid::nil(),
+        sequencia: 0,
+    })?;
+    serde_json::to_vec(&StatementResponse {
+        saldo: Balance {
+            total: 0,
+            data_extrato: state.clock.now(),
+            limite: 0,
+            alerta: false,
+        },
+        ultimas_transacoes: Vec::<LastTransaction>::new(),
+    })?;
+
+    let ids: Vec<i32> = clientes.iter().map(|c| c.id).collect();
+    let leituras = if ids.is_empty() { 0 } else { READS };
+
+    stream::iter(ids.iter().copied().cycle().take(leituras))
+        .map(|id| {
+            let query = StatementQuery {
+                quantidade: None,
+                tipo: None,
+            };
+            let response = handlers::get_bank_statement(
+                Tenant(state.clone()),
+                Path(id),
+                Query(query),
+                HeaderMap::new(),
+            );
+            async move { response.await.into_response() }
+        })
+        .buffer_unordered(CONCURRENCY)
+        .for_each(|_| async {})
+        .await;
+
+    Ok(WarmupReport {
+        clientes: ids.len(),
+        leituras,
+        duracao_ms: started.elapsed().as_millis(),
+    })
+}
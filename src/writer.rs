@@ -0,0 +1,528 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::{oneshot, Notify};
+use tracing::error;
+
+use crate::{
+    clock::Clock,
+    domain,
+    feed::Feed,
+    ids::IdGenerator,
+    metrics::{self, Counter, Gauge},
+    models::{NewTransaction, Statement, User},
+    storage::{Imported, Storage, StorageError, TransactionError},
+};
+
+const DEPTH: &str = "write_queue_depth";
+const SHED: &str = "write_queue_shed_total";
+const CLIENT_DEPTH: &str = "write_queue_client_depth";
+const CLIENT_SHED: &str = "write_queue_client_shed_total";
+
+struct Command {
+    user_id: i32,
+    work: Work,
+}
+
+enum Work {
+    One {
+        realizado_em: DateTime<Utc>,
+        transaction: NewTransaction,
+        reply: oneshot::Sender<Result<(User, Statement), TransactionError>>,
+    },
+    /// Written in one go through `Storage::import_history`.
+    Import {
+        transactions: Vec<(NewTransaction, DateTime<Utc>)>,
+        reply: oneshot::Sender<Result<(User, Vec<Statement>), TransactionError>>,
+    },
+}
+
+pub enum WriteError {
+    /// The queue was full and the command was dropped without touching storage.
+    Overloaded,
+    Transaction(TransactionError),
+}
+
+fn client_labels(user_id: i32) -> metrics::Labels {
+    vec![("cliente", user_id.to_string())]
+}
+
+/// One sub-queue per client, served round-robin so a client receiving most
+/// of the writes only ever delays the others by one transaction each.
+#[derive(Default)]
+struct Queues {
+    pending: HashMap<i32, VecDeque<Command>>,
+    /// Clients with pending commands, in the order they get their next turn.
+    turns: VecDeque<i32>,
+    len: usize,
+    /// Clients a transaction went through for. Only these get per-client
+    /// metrics, so made-up ids in the path cannot create new series.
+    known: HashSet<i32>,
+    /// Whether the writer is applying a command it took off the queue.
+    writing: bool,
+    closed: bool,
+}
+
+impl Queues {
+    fn depth(&self, user_id: i32) -> usize {
+        self.pending.get(&user_id).map_or(0, VecDeque::len)
+    }
+
+    fn report(&self, user_id: i32) {
+        if self.known.contains(&user_id) {
+            metrics::gauge(CLIENT_DEPTH, client_labels(user_id)).set(self.depth(user_id) as f64);
+        }
+    }
+
+    fn push(&mut self, command: Command) {
+        let user_id = command.user_id;
+        let queue = self.pending.entry(user_id).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(user_id);
+        }
+        queue.push_back(command);
+        self.len += 1;
+        self.report(user_id);
+    }
+
+    fn pop(&mut self) -> Option<Command> {
+        let user_id = self.turns.pop_front()?;
+        let queue = self.pending.get_mut(&user_id)?;
+        let command = queue.pop_front()?;
+
+        if queue.is_empty() {
+            self.pending.remove(&user_id);
+        } else {
+            self.turns.push_back(user_id);
+        }
+        self.len -= 1;
+        self.report(user_id);
+
+        Some(command)
+    }
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    ready: Notify,
+}
+
+/// Front of the bounded queue that feeds the writer task. Submitting never
+/// waits for room: a full queue is reported straight away so callers can shed
+/// load instead of piling up latency.
+pub struct WriteQueue {
+    shared: Arc<Shared>,
+    capacity: usize,
+    client_capacity: usize,
+    clock: Arc<dyn Clock>,
+    ids: Arc<dyn IdGenerator>,
+    depth: Arc<Gauge>,
+    shed: Arc<Counter>,
+}
+
+impl WriteQueue {
+    /// Creates the queue and spawns the task that drains it into `storage`.
+    /// At most `capacity` transactions wait in total and `client_capacity`
+    /// for any one client, by default the whole capacity. Transactions are
+    /// stamped by `clock` when they are submitted, get their ids from `ids`
+    /// when they are written and go out on `feed` in the order they were.
+    pub fn spawn(
+        storage: Arc<dyn Storage>,
+        capacity: usize,
+        client_capacity: Option<usize>,
+        clock: Arc<dyn Clock>,
+        ids: Arc<dyn IdGenerator>,
+        feed: Arc<Feed>,
+    ) -> Self {
+        let capacity = capacity.max(1);
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Queues::default()),
+            ready: Notify::new(),
+        });
+        let depth = metrics::gauge(DEPTH, Vec::new());
+
+        tokio::spawn(run_writer(
+            storage,
+            ids.clone(),
+            feed,
+            shared.clone(),
+            depth.clone(),
+        ));
+
+        WriteQueue {
+            shared,
+            capacity,
+            client_capacity: client_capacity.unwrap_or(capacity).clamp(1, capacity),
+            clock,
+            ids,
+            depth,
+            shed: metrics::counter(SHED, Vec::new()),
+        }
+    }
+
+    pub async fn submit(
+        &self,
+        user_id: i32,
+        transaction: NewTransaction,
+    ) -> Result<(User, Statement), WriteError> {
+        let (reply, response) = oneshot::channel();
+        let work = Work::One {
+            realizado_em: self.clock.now(),
+            transaction,
+            reply,
+        };
+        self.enqueue(user_id, work)?;
+
+        match response.await {
+            Ok(result) => result.map_err(WriteError::Transaction),
+            Err(_) => Err(writer_gone()),
+        }
+    }
+
+    /// Writes `transactions`, stamped with their own times, after whatever
+    /// is queued for the client and all together or not at all. They take
+    /// one place in the queue.
+    pub async fn import(
+        &self,
+        user_id: i32,
+        transactions: Vec<(NewTransaction, DateTime<Utc>)>,
+    ) -> Result<(User, Vec<Statement>), WriteError> {
+        let (reply, response) = oneshot::channel();
+        self.enqueue(
+            user_id,
+            Work::Import {
+                transactions,
+                reply,
+            },
+        )?;
+
+        match response.await {
+            Ok(result) => result.map_err(WriteError::Transaction),
+            Err(_) => Err(writer_gone()),
+        }
+    }
+
+    fn enqueue(&self, user_id: i32, work: Work) -> Result<(), WriteError> {
+        {
+            let mut queues = self.shared.queues.lock().unwrap();
+            if queues.len >= self.capacity || queues.depth(user_id) >= self.client_capacity {
+                self.shed.inc();
+                if queues.known.contains(&user_id) {
+                    metrics::counter(CLIENT_SHED, client_labels(user_id)).inc();
+                }
+                return Err(WriteError::Overloaded);
+            }
+
+            queues.push(Command { user_id, work });
+            self.depth.set(queues.len as f64);
+        }
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Where the writer gets transaction ids from.
+    pub fn ids(&self) -> Arc<dyn IdGenerator> {
+        self.ids.clone()
+    }
+
+    /// Whether nothing is waiting or being written.
+    pub fn idle(&self) -> bool {
+        let queues = self.shared.queues.lock().unwrap();
+        queues.len == 0 && !queues.writing
+    }
+}
+
+impl Drop for WriteQueue {
+    /// Lets the writer finish what is queued and stop.
+    fn drop(&mut self) {
+        self.shared.queues.lock().unwrap().closed = true;
+        self.shared.ready.notify_one();
+    }
+}
+
+fn writer_gone() -> WriteError {
+    WriteError::Transaction(TransactionError::Storage(StorageError::Backend(
+        "writer task stopped".to_owned(),
+    )))
+}
+
+/// Lets `ids` carry on from the ones `storage` already holds.
+async fn resume(storage: &dyn Storage, ids: &dyn IdGenerator) -> Result<(), StorageError> {
+    ids.resume(storage.highest_integer_id().await?);
+    Ok(())
+}
+
+async fn run_writer(
+    storage: Arc<dyn Storage>,
+    ids: Arc<dyn IdGenerator>,
+    feed: Arc<Feed>,
+    shared: Arc<Shared>,
+    depth: Arc<Gauge>,
+) {
+    // Left for the first write, so a dump restored after startup is counted.
+    let mut resumed = !ids.resumes();
+    loop {
+        let next = {
+            let mut queues = shared.queues.lock().unwrap();
+            let next = queues.pop();
+            queues.writing = next.is_some();
+            depth.set(queues.len as f64);
+            if next.is_none() && queues.closed {
+                return;
+            }
+            next
+        };
+        let Some(next) = next else {
+            shared.ready.notified().await;
+            continue;
+        };
+
+        if !resumed {
+            if let Err(err) = resume(&*storage, &*ids).await {
+                error!("failed to read the stored ids: {err}");
+                next.work.fail(err);
+                continue;
+            }
+            resumed = true;
+        }
+
+        let user_id = next.user_id;
+        // The handler may have given up on the request; nothing to do then.
+        let found = match next.work {
+            Work::One {
+                realizado_em,
+                transaction,
+                reply,
+            } => {
+                let result = storage
+                    .apply_transaction(user_id, transaction, realizado_em, ids.next())
+                    .await;
+                if let Ok((user, statement)) = &result {
+                    feed.publish(user, statement);
+                }
+                let found = !matches!(result, Err(TransactionError::NotFound));
+                let _ = reply.send(result);
+                found
+            }
+            Work::Import {
+                transactions,
+                reply,
+            } => {
+                let transactions = transactions
+                    .into_iter()
+                    .map(|(transaction, realizado_em)| Imported {
+                        transaction,
+                        realizado_em,
+                        uuid: ids.next(),
+                    })
+                    .collect();
+                let result = storage.import_history(user_id, transactions).await;
+                if let Ok((user, statements)) = &result {
+                    publish_imported(&feed, user, statements);
+                }
+                let found = !matches!(result, Err(TransactionError::NotFound));
+                let _ = reply.send(result);
+                found
+            }
+        };
+
+        if found {
+            let mut queues = shared.queues.lock().unwrap();
+            if queues.known.insert(user_id) {
+                queues.report(user_id);
+            }
+        }
+    }
+}
+
+impl Work {
+    fn fail(self, err: StorageError) {
+        let err = TransactionError::Storage(err);
+        match self {
+            Work::One { reply, .. } => {
+                let _ = reply.send(Err(err));
+            }
+            Work::Import { reply, .. } => {
+                let _ = reply.send(Err(err));
+            }
+        }
+    }
+}
+
+/// Publishes imported statements with the balance each one left behind,
+/// worked back from the one after the last.
+fn publish_imported(feed: &Feed, user: &User, statements: &[Statement]) {
+    let total: i64 = statements
+        .iter()
+        .map(|statement| domain::signed_valor(&statement.tipo, statement.valor))
+        .sum();
+    let mut saldo = i64::from(user.saldo) - total;
+    for statement in statements {
+        saldo += domain::signed_valor(&statement.tipo, statement.valor);
+        let after = User {
+            saldo: saldo as i32,
+            ..user.clone()
+        };
+        feed.publish(&after, statement);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::ManualClock,
+        ids::{self, IdStrategy},
+        storage::MemoryStorage,
+    };
+
+    fn transaction(valor: i32, tipo: &str) -> NewTransaction {
+        NewTransaction {
+            valor,
+            tipo: tipo.to_owned(),
+            descricao: "teste".to_owned(),
+            categoria: None,
+            tags: Vec::new(),
+        }
+    }
+
+    async fn queue(clock: Arc<ManualClock>) -> (WriteQueue, Arc<dyn Storage>) {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let user = User {
+            id: 1,
+            limite: 1000,
+            saldo: 0,
+            ativo: true,
+            ultima_sequencia: 0,
+            alerta_percentual: None,
+        };
+        storage.seed(&[user]).await.unwrap();
+        let queue = WriteQueue::spawn(
+            storage.clone(),
+            10,
+            None,
+            clock,
+            ids::generator(IdStrategy::Sequential, 0),
+            Arc::new(Feed::new()),
+        );
+        (queue, storage)
+    }
+
+    fn command(user_id: i32) -> Command {
+        Command {
+            user_id,
+            work: Work::One {
+                realizado_em: Utc::now(),
+                transaction: transaction(1, "c"),
+                reply: oneshot::channel().0,
+            },
+        }
+    }
+
+    fn drain(queues: &mut Queues) -> Vec<i32> {
+        std::iter::from_fn(|| queues.pop().map(|command| command.user_id)).collect()
+    }
+
+    #[test]
+    fn clients_take_turns_whoever_queued_first() {
+        let mut queues = Queues::default();
+        for user_id in [1, 1, 1, 1, 2, 3, 2] {
+            queues.push(command(user_id));
+        }
+
+        assert_eq!(queues.len, 7);
+        assert_eq!(queues.depth(1), 4);
+        assert_eq!(drain(&mut queues), [1, 2, 3, 1, 2, 1, 1]);
+        assert_eq!(queues.len, 0);
+        assert!(queues.turns.is_empty() && queues.pending.is_empty());
+    }
+
+    #[test]
+    fn a_client_coming_back_waits_for_the_others_turns() {
+        let mut queues = Queues::default();
+        for user_id in [1, 1, 2] {
+            queues.push(command(user_id));
+        }
+        assert_eq!(queues.pop().map(|command| command.user_id), Some(1));
+        assert_eq!(queues.pop().map(|command| command.user_id), Some(2));
+        queues.push(command(2));
+        queues.push(command(3));
+
+        assert_eq!(drain(&mut queues), [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn transactions_are_stamped_when_submitted() {
+        let clock = Arc::new(ManualClock::new("2024-03-01T12:00:00Z".parse().unwrap()));
+        let (queue, _) = queue(clock.clone()).await;
+        let later = "2024-03-01T13:00:00Z".parse().unwrap();
+        clock.set(later);
+
+        let Ok((user, statement)) = queue.submit(1, transaction(100, "d")).await else {
+            panic!("the transaction should go through");
+        };
+        assert_eq!(statement.realizado_em, later);
+        assert_eq!(statement.uuid, Uuid::from_u64_pair(0, 1));
+        assert_eq!(user.saldo, -100);
+    }
+
+    #[tokio::test]
+    async fn imports_are_written_whole_or_not_at_all() {
+        let clock = Arc::new(ManualClock::new("2024-03-01T12:00:00Z".parse().unwrap()));
+        let (queue, storage) = queue(clock).await;
+        let at = |hour: u32| Utc.with_ymd_and_hms(2024, 2, 1, hour, 0, 0).unwrap();
+
+        let refused = vec![
+            (transaction(500, "c"), at(1)),
+            (transaction(2000, "d"), at(2)),
+        ];
+        assert!(matches!(
+            queue.import(1, refused).await,
+            Err(WriteError::Transaction(TransactionError::LimitExceeded))
+        ));
+        assert_eq!(
+            storage.client(1).await.unwrap().unwrap().ultima_sequencia,
+            0
+        );
+
+        let accepted = vec![
+            (transaction(500, "c"), at(1)),
+            (transaction(1200, "d"), at(2)),
+        ];
+        let Ok((user, statements)) = queue.import(1, accepted).await else {
+            panic!("the import should go through");
+        };
+        assert_eq!((user.saldo, user.ultima_sequencia), (-700, 2));
+        assert_eq!(
+            statements
+                .iter()
+                .map(|s| (s.sequencia, s.realizado_em))
+                .collect::<Vec<_>>(),
+            [(1, at(1)), (2, at(2))]
+        );
+    }
+
+    #[tokio::test]
+    async fn queues_sharing_sequential_ids_never_repeat_one() {
+        let clock = Arc::new(ManualClock::new("2024-03-01T12:00:00Z".parse().unwrap()));
+        let (first, storage) = queue(clock.clone()).await;
+        assert!(first.submit(1, transaction(1, "c")).await.is_ok());
+
+        let second =
+            WriteQueue::spawn(storage, 10, None, clock, first.ids(), Arc::new(Feed::new()));
+        let mut ids = Vec::new();
+        for queue in [&second, &first, &second] {
+            let Ok((_, statement)) = queue.submit(1, transaction(1, "c")).await else {
+                panic!("the transaction should go through");
+            };
+            ids.push(statement.uuid.as_u64_pair().1);
+        }
+        assert_eq!(ids, [2, 3, 4]);
+    }
+}